@@ -0,0 +1,232 @@
+use bitcoincore_rpc::jsonrpc::{self, Request, Response, Transport};
+use parking_lot::Mutex;
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::metrics::{Gauge, Metrics};
+
+/// Circuit breaker policy, for failing fast while bitcoind RPC is unavailable
+#[derive(Clone, Debug)]
+pub struct BreakerPolicy {
+    pub max_failures: usize, // consecutive connection failures (within `window`) opening the breaker
+    pub window: Duration,
+    pub cooldown: Duration, // before probing bitcoind again
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Closed {
+        failures: usize,
+        since: Option<Instant>, // the first failure
+    },
+    Open {
+        until: Instant,
+    },
+    HalfOpen {
+        since: Instant, // a single probe request is in flight
+    },
+}
+
+impl State {
+    fn value(&self) -> f64 {
+        match self {
+            State::Closed { .. } => 0.0,
+            State::Open { .. } => 1.0,
+            State::HalfOpen { .. } => 2.0,
+        }
+    }
+}
+
+struct Breaker {
+    policy: BreakerPolicy,
+    state: State,
+}
+
+impl Breaker {
+    fn new(policy: BreakerPolicy) -> Self {
+        Self {
+            policy,
+            state: State::Closed {
+                failures: 0,
+                since: None,
+            },
+        }
+    }
+
+    /// Returns false if the request should fail fast.
+    fn allow(&mut self, now: Instant) -> bool {
+        let allowed = match self.state {
+            State::Closed { .. } => return true,
+            State::Open { until } => now >= until,
+            // allow another probe, in case the previous one got lost
+            State::HalfOpen { since } => now >= since + self.policy.cooldown,
+        };
+        if allowed {
+            self.state = State::HalfOpen { since: now };
+        }
+        allowed
+    }
+
+    fn record(&mut self, success: bool, now: Instant) {
+        self.state = match (&self.state, success) {
+            (State::Closed { .. }, true) => State::Closed {
+                failures: 0,
+                since: None,
+            },
+            (State::HalfOpen { .. }, true) => {
+                info!("bitcoind RPC is available again");
+                State::Closed {
+                    failures: 0,
+                    since: None,
+                }
+            }
+            (State::Open { .. }, true) => return, // sent before the breaker was opened
+            (State::Closed { failures, since }, false) => {
+                let (failures, since) = match since {
+                    Some(since) if now < *since + self.policy.window => (failures + 1, *since),
+                    _ => (1, now),
+                };
+                if failures < self.policy.max_failures {
+                    State::Closed {
+                        failures,
+                        since: Some(since),
+                    }
+                } else {
+                    warn!(
+                        "bitcoind RPC failed {} times, failing fast for {:?}",
+                        failures, self.policy.cooldown
+                    );
+                    State::Open {
+                        until: now + self.policy.cooldown,
+                    }
+                }
+            }
+            (_, false) => State::Open {
+                until: now + self.policy.cooldown,
+            },
+        };
+    }
+}
+
+/// Stops sending RPC requests to a failing bitcoind: after `max_failures` consecutive connection
+/// failures, requests fail fast during `cooldown`. Then, a single probe request is allowed - closing
+/// the breaker if it succeeds (or re-opening it otherwise).
+pub(crate) struct CircuitBreaker {
+    breaker: Mutex<Breaker>,
+    gauge: Gauge,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(policy: BreakerPolicy, metrics: &Metrics) -> Self {
+        let breaker = Breaker::new(policy);
+        let gauge = metrics.gauge(
+            "daemon_rpc_breaker",
+            "bitcoind RPC circuit breaker state (0 - closed, 1 - open, 2 - half-open)",
+            "breaker",
+        );
+        gauge.set("state", breaker.state.value());
+        Self {
+            breaker: Mutex::new(breaker),
+            gauge,
+        }
+    }
+
+    fn call<T>(&self, f: impl FnOnce() -> Result<T, jsonrpc::Error>) -> Result<T, jsonrpc::Error> {
+        if !self.update(|breaker| breaker.allow(Instant::now())) {
+            return Err(jsonrpc::Error::Transport(Box::new(DaemonUnavailable)));
+        }
+        let result = f();
+        let success = !matches!(result, Err(jsonrpc::Error::Transport(_)));
+        self.update(|breaker| breaker.record(success, Instant::now()));
+        result
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut Breaker) -> T) -> T {
+        let mut breaker = self.breaker.lock();
+        let result = f(&mut breaker);
+        self.gauge.set("state", breaker.state.value());
+        result
+    }
+}
+
+/// Returned while the circuit breaker is open.
+#[derive(Debug)]
+struct DaemonUnavailable;
+
+impl fmt::Display for DaemonUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "daemon unavailable (circuit breaker is open)")
+    }
+}
+
+impl StdError for DaemonUnavailable {}
+
+/// JSON-RPC transport, failing fast while its circuit breaker is open.
+pub(crate) struct BreakerTransport<T> {
+    inner: T,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl<T: Transport> BreakerTransport<T> {
+    pub(crate) fn new(inner: T, breaker: Arc<CircuitBreaker>) -> Self {
+        Self { inner, breaker }
+    }
+}
+
+impl<T: Transport> Transport for BreakerTransport<T> {
+    fn send_request(&self, req: Request) -> Result<Response, jsonrpc::Error> {
+        self.breaker.call(|| self.inner.send_request(req))
+    }
+
+    fn send_batch(&self, reqs: &[Request]) -> Result<Vec<Response>, jsonrpc::Error> {
+        self.breaker.call(|| self.inner.send_batch(reqs))
+    }
+
+    fn fmt_target(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt_target(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Breaker, BreakerPolicy, State};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_breaker() {
+        let mut breaker = Breaker::new(BreakerPolicy {
+            max_failures: 2,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(10),
+        });
+        let now = Instant::now();
+        let secs = |n| now + Duration::from_secs(n);
+
+        // failures outside the window are not consecutive
+        assert!(breaker.allow(now));
+        breaker.record(false, now);
+        breaker.record(false, secs(61));
+        assert!(breaker.allow(secs(61)));
+
+        // open the breaker
+        breaker.record(false, secs(62));
+        assert_eq!(breaker.state, State::Open { until: secs(72) });
+        assert!(!breaker.allow(secs(71)));
+
+        // a failed probe re-opens it
+        assert!(breaker.allow(secs(72)));
+        assert!(!breaker.allow(secs(73))); // the probe is in flight
+        breaker.record(false, secs(74));
+        assert_eq!(breaker.state, State::Open { until: secs(84) });
+
+        // a successful probe closes it
+        assert!(breaker.allow(secs(84)));
+        breaker.record(true, secs(85));
+        assert!(breaker.allow(secs(85)));
+        breaker.record(false, secs(86));
+        assert!(breaker.allow(secs(86))); // a single failure doesn't open the breaker
+    }
+}
@@ -1,28 +1,174 @@
-use bitcoin::{Transaction, Txid};
-use parking_lot::RwLock;
+use anyhow::{Context, Result};
+use bitcoin::{BlockHash, Transaction, Txid, Wtxid};
+use parking_lot::{Mutex, RwLock};
+use serde_json::{json, Value};
 
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::metrics::{self, Histogram, Metrics};
+use crate::{
+    chain::Chain,
+    lru::Lru,
+    metrics::{self, Gauge, Histogram, Metrics},
+};
+
+/// Maximum number of cached verbose transactions
+const TX_INFO_CACHE_SIZE: usize = 10_000;
+
+/// Maximum number of cached mempool acceptance results
+const MEMPOOL_ACCEPT_CACHE_SIZE: usize = 1_000;
+
+/// Mempool acceptance may change as the mempool evolves, so it is cached only for a short time
+const MEMPOOL_ACCEPT_TTL: Duration = Duration::from_secs(10);
+
+/// Verbose transaction info, stored zstd-compressed if its JSON is large enough
+enum TxInfoData {
+    Plain(Value),
+    Compressed(Vec<u8>),
+}
+
+struct TxInfo {
+    blockhash: BlockHash, // confirming block
+    data: TxInfoData,
+    size: usize, // serialized JSON size (in bytes)
+}
+
+impl TxInfo {
+    fn new(blockhash: BlockHash, info: &Value, compression_threshold: Option<usize>) -> Self {
+        let json = info.to_string();
+        let data = match compression_threshold {
+            Some(threshold) if json.len() > threshold => {
+                match zstd::stream::encode_all(json.as_bytes(), 0) {
+                    Ok(compressed) => TxInfoData::Compressed(compressed),
+                    Err(e) => {
+                        warn!("failed to compress tx info: {}", e);
+                        TxInfoData::Plain(info.clone())
+                    }
+                }
+            }
+            _ => TxInfoData::Plain(info.clone()),
+        };
+        Self {
+            blockhash,
+            data,
+            size: json.len(),
+        }
+    }
+
+    fn value(&self) -> Result<Value> {
+        match &self.data {
+            TxInfoData::Plain(info) => Ok(info.clone()),
+            TxInfoData::Compressed(compressed) => {
+                let json = zstd::stream::decode_all(&compressed[..])
+                    .context("failed to decompress tx info")?;
+                serde_json::from_slice(&json).context("invalid tx info")
+            }
+        }
+    }
+}
+
+/// Cached verbose transactions, keeping track of their memory usage
+struct TxInfos {
+    lru: Lru<Txid, TxInfo>,
+    plain_size: usize,
+    compressed_size: usize,
+    uncompressed_size: usize, // before compression
+}
+
+impl TxInfos {
+    fn new(capacity: usize) -> Self {
+        Self {
+            lru: Lru::new(capacity),
+            plain_size: 0,
+            compressed_size: 0,
+            uncompressed_size: 0,
+        }
+    }
+
+    fn insert(&mut self, txid: Txid, info: TxInfo, gauge: &Gauge) {
+        self.add(&info);
+        for removed in self.lru.insert(txid, info) {
+            self.sub(&removed);
+        }
+        self.observe(gauge);
+    }
+
+    fn remove(&mut self, txid: &Txid, gauge: &Gauge) {
+        if let Some(removed) = self.lru.remove(txid) {
+            self.sub(&removed);
+        }
+        self.observe(gauge);
+    }
+
+    fn add(&mut self, info: &TxInfo) {
+        match &info.data {
+            TxInfoData::Plain(_) => self.plain_size += info.size,
+            TxInfoData::Compressed(compressed) => {
+                self.compressed_size += compressed.len();
+                self.uncompressed_size += info.size;
+            }
+        }
+    }
+
+    fn sub(&mut self, info: &TxInfo) {
+        match &info.data {
+            TxInfoData::Plain(_) => self.plain_size -= info.size,
+            TxInfoData::Compressed(compressed) => {
+                self.compressed_size -= compressed.len();
+                self.uncompressed_size -= info.size;
+            }
+        }
+    }
+
+    fn observe(&self, gauge: &Gauge) {
+        gauge.set("plain", self.plain_size as f64);
+        gauge.set("compressed", self.compressed_size as f64);
+        gauge.set("uncompressed", self.uncompressed_size as f64);
+    }
+}
+
+struct MempoolAccept {
+    result: Value,
+    tip: BlockHash, // a new block invalidates the result
+    time: Instant,
+}
 
 pub(crate) struct Cache {
     txs: Arc<RwLock<HashMap<Txid, Transaction>>>,
+    tx_infos: Mutex<TxInfos>, // verbose transactions and their confirming blocks
+    mempool_accepts: Mutex<Lru<Wtxid, MempoolAccept>>,
+    min_confirmations: usize,
+    compression_threshold: Option<usize>, // compress verbose transactions larger than this size
 
     // stats
     txs_size: Histogram,
+    tx_infos_size: Gauge,
 }
 
 impl Cache {
-    pub fn new(metrics: &Metrics) -> Self {
+    pub fn new(
+        metrics: &Metrics,
+        min_confirmations: usize,
+        compression_threshold: Option<usize>,
+    ) -> Self {
         Cache {
             txs: Default::default(),
+            tx_infos: Mutex::new(TxInfos::new(TX_INFO_CACHE_SIZE)),
+            mempool_accepts: Mutex::new(Lru::new(MEMPOOL_ACCEPT_CACHE_SIZE)),
+            min_confirmations,
+            compression_threshold,
             txs_size: metrics.histogram_vec(
                 "cache_txs_size",
                 "Cached transactions' size (in bytes)",
                 "type",
                 metrics::default_size_buckets(),
             ),
+            tx_infos_size: metrics.gauge(
+                "cache_tx_infos_size",
+                "Memory used by cached verbose transactions' JSON (in bytes)",
+                "type",
+            ),
         }
     }
 
@@ -40,4 +186,71 @@ impl Cache {
     {
         self.txs.read().get(txid).map(f)
     }
+
+    /// Cache verbose transaction info, if it has at least `min_confirmations` (in current `chain`).
+    pub fn add_tx_info(&self, txid: Txid, info: &Value, chain: &Chain) {
+        let blockhash = match info["blockhash"].as_str().and_then(|h| h.parse().ok()) {
+            Some(blockhash) => blockhash,
+            None => return, // unconfirmed
+        };
+        match confirmations(chain, blockhash) {
+            Some(confirmations) if confirmations >= self.min_confirmations => {
+                let info = TxInfo::new(blockhash, info, self.compression_threshold);
+                self.tx_infos.lock().insert(txid, info, &self.tx_infos_size)
+            }
+            _ => (), // should be queried live, since it may be reorged out
+        }
+    }
+
+    /// Return cached verbose transaction info (updating its confirmations using current `chain`).
+    pub fn get_tx_info(&self, txid: &Txid, chain: &Chain) -> Option<Value> {
+        let mut tx_infos = self.tx_infos.lock();
+        let cached = tx_infos.lru.get(txid)?;
+        let blockhash = cached.blockhash;
+        let confirmations = match confirmations(chain, blockhash) {
+            Some(confirmations) => confirmations,
+            None => {
+                // the confirming block was reorged out
+                debug!("removing tx {} (reorged block {})", txid, blockhash);
+                tx_infos.remove(txid, &self.tx_infos_size);
+                return None;
+            }
+        };
+        match cached.value() {
+            Ok(mut info) => {
+                info["confirmations"] = json!(confirmations);
+                Some(info)
+            }
+            Err(e) => {
+                warn!("removing tx {}: {:#}", txid, e);
+                tx_infos.remove(txid, &self.tx_infos_size);
+                None
+            }
+        }
+    }
+
+    /// Cache `testmempoolaccept` result (until `MEMPOOL_ACCEPT_TTL` passes or a new block is found).
+    pub fn add_mempool_accept(&self, wtxid: Wtxid, tip: BlockHash, result: Value) {
+        let accept = MempoolAccept {
+            result,
+            tip,
+            time: Instant::now(),
+        };
+        self.mempool_accepts.lock().insert(wtxid, accept);
+    }
+
+    pub fn get_mempool_accept(&self, wtxid: &Wtxid, tip: BlockHash) -> Option<Value> {
+        let mut mempool_accepts = self.mempool_accepts.lock();
+        let accept = mempool_accepts.get(wtxid)?;
+        if accept.tip == tip && accept.time.elapsed() < MEMPOOL_ACCEPT_TTL {
+            return Some(accept.result.clone());
+        }
+        mempool_accepts.remove(wtxid); // stale result
+        None
+    }
+}
+
+fn confirmations(chain: &Chain, blockhash: BlockHash) -> Option<usize> {
+    let height = chain.get_block_height(blockhash)?;
+    Some(chain.height() + 1 - height)
 }
@@ -5,6 +5,9 @@ use bitcoin::{BlockHash, BlockHeader};
 
 use crate::types::{FilePosition, HeaderRow};
 
+/// Number of blocks used for computing median time past (see BIP113)
+const MEDIAN_TIME_SPAN: usize = 11;
+
 /// Current blockchain headers' list
 pub(crate) struct Chain {
     rows: Vec<HeaderRow>,
@@ -57,6 +60,11 @@ impl Chain {
         self.rows.get(height).map(|r| r.header.block_hash())
     }
 
+    /// Get the block header row at specified height (if exists)
+    pub(crate) fn get_header_row(&self, height: usize) -> Option<&HeaderRow> {
+        self.rows.get(height)
+    }
+
     /// Get the block hash at file position
     pub(crate) fn get_header_row_for(&self, pos: FilePosition) -> Option<&HeaderRow> {
         let range = (Bound::Unbounded, Bound::Included(&pos));
@@ -84,6 +92,92 @@ impl Chain {
         self.heights.get(&blockhash).copied()
     }
 
+    /// Get the median timestamp of the block at specified height and its 10 predecessors
+    /// (fewer near genesis), used for evaluating BIP113 timelocks.
+    pub(crate) fn get_median_time_past(&self, height: usize) -> Option<u32> {
+        let first = height.saturating_sub(MEDIAN_TIME_SPAN - 1);
+        let mut times: Vec<u32> = self
+            .rows
+            .get(first..=height)?
+            .iter()
+            .map(|row| row.header.time)
+            .collect();
+        times.sort_unstable();
+        Some(times[times.len() / 2])
+    }
+
+    /// Find the height of the last block whose timestamp is not after `time` (or `None` if the
+    /// genesis block is newer). Block timestamps are not strictly increasing, so the result is
+    /// approximate (within a few blocks).
+    pub(crate) fn find_block_by_time(&self, time: u32) -> Option<usize> {
+        // blocks below `lo` are not newer than `time`, and blocks from `hi` are newer
+        let (mut lo, mut hi) = (0, self.rows.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.rows[mid].header.time <= time {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo.checked_sub(1)
+    }
+
+    /// Find the height of the last block whose median time past is not after `time` (or `None` if
+    /// the genesis block is newer). Unlike block timestamps, MTP is non-decreasing (see BIP113), so
+    /// the result is exact - but it lags the blocks' timestamps by about an hour.
+    pub(crate) fn find_block_by_median_time(&self, time: u32) -> Option<usize> {
+        let (mut lo, mut hi) = (0, self.rows.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.get_median_time_past(mid).expect("missing header") <= time {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo.checked_sub(1)
+    }
+
+    /// Get the height and hash of the last block whose median time past (BIP113) is at or before
+    /// `unix_time`. MTP is used (instead of block timestamps, which are not monotonic) so the
+    /// result doesn't depend on miners' clocks. Earlier times return the genesis block, and later
+    /// times return the tip.
+    pub(crate) fn block_at_time(&self, unix_time: u32) -> (usize, BlockHash) {
+        let height = self.find_block_by_median_time(unix_time).unwrap_or(0);
+        (height, self.rows[height].hash)
+    }
+
+    /// Get the statistics of the last `window` intervals between blocks, using their headers'
+    /// timestamps (the window is truncated at the genesis block). Returns `None` for an empty
+    /// window, or if the chain has no blocks after genesis.
+    pub(crate) fn block_interval_stats(&self, window: usize) -> Option<IntervalStats> {
+        let tip = self.height();
+        if window == 0 || tip == 0 {
+            return None;
+        }
+        let times: Vec<u32> = self.rows[tip - window.min(tip)..=tip]
+            .iter()
+            .map(|row| row.header.time)
+            .collect();
+        Some(IntervalStats::from_times(&times))
+    }
+
+    /// Get the hashes of `count` blocks, walking back from `tip` (inclusive), so the tip comes
+    /// first. Fewer hashes are returned if genesis is reached, and `None` if `tip` is not in the
+    /// chain.
+    pub(crate) fn get_ancestors(&self, tip: BlockHash, count: usize) -> Option<Vec<BlockHash>> {
+        let height = self.get_block_height(tip)?;
+        let first = (height + 1).saturating_sub(count);
+        Some(
+            self.rows[first..=height]
+                .iter()
+                .rev()
+                .map(|row| row.hash)
+                .collect(),
+        )
+    }
+
     /// Update the chain with a list of new headers (possibly a reorg)
     pub(crate) fn update(&mut self, rows: Vec<HeaderRow>) {
         if rows.is_empty() {
@@ -134,29 +228,86 @@ impl Chain {
     /// List of block hashes for efficient fork detection and block/header sync
     /// see https://en.bitcoin.it/wiki/Protocol_documentation#getblocks
     pub(crate) fn locator(&self) -> Vec<BlockHash> {
-        let mut result = vec![];
-        let mut index = self.height();
-        let mut step = 1;
-        loop {
-            if result.len() >= 10 {
-                step *= 2;
-            }
-            result.push(self.rows[index].hash);
-            if index == 0 {
-                break;
-            }
-            index = index.saturating_sub(step);
+        self.locator_from(self.height())
+    }
+
+    /// Similar to `locator`, but starting at the block at `height` (instead of the tip)
+    pub(crate) fn locator_from(&self, height: usize) -> Vec<BlockHash> {
+        locator_heights(height)
+            .into_iter()
+            .map(|height| self.rows[height].hash)
+            .collect()
+    }
+}
+
+/// Statistics of the intervals (in seconds) between consecutive blocks
+#[derive(Debug, PartialEq)]
+pub(crate) struct IntervalStats {
+    pub(crate) count: usize, // number of intervals
+    pub(crate) mean: f64,
+    pub(crate) median: f64,
+    pub(crate) min: u32,
+    pub(crate) max: u32,
+    pub(crate) non_monotonic: usize, // intervals with an earlier block time (clamped to 0)
+}
+
+impl IntervalStats {
+    /// `times` are consecutive block timestamps (at least 2 of them).
+    fn from_times(times: &[u32]) -> Self {
+        let mut non_monotonic = 0;
+        let mut intervals: Vec<u32> = times
+            .windows(2)
+            .map(|pair| {
+                if pair[1] < pair[0] {
+                    non_monotonic += 1;
+                }
+                pair[1].saturating_sub(pair[0])
+            })
+            .collect();
+        intervals.sort_unstable();
+        let count = intervals.len();
+        let sum: u64 = intervals.iter().map(|&i| u64::from(i)).sum();
+        let median = if count % 2 == 1 {
+            f64::from(intervals[count / 2])
+        } else {
+            (f64::from(intervals[count / 2 - 1]) + f64::from(intervals[count / 2])) / 2.0
+        };
+        Self {
+            count,
+            mean: sum as f64 / count as f64,
+            median,
+            min: intervals[0],
+            max: intervals[count - 1],
+            non_monotonic,
+        }
+    }
+}
+
+/// Block heights of a locator starting at `height`: the 10 last blocks, followed by exponentially
+/// spaced ones (and ending with the genesis block)
+pub(crate) fn locator_heights(height: usize) -> Vec<usize> {
+    let mut result = vec![];
+    let mut index = height;
+    let mut step = 1;
+    loop {
+        if result.len() >= 10 {
+            step *= 2;
         }
-        result
+        result.push(index);
+        if index == 0 {
+            break;
+        }
+        index = index.saturating_sub(step);
     }
+    result
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Chain, FilePosition, HeaderRow};
+    use super::{locator_heights, Chain, FilePosition, HeaderRow, IntervalStats};
     use bitcoin::consensus::deserialize;
     use bitcoin::hashes::hex::{FromHex, ToHex};
-    use bitcoin::Block;
+    use bitcoin::{Block, BlockHash};
 
     fn regtest_genesis() -> HeaderRow {
         let block_bytes = Vec::from_hex("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f20020000000101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000").unwrap();
@@ -182,6 +333,161 @@ mod tests {
         );
     }
 
+    fn chain_with_times(genesis: &HeaderRow, times: &[u32]) -> Chain {
+        let mut chain = Chain::new(genesis.clone());
+        let mut prev_blockhash = genesis.hash;
+        let rows = times
+            .iter()
+            .zip(1u32..)
+            .map(|(time, i)| {
+                let mut header = genesis.header;
+                header.prev_blockhash = prev_blockhash;
+                header.time = *time;
+                prev_blockhash = header.block_hash();
+                HeaderRow {
+                    header,
+                    hash: header.block_hash(),
+                    pos: FilePosition {
+                        file_id: 0,
+                        offset: i * 1000,
+                    },
+                    size: 100,
+                }
+            })
+            .collect();
+        chain.update(rows);
+        chain
+    }
+
+    #[test]
+    fn test_median_time_past() {
+        let genesis = regtest_genesis();
+        let regtest = chain_with_times(&genesis, &[5, 1, 4, 2, 3, 10, 9, 8, 7, 6, 11, 12]);
+
+        // fewer than 11 blocks are available near genesis
+        assert_eq!(regtest.get_median_time_past(0), Some(genesis.header.time));
+        assert_eq!(regtest.get_median_time_past(2), Some(5));
+        assert_eq!(regtest.get_median_time_past(11), Some(6));
+        assert_eq!(regtest.get_median_time_past(12), Some(7));
+        assert_eq!(regtest.get_median_time_past(13), None);
+    }
+
+    #[test]
+    fn test_find_block_by_time() {
+        let genesis = regtest_genesis();
+        let t = genesis.header.time;
+        let times: Vec<u32> = [10, 20, 15, 30, 40, 50].iter().map(|dt| t + dt).collect();
+        let regtest = chain_with_times(&genesis, &times);
+
+        assert_eq!(regtest.find_block_by_time(t - 1), None);
+        assert_eq!(regtest.find_block_by_time(t), Some(0));
+        assert_eq!(regtest.find_block_by_time(t + 9), Some(0));
+        assert_eq!(regtest.find_block_by_time(t + 25), Some(3));
+        assert_eq!(regtest.find_block_by_time(t + 40), Some(5));
+        assert_eq!(regtest.find_block_by_time(t + 45), Some(5));
+        assert_eq!(regtest.find_block_by_time(u32::MAX), Some(6));
+    }
+
+    #[test]
+    fn test_find_block_by_median_time() {
+        let genesis = regtest_genesis();
+        let t = genesis.header.time;
+        let times: Vec<u32> = [10, 20, 15, 30, 40, 50].iter().map(|dt| t + dt).collect();
+        let regtest = chain_with_times(&genesis, &times);
+
+        // median times past: t, t+10, t+10, t+15, t+15, t+20, t+20
+        assert_eq!(regtest.find_block_by_median_time(t - 1), None);
+        assert_eq!(regtest.find_block_by_median_time(t), Some(0));
+        assert_eq!(regtest.find_block_by_median_time(t + 9), Some(0));
+        assert_eq!(regtest.find_block_by_median_time(t + 10), Some(2));
+        assert_eq!(regtest.find_block_by_median_time(t + 19), Some(4));
+        assert_eq!(regtest.find_block_by_median_time(t + 20), Some(6));
+        assert_eq!(regtest.find_block_by_median_time(u32::MAX), Some(6));
+
+        assert_eq!(regtest.block_at_time(t - 1), (0, genesis.hash));
+        assert_eq!(
+            regtest.block_at_time(t + 19),
+            (4, regtest.get_block_hash(4).unwrap())
+        );
+        assert_eq!(regtest.block_at_time(u32::MAX), (6, regtest.tip()));
+    }
+
+    #[test]
+    fn test_interval_stats() {
+        let stats = IntervalStats::from_times(&[1000, 1600, 1500, 2400, 2500]);
+        assert_eq!(
+            stats,
+            IntervalStats {
+                count: 4,
+                mean: 400.0,
+                median: 350.0,
+                min: 0,
+                max: 900,
+                non_monotonic: 1,
+            }
+        );
+        let stats = IntervalStats::from_times(&[1000, 1300, 1900, 2000]);
+        assert_eq!((stats.median, stats.min, stats.max), (300.0, 100, 600));
+        assert_eq!(stats.non_monotonic, 0);
+    }
+
+    #[test]
+    fn test_block_interval_stats() {
+        let genesis = regtest_genesis();
+        let t = genesis.header.time;
+        let regtest = Chain::new(genesis.clone());
+        assert_eq!(regtest.block_interval_stats(1), None);
+
+        let regtest = chain_with_times(&genesis, &[t + 600, t + 500, t + 1400]);
+        assert_eq!(regtest.block_interval_stats(0), None);
+        let stats = regtest.block_interval_stats(2).unwrap();
+        assert_eq!((stats.count, stats.min, stats.max), (2, 0, 900));
+        let stats = regtest.block_interval_stats(10).unwrap(); // truncated at genesis
+        assert_eq!((stats.count, stats.median), (3, 600.0));
+        assert_eq!(stats.non_monotonic, 1);
+    }
+
+    #[test]
+    fn test_get_ancestors() {
+        let genesis = regtest_genesis();
+        let regtest = chain_with_times(&genesis, &[1, 2, 3]);
+        let hash = |height| regtest.get_block_hash(height).unwrap();
+
+        assert_eq!(
+            regtest.get_ancestors(hash(3), 2),
+            Some(vec![hash(3), hash(2)])
+        );
+        assert_eq!(
+            regtest.get_ancestors(hash(1), 5),
+            Some(vec![hash(1), genesis.hash])
+        );
+        assert_eq!(regtest.get_ancestors(hash(2), 0), Some(vec![]));
+        assert_eq!(regtest.get_ancestors(BlockHash::default(), 1), None);
+    }
+
+    #[test]
+    fn test_locator() {
+        assert_eq!(locator_heights(0), vec![0]);
+        assert_eq!(locator_heights(5), vec![5, 4, 3, 2, 1, 0]);
+        let mut expected: Vec<usize> = (91..=100).rev().collect();
+        expected.extend(&[90, 88, 84, 76, 60, 28, 0]);
+        assert_eq!(locator_heights(100), expected);
+
+        let genesis = regtest_genesis();
+        let regtest = chain_with_times(&genesis, &[1; 12]);
+        let hashes = |heights: Vec<usize>| -> Vec<_> {
+            heights
+                .into_iter()
+                .map(|height| regtest.get_block_hash(height).unwrap())
+                .collect()
+        };
+        assert_eq!(regtest.locator(), hashes(locator_heights(12)));
+        assert_eq!(
+            regtest.locator_from(7),
+            hashes(vec![7, 6, 5, 4, 3, 2, 1, 0])
+        );
+    }
+
     // created with `generateblock ADDR 10`
     const HEX_BLOCKS: &[&str] = &[
         "0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910fc98e8631211374711ec913c13da27c0ee394227e4bedcd959e80c027da9207afe2fbe361ffff7f200000000001020000000001010000000000000000000000000000000000000000000000000000000000000000ffffffff03510101ffffffff0200f2052a010000001976a9147f95f4c31a3a70f2c3661573a7d2926b451d760d88ac0000000000000000266a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf90120000000000000000000000000000000000000000000000000000000000000000000000000",
@@ -1,7 +1,10 @@
-use bitcoin::network::constants::Network;
+use bitcoin::network::constants::{Network, ServiceFlags};
 use bitcoincore_rpc::Auth;
 use dirs_next::home_dir;
+use percent_encoding::percent_decode_str;
+use url::Url;
 
+use std::collections::HashSet;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::net::SocketAddr;
@@ -12,6 +15,16 @@ use std::str::FromStr;
 use std::env::consts::{ARCH, OS};
 use std::time::Duration;
 
+use crate::{
+    breaker::BreakerPolicy,
+    daemon::{is_unsafe_rpc_method, BlockSourceKind},
+    db::DbSyncMode,
+    fees::FeeRateUnit,
+    proxy::HttpProxy,
+    retry::RetryPolicy,
+    throttle::BlockReadThrottle,
+};
+
 pub const ELECTRS_VERSION: &str = env!("CARGO_PKG_VERSION");
 const DEFAULT_SERVER_ADDRESS: [u8; 4] = [127, 0, 0, 1]; // by default, serve on IPv4 localhost
 
@@ -127,23 +140,68 @@ impl From<BitcoinNetwork> for Network {
 pub struct Config {
     // See below for the documentation of each field:
     pub network: Network,
+    pub allowed_networks: Vec<Network>, // empty if all networks are allowed
     pub db_path: PathBuf,
     pub daemon_dir: PathBuf,
     pub daemon_auth: SensitiveAuth,
     pub daemon_rpc_addr: SocketAddr,
+    pub daemon_rest_addr: SocketAddr, // used if `block_source` is REST
+    pub block_source: BlockSourceKind,
+    pub daemon_http_proxy: Option<HttpProxy>,
+    pub broadcast_backends: Vec<BroadcastBackend>,
+    pub broadcast_idempotent: bool,
     pub daemon_p2p_addr: SocketAddr,
+    pub required_services: ServiceFlags,
     pub electrum_rpc_addr: SocketAddr,
     pub monitoring_addr: SocketAddr,
-    pub blocks_dir: PathBuf,
+    pub blocks_dirs: Vec<PathBuf>, // bitcoind's blocks directory, followed by the extra ones
+    pub mmap_block_files: bool,
+    pub mmap_max_block_files: usize,
+    pub block_prefetch_window: usize, // 0 if disabled
+    pub max_open_block_files: usize,  // 0 if disabled
+    pub validate_block_sizes: bool,
+    pub block_read_buffer_size: usize, // 0 if disabled
     pub wait_duration: Duration,
     pub jsonrpc_timeout: Duration,
+    pub jsonrpc_block_timeout: Duration,
+    pub jsonrpc_wait_timeout: Duration,
+    pub p2p_handshake_timeout: Duration,
+    pub stale_tip_threshold: Option<Duration>,
+    pub p2p_watchdog_threshold: Option<Duration>,
+    pub tip_lag_threshold: Option<usize>,
+    pub tip_lag_grace_period: Duration,
+    pub retry_policy: RetryPolicy,
+    pub breaker_policy: Option<BreakerPolicy>, // `None` if the circuit breaker is disabled
+    pub cache_min_confirmations: usize,
+    pub tx_info_compression_threshold: Option<usize>, // None if compression is disabled
+    pub coinbase_maturity: Option<usize>,             // None if listunspent annotation is disabled
+    pub fee_rate_unit: FeeRateUnit,
+    pub script_asm: bool,
     pub index_batch_size: usize,
+    pub db_sync_mode: DbSyncMode,
+    pub index_tx_parallelism: usize,
+    pub index_cpu_cores: Vec<usize>, // empty if indexing threads are not pinned
+    pub block_read_throttle: Option<BlockReadThrottle>,
+    pub min_free_disk_bytes: Option<u64>, // None if disk space is not checked
+    pub block_filter_size: Option<usize>, // None if block filters are disabled
+    pub index_balances: bool,
     pub index_lookup_limit: Option<usize>,
     pub reindex_last_blocks: usize,
     pub auto_reindex: bool,
+    pub skip_daemon_version_check: bool,
     pub ignore_mempool: bool,
+    pub mempool_only: bool,
+    pub mempool_scan_batch_size: Option<usize>,
+    pub max_mempool_query_txids: Option<usize>, // None if unlimited
+    pub max_history_txs: Option<usize>,         // None if unlimited
     pub sync_once: bool,
     pub disable_electrum_rpc: bool,
+    pub enable_admin_rpc: bool,
+    pub verify_continue_on_error: bool,
+    pub enable_estimate_raw_fee: bool,
+    pub utxo_set_info_ttl: Option<Duration>, // None if `gettxoutsetinfo` is disabled
+    pub client_rpc_accounting: bool,
+    pub rpc_passthrough_whitelist: HashSet<String>,
     pub server_banner: String,
     pub args: Vec<String>,
 }
@@ -169,6 +227,66 @@ impl fmt::Debug for SensitiveAuth {
     }
 }
 
+/// Additional bitcoind JSONRPC backend, used for broadcasting transactions
+#[derive(Clone)]
+pub struct BroadcastBackend {
+    pub addr: String, // 'host:port'
+    pub auth: Option<(String, String)>,
+}
+
+/// Parse a comma-separated list of p2p service names (e.g. "network,witness").
+fn parse_service_flags(names: &str) -> Result<ServiceFlags, String> {
+    let mut flags = ServiceFlags::NONE;
+    for name in names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        flags = flags
+            | match name.to_ascii_lowercase().as_str() {
+                "network" => ServiceFlags::NETWORK,
+                "getutxo" => ServiceFlags::GETUTXO,
+                "bloom" => ServiceFlags::BLOOM,
+                "witness" => ServiceFlags::WITNESS,
+                "compact_filters" => ServiceFlags::COMPACT_FILTERS,
+                "network_limited" => ServiceFlags::NETWORK_LIMITED,
+                _ => return Err(format!("unknown service: {:?}", name)),
+            };
+    }
+    Ok(flags)
+}
+
+impl BroadcastBackend {
+    fn parse(url: &str) -> Result<Self, String> {
+        let url = Url::parse(url).map_err(|e| e.to_string())?;
+        if url.scheme() != "http" {
+            return Err(format!("unsupported scheme: {}", url.scheme()));
+        }
+        let host = url.host_str().ok_or("missing host")?;
+        let port = url.port().ok_or("missing port")?;
+        let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().into_owned();
+        let auth = if url.username().is_empty() {
+            None
+        } else {
+            let password = url.password().unwrap_or("");
+            Some((decode(url.username()), decode(password)))
+        };
+        Ok(Self {
+            addr: format!("{}:{}", host, port),
+            auth,
+        })
+    }
+}
+
+impl fmt::Debug for BroadcastBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.auth {
+            Some((ref user, _)) => write!(f, "http://{}:<sensitive>@{}", user, self.addr),
+            None => write!(f, "http://{}", self.addr),
+        }
+    }
+}
+
 /// Returns default daemon directory
 fn default_daemon_dir() -> PathBuf {
     let mut home = home_dir().unwrap_or_else(|| {
@@ -179,6 +297,13 @@ fn default_daemon_dir() -> PathBuf {
     home
 }
 
+/// Returns the number of confirmations required for spending coinbase outputs
+fn default_coinbase_maturity(network: Network) -> usize {
+    match network {
+        Network::Bitcoin | Network::Testnet | Network::Regtest | Network::Signet => 100,
+    }
+}
+
 fn default_config_files() -> Vec<OsString> {
     #[cfg(not(feature = "ignore_default_config_files"))]
     {
@@ -244,6 +369,75 @@ impl Config {
             (DEFAULT_SERVER_ADDRESS, default_daemon_rpc_port).into(),
             ResolvAddr::resolve_or_exit,
         );
+        if config.daemon_rest_addr.is_some() && config.block_source != BlockSourceKind::Rest {
+            eprintln!("Error: daemon_rest_addr requires block_source = 'rest'");
+            std::process::exit(1);
+        }
+        let daemon_rest_addr: SocketAddr = config
+            .daemon_rest_addr
+            .map_or(daemon_rpc_addr, ResolvAddr::resolve_or_exit);
+        let daemon_http_proxy = config.daemon_http_proxy.map(|url| {
+            HttpProxy::parse(&url).unwrap_or_else(|err| {
+                eprintln!("Error: invalid daemon_http_proxy: {}", err);
+                std::process::exit(1)
+            })
+        });
+        let broadcast_backends = config
+            .broadcast_rpc_urls
+            .as_deref()
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(|url| {
+                BroadcastBackend::parse(url).unwrap_or_else(|err| {
+                    eprintln!("Error: invalid broadcast_rpc_urls: {}", err);
+                    std::process::exit(1)
+                })
+            })
+            .collect();
+        let allowed_networks = config
+            .allowed_networks
+            .as_deref()
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                Network::from_str(name).unwrap_or_else(|_| {
+                    eprintln!(
+                        "Error: invalid allowed_networks: unknown network {:?}",
+                        name
+                    );
+                    std::process::exit(1)
+                })
+            })
+            .collect();
+        let index_cpu_cores: Vec<usize> = config
+            .index_cpu_cores
+            .as_deref()
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|core| !core.is_empty())
+            .map(|core| {
+                core.parse().unwrap_or_else(|_| {
+                    eprintln!("Error: invalid index_cpu_cores: {:?} is not a core", core);
+                    std::process::exit(1)
+                })
+            })
+            .collect();
+        if !index_cpu_cores.is_empty() && config.index_tx_parallelism == 1 {
+            eprintln!("Error: index_cpu_cores requires index_tx_parallelism != 1 (blocks are indexed inline otherwise)");
+            std::process::exit(1);
+        }
+        let required_services = parse_service_flags(
+            config.required_services.as_deref().unwrap_or(""),
+        )
+        .unwrap_or_else(|err| {
+            eprintln!("Error: invalid required_services: {}", err);
+            std::process::exit(1)
+        });
         let daemon_p2p_addr: SocketAddr = config.daemon_p2p_addr.map_or(
             (DEFAULT_SERVER_ADDRESS, default_daemon_p2p_port).into(),
             ResolvAddr::resolve_or_exit,
@@ -272,7 +466,18 @@ impl Config {
         }
 
         let daemon_dir = &config.daemon_dir;
-        let blocks_dir = daemon_dir.join("blocks");
+        let blocks_dirs: Vec<PathBuf> = std::iter::once(daemon_dir.join("blocks"))
+            .chain(
+                config
+                    .extra_blocks_dirs
+                    .as_deref()
+                    .unwrap_or("")
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|dir| !dir.is_empty())
+                    .map(PathBuf::from),
+            )
+            .collect();
         let daemon_auth = SensitiveAuth(match (config.auth, config.cookie_file) {
             (None, None) => Auth::CookieFile(daemon_dir.join(".cookie")),
             (None, Some(cookie_file)) => Auth::CookieFile(cookie_file),
@@ -301,6 +506,50 @@ impl Config {
             _ => Some(config.index_lookup_limit),
         };
 
+        let block_filter_size = match config.block_filter_bytes {
+            0 => None,
+            bytes => Some(bytes),
+        };
+
+        let min_free_disk_bytes = match config.min_free_disk_bytes {
+            0 => None,
+            bytes => Some(bytes),
+        };
+
+        let max_mempool_query_txids = match config.max_mempool_query_txids {
+            0 => None,
+            count => Some(count),
+        };
+
+        let max_history_txs = match config.max_history_txs {
+            0 => None,
+            count => Some(count),
+        };
+
+        let mempool_scan_batch_size = match config.mempool_scan_batch_size {
+            0 => None,
+            _ => Some(config.mempool_scan_batch_size),
+        };
+
+        let stale_tip_threshold = match config.stale_tip_threshold_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+
+        let p2p_watchdog_threshold = match config.p2p_watchdog_block_intervals {
+            0 => None,
+            n => {
+                let spacing =
+                    bitcoin::consensus::params::Params::new(config.network).pow_target_spacing;
+                Some(Duration::from_secs(n.saturating_mul(spacing)))
+            }
+        };
+
+        let tip_lag_threshold = match config.tip_lag_threshold_blocks {
+            0 => None,
+            blocks => Some(blocks),
+        };
+
         if config.jsonrpc_timeout_secs <= config.wait_duration_secs {
             eprintln!(
                 "Error: jsonrpc_timeout_secs ({}) must be higher than wait_duration_secs ({})",
@@ -309,6 +558,144 @@ impl Config {
             std::process::exit(1);
         }
 
+        if config.p2p_handshake_timeout_secs == 0 {
+            eprintln!("Error: p2p_handshake_timeout_secs must be positive");
+            std::process::exit(1);
+        }
+
+        if config.retry_max_attempts == 0 {
+            eprintln!("Error: retry_max_attempts must be positive");
+            std::process::exit(1);
+        }
+        if config.retry_max_delay_ms < config.retry_base_delay_ms {
+            eprintln!(
+                "Error: retry_max_delay_ms ({}) must not be less than retry_base_delay_ms ({})",
+                config.retry_max_delay_ms, config.retry_base_delay_ms
+            );
+            std::process::exit(1);
+        }
+        if !config.retry_multiplier.is_finite() || config.retry_multiplier < 1.0 {
+            eprintln!(
+                "Error: retry_multiplier ({}) must be a finite number, at least 1",
+                config.retry_multiplier
+            );
+            std::process::exit(1);
+        }
+        if !(0.0..=1.0).contains(&config.retry_jitter) {
+            eprintln!(
+                "Error: retry_jitter ({}) must be between 0 and 1",
+                config.retry_jitter
+            );
+            std::process::exit(1);
+        }
+        let retry_policy = RetryPolicy {
+            base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            max_delay: Duration::from_millis(config.retry_max_delay_ms),
+            multiplier: config.retry_multiplier,
+            max_attempts: config.retry_max_attempts,
+            jitter: config.retry_jitter,
+        };
+        let breaker_policy = match config.rpc_breaker_max_failures {
+            0 => None,
+            max_failures => Some(BreakerPolicy {
+                max_failures,
+                window: Duration::from_secs(config.rpc_breaker_window_secs),
+                cooldown: Duration::from_secs(config.rpc_breaker_cooldown_secs),
+            }),
+        };
+
+        let rate_limit = |name: &str, rate: f64| {
+            if rate.is_nan() || rate < 0.0 {
+                eprintln!("Error: {} ({}) must not be negative", name, rate);
+                std::process::exit(1);
+            }
+            if rate > 0.0 {
+                Some(rate)
+            } else {
+                None
+            }
+        };
+        let throttle = BlockReadThrottle {
+            max_blocks_per_sec: rate_limit(
+                "block_read_max_blocks_per_sec",
+                config.block_read_max_blocks_per_sec,
+            ),
+            max_bytes_per_sec: rate_limit(
+                "block_read_max_mb_per_sec",
+                config.block_read_max_mb_per_sec,
+            )
+            .map(|mb| mb * 1e6),
+        };
+        let block_read_throttle =
+            if throttle.max_blocks_per_sec.is_some() || throttle.max_bytes_per_sec.is_some() {
+                Some(throttle)
+            } else {
+                None
+            };
+
+        let rpc_passthrough_whitelist: HashSet<String> = config
+            .rpc_passthrough_whitelist
+            .as_deref()
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|method| !method.is_empty())
+            .map(str::to_owned)
+            .collect();
+        if !config.rpc_passthrough_allow_unsafe {
+            for method in &rpc_passthrough_whitelist {
+                if is_unsafe_rpc_method(method) {
+                    eprintln!(
+                        "Error: whitelisting {:?} requires rpc_passthrough_allow_unsafe",
+                        method
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        let coinbase_maturity = if config.annotate_coinbase_maturity {
+            let window = config
+                .coinbase_maturity
+                .unwrap_or_else(|| default_coinbase_maturity(config.network));
+            if window == 0 {
+                eprintln!("Error: coinbase_maturity must be positive");
+                std::process::exit(1);
+            }
+            Some(window)
+        } else {
+            None
+        };
+
+        let tx_info_compression_threshold = match config.tx_info_cache_compression_threshold {
+            0 => None,
+            threshold => Some(threshold),
+        };
+
+        if config.cache_min_confirmations == 0 {
+            eprintln!("Error: cache_min_confirmations must be positive");
+            std::process::exit(1);
+        }
+
+        if config.mempool_only && config.ignore_mempool {
+            eprintln!("Error: mempool_only and ignore_mempool can't be specified at the same time");
+            std::process::exit(1);
+        }
+
+        if config.mempool_only && config.index_balances {
+            eprintln!(
+                "Error: index_balances requires indexing blocks (not supported by mempool_only)"
+            );
+            std::process::exit(1);
+        }
+
+        if config.block_source == BlockSourceKind::Rest && config.index_balances {
+            eprintln!(
+                "Error: index_balances requires block undo data from rev*.dat files (not available with block_source = 'rest')"
+            );
+            std::process::exit(1);
+        }
+
         if config.version {
             println!("v{}", ELECTRS_VERSION);
             std::process::exit(0);
@@ -316,23 +703,72 @@ impl Config {
 
         let config = Config {
             network: config.network,
+            allowed_networks,
             db_path: config.db_dir,
             daemon_dir: config.daemon_dir,
-            blocks_dir,
+            blocks_dirs,
+            mmap_block_files: config.mmap_block_files,
+            mmap_max_block_files: config.mmap_max_block_files,
+            block_prefetch_window: config.block_prefetch_window,
+            max_open_block_files: config.max_open_block_files,
+            validate_block_sizes: config.validate_block_sizes,
+            block_read_buffer_size: config.block_read_buffer_bytes,
             daemon_auth,
             daemon_rpc_addr,
+            daemon_rest_addr,
+            block_source: config.block_source,
+            daemon_http_proxy,
+            broadcast_backends,
+            broadcast_idempotent: config.broadcast_idempotent,
             daemon_p2p_addr,
+            required_services,
             electrum_rpc_addr,
             monitoring_addr,
             wait_duration: Duration::from_secs(config.wait_duration_secs),
             jsonrpc_timeout: Duration::from_secs(config.jsonrpc_timeout_secs),
+            jsonrpc_block_timeout: Duration::from_secs(config.jsonrpc_block_timeout_secs),
+            jsonrpc_wait_timeout: Duration::from_secs(config.jsonrpc_wait_timeout_secs),
+            p2p_handshake_timeout: Duration::from_secs(config.p2p_handshake_timeout_secs),
+            stale_tip_threshold,
+            p2p_watchdog_threshold,
+            tip_lag_threshold,
+            tip_lag_grace_period: Duration::from_secs(config.tip_lag_grace_secs),
+            retry_policy,
+            breaker_policy,
+            coinbase_maturity,
+            cache_min_confirmations: config.cache_min_confirmations,
+            tx_info_compression_threshold,
+            fee_rate_unit: config.fee_rate_unit,
+            script_asm: config.script_asm,
             index_batch_size: config.index_batch_size,
+            db_sync_mode: config.db_sync_mode,
+            index_tx_parallelism: config.index_tx_parallelism,
+            index_cpu_cores,
+            block_read_throttle,
+            min_free_disk_bytes,
+            block_filter_size,
+            index_balances: config.index_balances,
             index_lookup_limit,
             reindex_last_blocks: config.reindex_last_blocks,
             auto_reindex: config.auto_reindex,
+            skip_daemon_version_check: config.skip_daemon_version_check,
             ignore_mempool: config.ignore_mempool,
+            mempool_only: config.mempool_only,
+            mempool_scan_batch_size,
+            max_mempool_query_txids,
+            max_history_txs,
             sync_once: config.sync_once,
             disable_electrum_rpc: config.disable_electrum_rpc,
+            enable_admin_rpc: config.enable_admin_rpc,
+            verify_continue_on_error: config.verify_continue_on_error,
+            enable_estimate_raw_fee: config.enable_estimate_raw_fee,
+            utxo_set_info_ttl: if config.enable_utxo_set_info {
+                Some(Duration::from_secs(config.utxo_set_info_ttl_secs))
+            } else {
+                None
+            },
+            client_rpc_accounting: config.client_rpc_accounting,
+            rpc_passthrough_whitelist,
             server_banner: config.server_banner,
             args: args.map(|a| a.into_string().unwrap()).collect(),
         };
@@ -353,9 +789,19 @@ impl Config {
 
 #[cfg(test)]
 mod tests {
-    use super::{Auth, SensitiveAuth};
+    use super::{parse_service_flags, Auth, SensitiveAuth, ServiceFlags};
     use std::path::Path;
 
+    #[test]
+    fn test_parse_service_flags() {
+        assert_eq!(parse_service_flags(""), Ok(ServiceFlags::NONE));
+        assert_eq!(
+            parse_service_flags("network, WITNESS"),
+            Ok(ServiceFlags::NETWORK | ServiceFlags::WITNESS)
+        );
+        assert!(parse_service_flags("network,segwit").is_err());
+    }
+
     #[test]
     fn test_auth_debug() {
         let auth = Auth::None;
@@ -1,29 +1,89 @@
 use anyhow::{Context, Result};
 
 use bitcoin::{
-    consensus::{serialize, Decodable},
-    hashes::hex::ToHex,
-    Amount, Block, BlockHash, Transaction, Txid,
+    consensus::{deserialize, encode, serialize, Decodable},
+    hashes::hex::{FromHex, ToHex},
+    util::uint::Uint256,
+    Amount, Block, BlockHash, BlockHeader, Network, OutPoint, Script, Transaction, TxOut, Txid,
+    VarInt,
 };
-use bitcoincore_rpc::{json, jsonrpc, Auth, Client, RpcApi};
-use crossbeam_channel::Receiver;
+use bitcoincore_rpc::{json, jsonrpc, jsonrpc::Transport, Auth, Client, RpcApi};
+use crossbeam_channel::{bounded, Receiver};
 use parking_lot::Mutex;
-use serde_json::{json, Value};
+use rayon::prelude::*;
+use serde::de::DeserializeOwned;
+use serde_json::{json, value::to_raw_value, Value};
 
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::os::unix::{fs::FileExt, io::AsRawFd};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{error, fmt};
 
 use crate::{
-    chain::Chain,
-    config::Config,
-    metrics::Metrics,
-    p2p::Connection,
+    breaker::{BreakerTransport, CircuitBreaker},
+    chain::{locator_heights, Chain},
+    config::{BroadcastBackend, Config},
+    lru::Lru,
+    metrics::{Counter, Gauge, Metrics},
+    p2p::{Connection, ShutdownHandle},
+    proxy::ProxyTransport,
+    rest::RestBlockSource,
+    retry::RetryPolicy,
+    script::{script_to_asm, tx_sigop_cost, OutputType},
     signals::ExitFlag,
+    thread::spawn,
     types::{FilePosition, HeaderRow},
+    undo::parse_block_undo,
 };
 
+/// Maximum number of distinct clients accounted by `daemon_client_rpcs` metric (the RPCs of
+/// clients seen later are accounted as "other", to bound the metric's cardinality)
+const MAX_CLIENT_RPC_TAGS: usize = 100;
+
+/// Maximum number of requests to send in a single JSON-RPC batch
+const RPC_BATCH_SIZE: usize = 100;
+
+/// Maximum number of bytes to read using `BlockSource::read_range`
+const MAX_READ_RANGE: usize = 1 << 20;
+
+/// Maximum serialized block size (used for validating block sizes in `blk*.dat` files)
+const MAX_BLOCK_SIZE: u32 = 4_000_000;
+
+/// Maximum number of verbose blocks to cache (verbosity 2 blocks may take a few MBs)
+const VERBOSE_BLOCK_CACHE_SIZE: usize = 16;
+
+/// Maximum number of `getblockstats` sizes to cache (for validating blocks read from disk)
+const BLOCK_SIZE_CACHE_SIZE: usize = 1000;
+
+/// Maximum number of confirmed transactions' fee rates to cache
+const TX_FEE_RATE_CACHE_SIZE: usize = 10_000;
+
+/// Maximum number of coinbase messages to cache (coinbase scriptSig takes up to 100 bytes)
+const COINBASE_MESSAGE_CACHE_SIZE: usize = 1000;
+
+/// Maximum number of blocks' weight and sigops to cache
+const BLOCK_WEIGHT_SIGOPS_CACHE_SIZE: usize = 10_000;
+
+/// Maximum number of headers in a p2p `headers` message
+const MAX_HEADERS: usize = 2000;
+
+/// Block space available for mempool transactions (bitcoind's default `-blockmaxweight` is
+/// 3,996,000 weight units, reserving the rest for the block header and coinbase)
+const MAX_BLOCK_VSIZE: u64 = 999_000;
+
+/// Duration to reuse the mempool txids fetched for bulk membership checks (at the same best block)
+const MEMPOOL_TXIDS_CACHE_TTL: Duration = Duration::from_secs(10);
+
 enum PollResult {
     Done(Result<()>),
     Retry,
@@ -77,41 +137,499 @@ fn read_cookie(path: &Path) -> Result<(String, String)> {
     Ok((parts[0].to_owned(), parts[1].to_owned()))
 }
 
-fn rpc_connect(config: &Config) -> Result<Client> {
+/// Wrap the transport with the circuit breaker (if enabled).
+fn rpc_client(transport: impl Transport, breaker: Option<&Arc<CircuitBreaker>>) -> Client {
+    Client::from_jsonrpc(match breaker {
+        Some(breaker) => {
+            jsonrpc::Client::with_transport(BreakerTransport::new(transport, Arc::clone(breaker)))
+        }
+        None => jsonrpc::Client::with_transport(transport),
+    })
+}
+
+fn rpc_connect(
+    config: &Config,
+    timeout: Duration,
+    breaker: Option<&Arc<CircuitBreaker>>,
+) -> Result<Client> {
+    let auth = match config.daemon_auth.get_auth() {
+        Auth::None => None,
+        Auth::UserPass(user, pass) => Some((user, pass)),
+        Auth::CookieFile(path) => Some(read_cookie(&path)?),
+    };
+    if let Some(proxy) = &config.daemon_http_proxy {
+        let transport = ProxyTransport::new(proxy.clone(), config.daemon_rpc_addr, auth, timeout);
+        return Ok(rpc_client(transport, breaker));
+    }
     let rpc_url = format!("http://{}", config.daemon_rpc_addr);
-    // Allow `wait_for_new_block` to take a bit longer before timing out.
-    // See https://github.com/romanz/electrs/issues/495 for more details.
     let builder = jsonrpc::simple_http::SimpleHttpTransport::builder()
         .url(&rpc_url)?
-        .timeout(config.jsonrpc_timeout);
-    let builder = match config.daemon_auth.get_auth() {
-        Auth::None => builder,
-        Auth::UserPass(user, pass) => builder.auth(user, Some(pass)),
-        Auth::CookieFile(path) => {
-            let (user, pass) = read_cookie(&path)?;
-            builder.auth(user, Some(pass))
-        }
+        .timeout(timeout);
+    let builder = match auth {
+        None => builder,
+        Some((user, pass)) => builder.auth(user, Some(pass)),
+    };
+    Ok(rpc_client(builder.build(), breaker))
+}
+
+fn broadcast_connect(backend: &BroadcastBackend, timeout: Duration) -> Result<Client> {
+    let rpc_url = format!("http://{}", backend.addr);
+    let builder = jsonrpc::simple_http::SimpleHttpTransport::builder()
+        .url(&rpc_url)?
+        .timeout(timeout);
+    let builder = match &backend.auth {
+        None => builder,
+        Some((user, pass)) => builder.auth(user, Some(pass)),
     };
     Ok(Client::from_jsonrpc(jsonrpc::Client::with_transport(
         builder.build(),
     )))
 }
 
+/// A readable and seekable `blk*.dat` file.
+pub(crate) trait BlockFile: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> BlockFile for T {}
+
+/// Read-only memory mapping of a whole file.
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only, so it can be shared between threads.
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    fn open(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let len = usize::try_from(file.metadata()?.len())?;
+        if len == 0 {
+            // `mmap` doesn't support empty mappings
+            return Ok(Self {
+                ptr: std::ptr::null_mut(),
+                len,
+            });
+        }
+        // Note: bitcoind only appends to block files (truncating them while mapped would crash electrs).
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("failed to mmap {}", path.display()));
+        }
+        Ok(Self { ptr, len })
+    }
+}
+
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+/// A block read into memory, seekable using its original `blk*.dat` file offsets.
+pub(crate) struct PrefetchedBlock {
+    data: Cursor<Vec<u8>>,
+    offset: u64, // of the block's first byte
+}
+
+impl PrefetchedBlock {
+    pub(crate) fn new(data: Vec<u8>, offset: u64) -> Self {
+        Self {
+            data: Cursor::new(data),
+            offset,
+        }
+    }
+}
+
+impl Read for PrefetchedBlock {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl Seek for PrefetchedBlock {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => SeekFrom::Start(n.checked_sub(self.offset).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "seeking before block start",
+                )
+            })?),
+            pos => pos,
+        };
+        Ok(self.data.seek(pos)? + self.offset)
+    }
+}
+
+/// An open `blk*.dat` file handle, shared by multiple readers (using positional reads).
+struct CachedFile {
+    file: File,
+    eof: AtomicBool, // set when a read reaches EOF (the file may have been replaced)
+}
+
+/// A reader of a shared file handle (having its own position).
+struct SharedFile {
+    cached: Arc<CachedFile>,
+    pos: u64,
+}
+
+impl Read for SharedFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.cached.file.read_at(buf, self.pos)?;
+        if n == 0 && !buf.is_empty() {
+            self.cached.eof.store(true, Ordering::Relaxed);
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SharedFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(n) => (n, 0),
+            SeekFrom::Current(delta) => (self.pos, delta),
+            SeekFrom::End(delta) => (self.cached.file.metadata()?.len(), delta),
+        };
+        let pos = if delta >= 0 {
+            base.checked_add(delta as u64)
+        } else {
+            base.checked_sub(delta.wrapping_neg() as u64)
+        };
+        self.pos = pos.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid seek position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// bitcoind's block files' obfuscation key (stored in `xor.dat`, since bitcoind 28.0)
+type XorKey = [u8; 8];
+
+/// Deobfuscates a block file, by XOR-ing its bytes with the key (according to their file offset).
+struct XorFile<F> {
+    inner: F,
+    key: XorKey,
+    pos: u64,
+}
+
+impl<F> XorFile<F> {
+    fn new(inner: F, key: XorKey) -> Self {
+        Self { inner, key, pos: 0 }
+    }
+}
+
+impl<F: Read> Read for XorFile<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        for (i, byte) in buf[..n].iter_mut().enumerate() {
+            *byte ^= self.key[((self.pos + i as u64) % 8) as usize];
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<F: Seek> Seek for XorFile<F> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// Load the obfuscation key of the block files in `blocks_dir` (if they are obfuscated).
+fn read_xor_key(blocks_dir: &Path) -> Result<Option<XorKey>> {
+    let path = blocks_dir.join("xor.dat");
+    let bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None), // older bitcoind
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let key = XorKey::try_from(bytes.as_slice())
+        .map_err(|_| anyhow!("invalid {}: {} bytes", path.display(), bytes.len()))?;
+    if key == [0; 8] {
+        return Ok(None); // obfuscation is disabled
+    }
+    info!("block files are obfuscated using {}", path.display());
+    Ok(Some(key))
+}
+
+/// A shared mapping, usable by `std::io::Cursor`.
+struct MappedFile(Arc<Mmap>);
+
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        let mmap: &Mmap = &self.0;
+        mmap.as_ref()
+    }
+}
+
+/// A source of blocks' data, addressed by their `blk*.dat` file positions.
+pub(crate) trait BlockSource: Send + Sync {
+    /// Open a reader, positioned at `pos`.
+    fn open(&self, pos: FilePosition) -> Result<Box<dyn BlockFile>>;
+
+    /// Open block undo data at `pos` (in `rev*.dat` files).
+    fn open_undo(&self, pos: FilePosition) -> Result<Box<dyn Read + Send>>;
+
+    /// Read the whole block at `pos` into memory (using its size, preceding it in the file).
+    fn read_block(&self, pos: FilePosition) -> Result<Box<dyn BlockFile>> {
+        ensure!(pos.offset >= 4, "missing block size at {:?}", pos);
+        let mut file = self.open(pos.with_offset(pos.offset - 4))?;
+        let size = u32::consensus_decode(&mut file)
+            .with_context(|| format!("failed to read block size at {:?}", pos))?;
+        ensure!(
+            size <= MAX_BLOCK_SIZE,
+            "block too large at {:?}: {} bytes",
+            pos,
+            size
+        );
+        let mut data = Vec::with_capacity(size as usize);
+        file.take(u64::from(size))
+            .read_to_end(&mut data)
+            .with_context(|| format!("failed to read {} bytes at {:?}", size, pos))?;
+        if data.len() != size as usize {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+                .with_context(|| format!("truncated block at {:?}", pos));
+        }
+        Ok(Box::new(PrefetchedBlock::new(data, u64::from(pos.offset))))
+    }
+
+    /// Read up to `len` bytes starting at `pos` (less if the file is shorter).
+    fn read_range(&self, pos: FilePosition, len: usize) -> Result<Vec<u8>> {
+        ensure!(
+            len <= MAX_READ_RANGE,
+            "too many bytes to read: {} > {}",
+            len,
+            MAX_READ_RANGE
+        );
+        let mut buf = Vec::with_capacity(len);
+        self.open(pos)?
+            .take(len as u64)
+            .read_to_end(&mut buf)
+            .with_context(|| format!("failed to read {} bytes at {:?}", len, pos))?;
+        Ok(buf)
+    }
+}
+
+/// Reads blocks from bitcoind's `blk*.dat` files (the default block source).
 pub(crate) struct FileReader {
-    blocks_dir: PathBuf,
+    blocks_dirs: Vec<PathBuf>, // searched in order (e.g. fast storage first, then archival)
+    mappings: Option<Mutex<Lru<u16, Arc<Mmap>>>>, // `None` if mmap is disabled
+    handles: Option<Mutex<Lru<u16, Arc<CachedFile>>>>, // `None` if handles are not cached
+    xor_key: Option<XorKey>,   // `None` if block files are not obfuscated
 }
 
 impl FileReader {
-    pub(crate) fn open(&self, pos: FilePosition) -> Result<File> {
+    /// Extra blocks directories should contain files copied from bitcoind's blocks directory,
+    /// so they are deobfuscated using its key.
+    fn new(config: &Config) -> Result<Self> {
+        let mappings = if config.mmap_block_files {
+            Some(Mutex::new(Lru::new(config.mmap_max_block_files)))
+        } else {
+            None
+        };
+        let handles = match config.max_open_block_files {
+            0 => None,
+            size => Some(Mutex::new(Lru::new(size))),
+        };
+        Ok(Self {
+            xor_key: read_xor_key(&config.blocks_dirs[0])?,
+            blocks_dirs: config.blocks_dirs.clone(),
+            mappings,
+            handles,
+        })
+    }
+
+    /// Find the first blocks directory containing the file `name`.
+    fn locate(&self, name: &str) -> Result<PathBuf> {
+        for (i, dir) in self.blocks_dirs.iter().enumerate() {
+            let path = dir.join(name);
+            match std::fs::metadata(&path) {
+                Ok(_) => {
+                    if i > 0 {
+                        debug!("reading {} from {}", name, dir.display());
+                    }
+                    return Ok(path);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("failed to open {}", path.display()))
+                }
+            }
+        }
+        Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+            .with_context(|| format!("failed to find {} in {:?}", name, self.blocks_dirs))
+    }
+
+    /// Re-use an open file handle (if possible), to avoid opening the file for each read.
+    fn open_cached(
+        &self,
+        handles: &Mutex<Lru<u16, Arc<CachedFile>>>,
+        file_id: u16,
+        name: &str,
+    ) -> Result<Arc<CachedFile>> {
+        let mut handles = handles.lock();
+        if let Some(cached) = handles.get(&file_id) {
+            if !cached.eof.load(Ordering::Relaxed) {
+                return Ok(Arc::clone(cached));
+            }
+            // a previous read has reached EOF, so the file may have been moved or replaced
+        }
+        let path = self.locate(name)?;
+        let file =
+            File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+        let cached = Arc::new(CachedFile {
+            file,
+            eof: AtomicBool::new(false),
+        });
+        handles.insert(file_id, Arc::clone(&cached));
+        Ok(cached)
+    }
+
+    fn map(mappings: &Mutex<Lru<u16, Arc<Mmap>>>, file_id: u16, path: &Path) -> Result<Arc<Mmap>> {
+        let len = std::fs::metadata(path)
+            .with_context(|| format!("failed to open {}", path.display()))?
+            .len();
+        let mut mappings = mappings.lock();
+        if let Some(mmap) = mappings.get(&file_id) {
+            if mmap.len as u64 == len {
+                return Ok(Arc::clone(mmap));
+            }
+            // bitcoind has appended new blocks to this file, so it should be re-mapped
+        }
+        let mmap = Arc::new(Mmap::open(path)?);
+        mappings.insert(file_id, Arc::clone(&mmap));
+        Ok(mmap)
+    }
+}
+
+impl BlockSource for FileReader {
+    fn open(&self, pos: FilePosition) -> Result<Box<dyn BlockFile>> {
         let name = format!("blk{:05}.dat", pos.file_id);
-        let path = self.blocks_dir.join(name);
-        let mut file =
+        let mut file: Box<dyn BlockFile> = match (&self.mappings, &self.handles) {
+            (Some(mappings), _) => Box::new(Cursor::new(MappedFile(Self::map(
+                mappings,
+                pos.file_id,
+                &self.locate(&name)?,
+            )?))),
+            (None, Some(handles)) => Box::new(SharedFile {
+                cached: self.open_cached(handles, pos.file_id, &name)?,
+                pos: 0,
+            }),
+            (None, None) => {
+                let path = self.locate(&name)?;
+                Box::new(
+                    File::open(&path)
+                        .with_context(|| format!("failed to open {}", path.display()))?,
+                )
+            }
+        };
+        if let Some(key) = self.xor_key {
+            file = Box::new(XorFile::new(file, key));
+        }
+        file.seek(SeekFrom::Start(u64::from(pos.offset)))
+            .context(SeekFailed(pos))?;
+        Ok(file)
+    }
+
+    /// Open the `rev*.dat` file containing block undo data at `pos`.
+    fn open_undo(&self, pos: FilePosition) -> Result<Box<dyn Read + Send>> {
+        let path = self.locate(&format!("rev{:05}.dat", pos.file_id))?;
+        let file =
             File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+        let mut file: Box<dyn BlockFile> = match self.xor_key {
+            Some(key) => Box::new(XorFile::new(file, key)),
+            None => Box::new(file),
+        };
         file.seek(SeekFrom::Start(u64::from(pos.offset)))?;
-        Ok(file)
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Where blocks are read from (see `doc/config.md`):
+/// - files: bitcoind's `blk*.dat` files (requires access to its blocks directory)
+/// - rest: bitcoind's REST interface at `daemon_rest_addr` (e.g. for remote daemons)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockSourceKind {
+    Files,
+    Rest,
+}
+
+impl Default for BlockSourceKind {
+    fn default() -> Self {
+        BlockSourceKind::Files
+    }
+}
+
+impl FromStr for BlockSourceKind {
+    type Err = anyhow::Error;
+
+    fn from_str(kind: &str) -> Result<Self> {
+        match kind.to_ascii_lowercase().as_str() {
+            "files" => Ok(BlockSourceKind::Files),
+            "rest" => Ok(BlockSourceKind::Rest),
+            _ => bail!("unknown block source: {:?}", kind),
+        }
+    }
+}
+
+impl fmt::Display for BlockSourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            BlockSourceKind::Files => "files",
+            BlockSourceKind::Rest => "rest",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for BlockSourceKind {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "either 'files' or 'rest'")
+    }
+}
+
+/// Select the block source using `config.block_source` (the REST source is also returned, for
+/// registering blocks' positions).
+fn block_source(config: &Config) -> Result<(Arc<dyn BlockSource>, Option<Arc<RestBlockSource>>)> {
+    match config.block_source {
+        BlockSourceKind::Files => Ok((Arc::new(FileReader::new(config)?), None)),
+        BlockSourceKind::Rest => {
+            let rest = Arc::new(RestBlockSource::new(
+                config.daemon_rest_addr,
+                config.jsonrpc_block_timeout,
+            ));
+            Ok((Arc::clone(&rest) as Arc<dyn BlockSource>, Some(rest)))
+        }
     }
 }
 
+#[derive(Serialize)]
 pub(crate) struct BlockHashPosition {
     pub(crate) hash: BlockHash,
     pub(crate) pos: FilePosition,
@@ -123,10 +641,123 @@ impl BlockHashPosition {
     }
 }
 
+/// A block containing a specific transaction
+#[derive(Serialize)]
+pub(crate) struct TransactionBlock {
+    pub(crate) blockhash: BlockHash,
+    pub(crate) in_active_chain: bool,
+    pub(crate) height: Option<usize>, // `None` if not found in electrs' chain
+}
+
+/// Serialized size, virtual size and weight of a transaction
+#[derive(Serialize)]
+pub(crate) struct TxSizeInfo {
+    pub(crate) size: usize,
+    pub(crate) vsize: usize,
+    pub(crate) weight: usize,
+}
+
+impl TxSizeInfo {
+    pub(crate) fn new(tx: &Transaction) -> Self {
+        Self {
+            size: tx.get_size(),
+            vsize: tx.get_vsize(),
+            weight: tx.get_weight(),
+        }
+    }
+}
+
+/// A block which has failed verification (see `Daemon::verify_blocks_report`)
+pub(crate) struct VerifyFailure {
+    pub(crate) blockhash: BlockHash,
+    pub(crate) pos: Option<FilePosition>, // None if the block's location is unknown
+    pub(crate) kind: &'static str,        // see `read_error_kind()`
+    pub(crate) error: String,
+}
+
+/// Signals for accepting an unconfirmed (zero-conf) mempool transaction
+pub(crate) struct ZeroConfInfo {
+    pub(crate) bip125_replaceable: bool, // signaling (or inheriting) BIP125 replaceability
+    pub(crate) fee_rate: Amount,         // per kvB, taking its ancestors into account
+    pub(crate) fee_rate_floor: Amount,   // per kvB, for getting into the next block
+    pub(crate) conflicts: Vec<Txid>,     // other mempool transactions spending the same outputs
+    pub(crate) ancestor_count: u64,      // including the transaction itself
+}
+
+/// Mempool changes between two `getrawmempool` calls.
+#[derive(Debug)]
+pub(crate) struct MempoolDelta {
+    pub(crate) added: Vec<Txid>,
+    pub(crate) removed: Vec<Txid>,
+}
+
+impl MempoolDelta {
+    fn new(prev: &HashSet<Txid>, curr: &HashSet<Txid>) -> Self {
+        Self {
+            added: curr.difference(prev).copied().collect(),
+            removed: prev.difference(curr).copied().collect(),
+        }
+    }
+}
+
+thread_local! {
+    /// The Electrum client on whose behalf bitcoind RPCs are currently made (see `with_client_tag`)
+    static CLIENT_TAG: RefCell<Option<Arc<str>>> = RefCell::new(None);
+}
+
 pub struct Daemon {
     p2p: Mutex<Connection>,
+    p2p_shutdown: Mutex<ShutdownHandle>, // replaced on reconnection
+    is_shutdown: AtomicBool,
     rpc: Client,
-    reader: FileReader,
+    block_rpc: Client, // for large block responses, using a longer timeout
+    // for `waitfornewblock`, using a timeout longer than the block interval
+    // (see https://github.com/romanz/electrs/issues/495 for more details)
+    wait_rpc: Client,
+    wait_timeout: Duration,
+    // for `gettxoutsetinfo` (`None` if disabled), which may take minutes
+    utxo_set_rpc: Option<Client>,
+    utxo_set_info: Mutex<Option<(Instant, Value)>>, // cached result
+    utxo_set_info_ttl: Duration,
+    verbose_blocks: Mutex<Lru<(BlockHash, u8), Value>>,
+    coinbase_messages: Mutex<Lru<BlockHash, Vec<u8>>>,
+    tx_fee_rates: Mutex<Lru<Txid, Amount>>,
+    block_weight_sigops: Mutex<Lru<BlockHash, (usize, usize)>>,
+    block_sizes: Option<Mutex<Lru<BlockHash, u64>>>, // `None` if block sizes are not validated
+    rpc_passthrough_whitelist: HashSet<String>,
+    broadcast_idempotent: bool,
+    script_asm: bool,
+    max_mempool_query_txids: Option<usize>,
+    broadcast_rpcs: Vec<(String, Client)>, // additional backends (used only for broadcasting)
+    mempool_txids: Mutex<HashSet<Txid>>,   // as of the last `get_mempool_delta` call
+    reader: Arc<dyn BlockSource>,
+    rest: Option<Arc<RestBlockSource>>, // `None` if blocks are read from `blk*.dat` files
+    read_errors: Counter,
+    prefetch_window: usize,  // 0 if block prefetching is disabled
+    read_buffer_size: usize, // 0 if block reads are not buffered
+    retry_policy: RetryPolicy,
+    version: AtomicUsize,
+    uptime: AtomicU64, // used for detecting bitcoind restarts
+    restarts: Counter,
+    refresh_pending: AtomicBool, // capabilities' refresh failed after a restart (retried later)
+    verify_block_locations: bool,
+    stale_tip_threshold: Option<Duration>, // `None` in mempool-only mode (blocks are not indexed)
+    is_stale: AtomicBool,
+    p2p_watchdog_threshold: Option<Duration>, // `None` in mempool-only mode (blocks are not indexed)
+    p2p_suspect: Mutex<(Option<BlockHash>, Option<Instant>)>, // unrelayed best block, last reconnection
+    p2p_reconnects: Counter,
+    client_rpcs: Option<Counter>, // `None` if per-client accounting is disabled
+    client_rpc_tags: Mutex<HashSet<Arc<str>>>, // tags accounted by `client_rpcs`
+    background_validation: AtomicBool, // running from an assumeutxo snapshot
+    blockchain_info: Mutex<(bool, BlockHash)>, // IBD state and best block (updated after sync)
+    tip_lag_threshold: Option<usize>, // `None` in mempool-only mode (blocks are not indexed)
+    tip_lag_grace_period: Duration,
+    lagging_since: Mutex<Option<(Instant, bool)>>, // and whether a warning was logged
+    tip_status: Gauge,
+    indexes_synced: AtomicBool,
+    index_status: Gauge,
+    next_block_fee_rate: Mutex<Option<(BlockHash, Amount)>>, // cached for the best block
+    mempool_txids_cache: Mutex<Option<(BlockHash, Instant, HashSet<Txid>)>>,
 }
 
 impl Daemon {
@@ -135,8 +766,19 @@ impl Daemon {
         exit_flag: &ExitFlag,
         metrics: &Metrics,
     ) -> Result<Self> {
-        let mut rpc = rpc_connect(config)?;
+        ensure!(
+            is_allowed_network(config, config.network),
+            "network {} is not allowed (allowed_networks: {:?})",
+            config.network,
+            config.allowed_networks
+        );
+        let breaker = config
+            .breaker_policy
+            .clone()
+            .map(|policy| Arc::new(CircuitBreaker::new(policy, metrics)));
+        let mut rpc = rpc_connect(config, config.jsonrpc_timeout, breaker.as_ref())?;
 
+        let mut attempt = 1;
         loop {
             exit_flag
                 .poll()
@@ -147,38 +789,260 @@ impl Daemon {
                     break; // on success, finish polling
                 }
                 PollResult::Retry => {
-                    std::thread::sleep(std::time::Duration::from_secs(1)); // wait a bit before polling
+                    // wait a bit before polling (bitcoind may take a long time to sync, so keep polling)
+                    std::thread::sleep(config.retry_policy.delay(attempt));
+                    attempt += 1;
                 }
             }
         }
 
         let network_info = rpc.get_network_info()?;
         if network_info.version < 21_00_00 {
-            bail!("electrs requires bitcoind 0.21+");
+            if !config.skip_daemon_version_check {
+                bail!("electrs requires bitcoind 0.21+");
+            }
+            warn!(
+                "skipping version check: bitcoind {} ({}) is older than 0.21",
+                network_info.version, network_info.subversion
+            );
         }
         if !network_info.network_active {
             bail!("electrs requires active bitcoind p2p network");
         }
         let info = rpc.get_blockchain_info()?;
-        if info.pruned {
+        match daemon_network(&info.chain) {
+            Some(network) if is_allowed_network(config, network) => (),
+            _ if config.allowed_networks.is_empty() => (),
+            _ => bail!(
+                "bitcoind's network {} is not allowed (allowed_networks: {:?})",
+                info.chain,
+                config.allowed_networks
+            ),
+        }
+        if info.pruned && !config.mempool_only {
             bail!("electrs requires non-pruned bitcoind node");
         }
 
-        let p2p = Mutex::new(Connection::connect(
+        let p2p = Connection::connect(
             config.network,
             config.daemon_p2p_addr,
+            config.required_services,
+            config.p2p_handshake_timeout,
             metrics,
-        )?);
-        let reader = FileReader {
-            blocks_dir: config.blocks_dir.clone(),
+            &config.retry_policy,
+            exit_flag,
+        )?;
+        let (reader, rest) = block_source(config)?;
+        let daemon = Self {
+            p2p_shutdown: Mutex::new(p2p.shutdown_handle()),
+            p2p: Mutex::new(p2p),
+            is_shutdown: AtomicBool::new(false),
+            rpc,
+            block_rpc: rpc_connect(config, config.jsonrpc_block_timeout, breaker.as_ref())?,
+            wait_rpc: rpc_connect(config, config.jsonrpc_wait_timeout, breaker.as_ref())?,
+            wait_timeout: config.jsonrpc_wait_timeout,
+            utxo_set_rpc: match config.utxo_set_info_ttl {
+                Some(_) => Some(rpc_connect(
+                    config,
+                    config.jsonrpc_wait_timeout,
+                    breaker.as_ref(),
+                )?),
+                None => None,
+            },
+            utxo_set_info: Mutex::new(None),
+            utxo_set_info_ttl: config.utxo_set_info_ttl.unwrap_or_default(),
+            rest,
+            verbose_blocks: Mutex::new(Lru::new(VERBOSE_BLOCK_CACHE_SIZE)),
+            coinbase_messages: Mutex::new(Lru::new(COINBASE_MESSAGE_CACHE_SIZE)),
+            tx_fee_rates: Mutex::new(Lru::new(TX_FEE_RATE_CACHE_SIZE)),
+            block_weight_sigops: Mutex::new(Lru::new(BLOCK_WEIGHT_SIGOPS_CACHE_SIZE)),
+            block_sizes: if config.validate_block_sizes {
+                Some(Mutex::new(Lru::new(BLOCK_SIZE_CACHE_SIZE)))
+            } else {
+                None
+            },
+            rpc_passthrough_whitelist: config.rpc_passthrough_whitelist.clone(),
+            max_mempool_query_txids: config.max_mempool_query_txids,
+            broadcast_idempotent: config.broadcast_idempotent,
+            script_asm: config.script_asm,
+            broadcast_rpcs: config
+                .broadcast_backends
+                .iter()
+                .map(|backend| {
+                    let rpc = broadcast_connect(backend, config.jsonrpc_timeout)?;
+                    Ok((backend.addr.clone(), rpc))
+                })
+                .collect::<Result<_>>()?,
+            mempool_txids: Mutex::new(HashSet::new()),
+            reader,
+            read_errors: metrics.counter(
+                "daemon_block_read_errors",
+                "Block files' read errors",
+                "kind",
+            ),
+            prefetch_window: config.block_prefetch_window,
+            read_buffer_size: config.block_read_buffer_size,
+            retry_policy: config.retry_policy.clone(),
+            version: AtomicUsize::new(network_info.version),
+            uptime: AtomicU64::new(0),
+            refresh_pending: AtomicBool::new(false),
+            restarts: metrics.counter("daemon_restarts", "Detected bitcoind restarts", "type"),
+            verify_block_locations: !config.mempool_only,
+            stale_tip_threshold: config.stale_tip_threshold.filter(|_| !config.mempool_only),
+            is_stale: AtomicBool::new(false),
+            p2p_watchdog_threshold: config
+                .p2p_watchdog_threshold
+                .filter(|_| !config.mempool_only),
+            p2p_suspect: Mutex::new((None, None)),
+            p2p_reconnects: metrics.counter(
+                "daemon_p2p_reconnects",
+                "bitcoind p2p reconnections by the stale tip watchdog",
+                "result",
+            ),
+            blockchain_info: Mutex::new((info.initial_block_download, info.best_block_hash)),
+            client_rpcs: if config.client_rpc_accounting {
+                Some(metrics.counter(
+                    "daemon_client_rpcs",
+                    "bitcoind RPCs made on behalf of Electrum clients",
+                    "client",
+                ))
+            } else {
+                None
+            },
+            client_rpc_tags: Mutex::new(HashSet::new()),
+            background_validation: AtomicBool::new(false),
+            tip_lag_threshold: config.tip_lag_threshold.filter(|_| !config.mempool_only),
+            tip_lag_grace_period: config.tip_lag_grace_period,
+            lagging_since: Mutex::new(None),
+            tip_status: metrics.gauge("daemon_tip_status", "Chain tip status", "type"),
+            indexes_synced: AtomicBool::new(false),
+            index_status: metrics.gauge(
+                "daemon_index_synced",
+                "Whether bitcoind index is synced",
+                "index",
+            ),
+            next_block_fee_rate: Mutex::new(None),
+            mempool_txids_cache: Mutex::new(None),
         };
-        let daemon = Self { p2p, rpc, reader };
-        // Make sure `getblocklocations` RPC is available (and test it with the latest block)
-        daemon.verify_blocks(&[info.best_block_hash])?;
+        match daemon.unsynced_indexes() {
+            Ok(unsynced) if unsynced.is_empty() => {
+                daemon.indexes_synced.store(true, Ordering::Relaxed)
+            }
+            Ok(unsynced) => warn!(
+                "bitcoind indexes are not synced yet, so some queries may fail: {}",
+                unsynced.join(", ")
+            ),
+            Err(e) => {
+                warn!("skipping bitcoind indexes' check: {:#}", e);
+                daemon.indexes_synced.store(true, Ordering::Relaxed);
+            }
+        }
+        if config.index_balances && !config.ignore_mempool && network_info.version < 25_00_00 {
+            // mempool transactions' prevouts are fetched via `getrawtransaction` (see `add_prevouts`)
+            let info = daemon.get_index_info()?;
+            ensure!(
+                info.get("txindex").is_some(),
+                "index_balances requires bitcoind 25.0+ or txindex=1 (for mempool transactions' prevouts)"
+            );
+        }
+        if config.mempool_only {
+            return Ok(daemon); // blocks are not read from disk
+        }
+        if network_info.version >= 26_00_00 {
+            daemon.detect_snapshot();
+        }
+        // Make sure `getblocklocations` RPC is available (and test it with the latest block, read
+        // from the configured block source)
+        daemon.with_retry(exit_flag, "latest block verification", || {
+            daemon.verify_blocks(&[info.best_block_hash])
+        })?;
         Ok(daemon)
     }
 
+    /// Account bitcoind RPCs made by `f` to the Electrum client `tag` (if enabled).
+    pub(crate) fn with_client_tag<T>(&self, tag: Option<Arc<str>>, f: impl FnOnce() -> T) -> T {
+        if self.client_rpcs.is_none() || tag.is_none() {
+            return f();
+        }
+        // restore the previous tag, since rayon may run other clients' work on this thread
+        let prev = CLIENT_TAG.with(|current| current.replace(tag));
+        let result = f();
+        CLIENT_TAG.with(|current| current.replace(prev));
+        result
+    }
+
+    fn account_client_rpc(&self) {
+        if let Some(client_rpcs) = &self.client_rpcs {
+            CLIENT_TAG.with(|current| {
+                if let Some(tag) = &*current.borrow() {
+                    let mut tags = self.client_rpc_tags.lock();
+                    if tags.contains(tag) || tags.len() < MAX_CLIENT_RPC_TAGS {
+                        tags.insert(Arc::clone(tag));
+                        client_rpcs.inc(tag);
+                    } else {
+                        client_rpcs.inc("other");
+                    }
+                }
+            });
+        }
+    }
+
+    /// Check whether bitcoind is running from an assumeutxo snapshot, so historical blocks
+    /// may be missing until their background validation is over.
+    fn detect_snapshot(&self) {
+        let chainstates = match self.get_chainstates() {
+            Ok(chainstates) => chainstates,
+            Err(e) => {
+                warn!("skipping assumeutxo snapshot check: {:#}", e);
+                return;
+            }
+        };
+        if let Some((background, snapshot)) = snapshot_chainstates(&chainstates) {
+            warn!(
+                "bitcoind is running from an assumeutxo snapshot (base block: {}), so block files below it may be missing until background validation completes (now at height {}) - indexing is deferred meanwhile",
+                snapshot["snapshot_blockhash"], background["blocks"]
+            );
+            self.background_validation.store(true, Ordering::Relaxed);
+        } else {
+            self.background_validation.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Return the height of the background-validated chainstate (if bitcoind is still validating
+    /// the blocks below its assumeutxo snapshot).
+    fn background_validation_height(&self) -> Result<Option<usize>> {
+        if !self.background_validation.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let chainstates = self.get_chainstates()?;
+        match snapshot_chainstates(&chainstates) {
+            Some((background, _snapshot)) => {
+                let height = background["blocks"]
+                    .as_u64()
+                    .context("missing background chainstate height")?;
+                Ok(Some(usize::try_from(height)?))
+            }
+            None => {
+                info!("bitcoind completed the background validation of its assumeutxo snapshot");
+                self.background_validation.store(false, Ordering::Relaxed);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Retry `f` on RPC connection errors and timeouts (but not on errors returned by bitcoind).
+    fn with_retry<T>(
+        &self,
+        exit_flag: &ExitFlag,
+        what: &str,
+        f: impl FnMut() -> Result<T>,
+    ) -> Result<T> {
+        self.retry_policy
+            .retry(exit_flag, what, is_transport_error, f)
+    }
+
     pub(crate) fn estimate_fee(&self, nblocks: u16) -> Result<Option<Amount>> {
+        self.account_client_rpc();
         Ok(self
             .rpc
             .estimate_smart_fee(nblocks, None)
@@ -186,7 +1050,52 @@ impl Daemon {
             .fee_rate)
     }
 
+    /// Get bitcoind's raw fee estimation buckets (for short, medium and long horizons), as returned
+    /// by `estimaterawfee` - its format depends on bitcoind's version.
+    pub(crate) fn estimate_raw_fee(&self, nblocks: u16, threshold: Option<f64>) -> Result<Value> {
+        self.account_client_rpc();
+        let mut params = vec![json!(nblocks)];
+        if let Some(threshold) = threshold {
+            params.push(json!(threshold));
+        }
+        self.rpc
+            .call("estimaterawfee", &params)
+            .context("failed to estimate raw fee")
+    }
+
+    /// Get the UTXO set statistics (height, number of outputs, total amount and disk size) using
+    /// `gettxoutsetinfo`, skipping the UTXO set hash for speed. The RPC scans the whole UTXO set,
+    /// so it may take minutes (and briefly lock bitcoind's chainstate) - it uses a dedicated RPC
+    /// connection (with `jsonrpc_wait_timeout_secs` timeout), and its result is cached.
+    pub(crate) fn get_utxo_set_info(&self) -> Result<Value> {
+        let rpc = self
+            .utxo_set_rpc
+            .as_ref()
+            .context("UTXO set info is disabled")?;
+        // concurrent calls wait for the first one (instead of scanning the UTXO set again)
+        let mut cached = self.utxo_set_info.lock();
+        if let Some((time, info)) = &*cached {
+            if time.elapsed() < self.utxo_set_info_ttl {
+                return Ok(info.clone());
+            }
+        }
+        self.account_client_rpc();
+        let result: Value = rpc
+            .call("gettxoutsetinfo", &[json!("none")])
+            .context("failed to get UTXO set info")?;
+        let info = json!({
+            "height": result["height"],
+            "bestblock": result["bestblock"],
+            "txouts": result["txouts"],
+            "total_amount": result["total_amount"],
+            "disk_size": result["disk_size"],
+        });
+        *cached = Some((Instant::now(), info.clone()));
+        Ok(info)
+    }
+
     pub(crate) fn get_relay_fee(&self) -> Result<Amount> {
+        self.account_client_rpc();
         Ok(self
             .rpc
             .get_network_info()
@@ -195,9 +1104,92 @@ impl Daemon {
     }
 
     pub(crate) fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
-        self.rpc
-            .send_raw_transaction(tx)
-            .context("failed to broadcast transaction")
+        self.account_client_rpc();
+        check_transaction(tx)?;
+        match self.rpc.send_raw_transaction(tx) {
+            Err(e) if self.broadcast_idempotent && is_already_broadcast(&e) => {
+                debug!("tx {} is already broadcast: {}", tx.txid(), e);
+                Ok(tx.txid())
+            }
+            result => result.context("failed to broadcast transaction"),
+        }
+    }
+
+    /// Check whether bitcoind would accept `tx` into its mempool (without broadcasting it).
+    pub(crate) fn test_mempool_accept(&self, tx: &Transaction) -> Result<Value> {
+        self.account_client_rpc();
+        check_transaction(tx)?;
+        let mut results: Vec<Value> = self
+            .rpc
+            .call("testmempoolaccept", &[json!([serialize(tx).to_hex()])])
+            .context("failed to test mempool acceptance")?;
+        ensure!(
+            results.len() == 1,
+            "unexpected testmempoolaccept results: {:?}",
+            results
+        );
+        Ok(results.remove(0))
+    }
+
+    /// Broadcast `tx` only if `testmempoolaccept` accepts it, returning the reject reason otherwise
+    /// (at the cost of an additional RPC).
+    pub(crate) fn broadcast_checked(&self, tx: &Transaction) -> Result<Txid> {
+        let result = self.test_mempool_accept(tx)?;
+        if !result["allowed"].as_bool().unwrap_or(false) {
+            let reason = result["reject-reason"]
+                .as_str()
+                .unwrap_or("unknown reason")
+                .to_owned();
+            return Err(TransactionRejected {
+                txid: tx.txid(),
+                reason,
+            }
+            .into());
+        }
+        self.broadcast_all(tx)
+    }
+
+    /// Broadcast via bitcoind and the additional backends concurrently, succeeding if any of them
+    /// accepts the transaction (or already has it).
+    pub(crate) fn broadcast_all(&self, tx: &Transaction) -> Result<Txid> {
+        if self.broadcast_rpcs.is_empty() {
+            return self.broadcast(tx);
+        }
+        self.account_client_rpc();
+        check_transaction(tx)?;
+        let txid = tx.txid();
+        let backends: Vec<(&str, &Client)> = std::iter::once(("bitcoind", &self.rpc))
+            .chain(
+                self.broadcast_rpcs
+                    .iter()
+                    .map(|(addr, rpc)| (addr.as_str(), rpc)),
+            )
+            .collect();
+        let results: Vec<Result<Txid>> = backends
+            .par_iter()
+            .map(|(_, rpc)| {
+                rpc.send_raw_transaction(tx)
+                    .context("failed to broadcast transaction")
+            })
+            .collect();
+
+        let mut errors = vec![];
+        for ((name, _), result) in backends.iter().zip(results) {
+            match result {
+                Ok(_) => debug!("{} accepted tx {}", name, txid),
+                Err(e) if is_already_known(&e) => {
+                    debug!("{} already has tx {}: {:#}", name, txid, e)
+                }
+                Err(e) => {
+                    warn!("{} rejected tx {}: {:#}", name, txid, e);
+                    errors.push(e);
+                }
+            }
+        }
+        if errors.len() < backends.len() {
+            return Ok(txid);
+        }
+        Err(errors.remove(0)) // bitcoind's error
     }
 
     pub(crate) fn get_transaction_info(
@@ -205,6 +1197,7 @@ impl Daemon {
         txid: &Txid,
         blockhash: Option<BlockHash>,
     ) -> Result<Value> {
+        self.account_client_rpc();
         // No need to parse the resulting JSON, just return it as-is to the client.
         self.rpc
             .call(
@@ -214,31 +1207,485 @@ impl Daemon {
             .context("failed to get transaction info")
     }
 
-    pub(crate) fn get_transaction_hex(
+    /// Similar to `get_transaction_info`, but also returns the spent outputs (`vin[].prevout`)
+    /// and the transaction fee - using `getrawtransaction` verbosity 2 (if supported by bitcoind).
+    pub(crate) fn get_transaction_info_with_prevouts(
         &self,
         txid: &Txid,
         blockhash: Option<BlockHash>,
     ) -> Result<Value> {
-        let tx = self.get_transaction(txid, blockhash)?;
-        Ok(json!(serialize(&tx).to_hex()))
+        self.account_client_rpc();
+        if self.version.load(Ordering::Relaxed) >= 25_00_00 {
+            return self
+                .rpc
+                .call(
+                    "getrawtransaction",
+                    &[json!(txid), json!(2), json!(blockhash)],
+                )
+                .context("failed to get transaction info");
+        }
+        let mut info = self.get_transaction_info(txid, blockhash)?;
+        self.add_prevouts(&mut info)?;
+        Ok(info)
     }
 
-    pub(crate) fn get_transaction(
+    /// Fetch a mempool transaction, together with its spent outputs (in inputs' order).
+    pub(crate) fn get_transaction_with_prevouts(
         &self,
         txid: &Txid,
-        blockhash: Option<BlockHash>,
-    ) -> Result<Transaction> {
-        self.rpc
-            .get_raw_transaction(txid, blockhash.as_ref())
-            .context("failed to get transaction")
+    ) -> Result<(Transaction, Vec<TxOut>)> {
+        let info = self.get_transaction_info_with_prevouts(txid, None)?;
+        let tx_hex = info["hex"].as_str().context("missing transaction hex")?;
+        let tx: Transaction = deserialize(&Vec::from_hex(tx_hex).context("non-hex transaction")?)
+            .context("invalid transaction")?;
+        if tx.is_coin_base() {
+            return Ok((tx, vec![]));
+        }
+        let vin = info["vin"]
+            .as_array()
+            .context("missing transaction inputs")?;
+        let prevouts = vin
+            .iter()
+            .map(|vin| {
+                let prevout = &vin["prevout"];
+                let value = prevout["value"].as_f64().context("missing prevout value")?;
+                let script_hex = prevout["scriptPubKey"]["hex"]
+                    .as_str()
+                    .context("missing prevout script")?;
+                Ok(TxOut {
+                    value: Amount::from_btc(value)?.as_sat(),
+                    script_pubkey: Script::from(
+                        Vec::from_hex(script_hex).context("non-hex prevout script")?,
+                    ),
+                })
+            })
+            .collect::<Result<Vec<TxOut>>>()?;
+        ensure!(
+            prevouts.len() == tx.input.len(),
+            "missing prevouts for transaction {}",
+            txid
+        );
+        Ok((tx, prevouts))
     }
 
-    pub(crate) fn get_block_txids(&self, blockhash: BlockHash) -> Result<Vec<Txid>> {
-        Ok(self
-            .rpc
-            .get_block_info(&blockhash)
-            .context("failed to get block txids")?
-            .tx)
+    /// Emulate `getrawtransaction` verbosity 2, by fetching the spent transactions.
+    fn add_prevouts(&self, info: &mut Value) -> Result<()> {
+        let tx_hex = info["hex"].as_str().context("missing transaction hex")?;
+        let tx: Transaction = deserialize(&Vec::from_hex(tx_hex).context("non-hex transaction")?)
+            .context("invalid transaction")?;
+        if tx.is_coin_base() {
+            return Ok(());
+        }
+        let parents: Vec<Txid> = tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output.txid)
+            .collect::<HashSet<Txid>>()
+            .into_iter()
+            .collect();
+        let parents: HashMap<Txid, Transaction> = parents
+            .iter()
+            .copied()
+            .zip(self.get_transactions(&parents)?)
+            .collect();
+
+        let vin = info["vin"]
+            .as_array_mut()
+            .context("missing transaction inputs")?;
+        let mut total_input: u64 = 0;
+        for (txin, vin) in tx.input.iter().zip(vin) {
+            let outpoint = txin.previous_output;
+            let prevout = parents
+                .get(&outpoint.txid)
+                .and_then(|parent| parent.output.get(outpoint.vout as usize))
+                .with_context(|| format!("missing prevout {}", outpoint))?;
+            total_input += prevout.value;
+            vin["prevout"] = json!({
+                "value": Amount::from_sat(prevout.value).as_btc(),
+                "scriptPubKey": {"hex": prevout.script_pubkey.to_hex()},
+            });
+            if self.script_asm {
+                vin["prevout"]["scriptPubKey"]["asm"] =
+                    json!(script_to_asm(&prevout.script_pubkey));
+            }
+        }
+        let total_output: u64 = tx.output.iter().map(|txout| txout.value).sum();
+        let fee = total_input
+            .checked_sub(total_output)
+            .context("outputs exceed inputs")?;
+        info["fee"] = json!(Amount::from_sat(fee).as_btc());
+        Ok(())
+    }
+
+    /// Check which of the `candidates` blocks contain the transaction (using bitcoind's view).
+    pub(crate) fn get_transaction_blocks(
+        &self,
+        txid: &Txid,
+        candidates: &[BlockHash],
+        chain: &Chain,
+    ) -> Result<Vec<TransactionBlock>> {
+        self.account_client_rpc();
+        let params_list: Vec<Vec<Value>> = candidates
+            .iter()
+            .map(|blockhash| vec![json!(txid), json!(true), json!(blockhash)])
+            .collect();
+        let results = self.call_batch::<Value>("getrawtransaction", &params_list)?;
+        let mut blocks = vec![];
+        for (blockhash, result) in candidates.iter().zip(results) {
+            let info = match result {
+                Ok(info) => info,
+                Err(e) => {
+                    debug!("tx {} is not found in block {}: {:#}", txid, blockhash, e);
+                    continue;
+                }
+            };
+            let in_active_chain = info["in_active_chain"].as_bool().unwrap_or(false);
+            let height = chain.get_block_height(*blockhash);
+            if in_active_chain != height.is_some() {
+                // may happen if the index is not synced yet with bitcoind
+                warn!(
+                    "block {} is {} bitcoind's active chain, but {} electrs' chain",
+                    blockhash,
+                    if in_active_chain { "in" } else { "not in" },
+                    if height.is_some() { "in" } else { "not in" },
+                );
+            }
+            blocks.push(TransactionBlock {
+                blockhash: *blockhash,
+                in_active_chain,
+                height,
+            });
+        }
+        Ok(blocks)
+    }
+
+    /// Check whether `blockhash` is on bitcoind's active chain (without fetching the whole block).
+    pub(crate) fn is_block_on_active_chain(&self, blockhash: BlockHash) -> Result<bool> {
+        self.account_client_rpc();
+        let header: Value = match self
+            .rpc
+            .call("getblockheader", &[json!(blockhash), json!(true)])
+        {
+            Ok(header) => header,
+            // RPC_INVALID_ADDRESS_OR_KEY is returned for unknown blocks
+            Err(e) if extract_bitcoind_error(&e).map_or(false, |e| e.code == -5) => {
+                return Err(BlockNotFound(blockhash).into())
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to get block header {}", blockhash))
+            }
+        };
+        let confirmations = header["confirmations"]
+            .as_i64()
+            .with_context(|| format!("missing confirmations for block {}", blockhash))?;
+        Ok(confirmations >= 0) // stale blocks have -1 confirmations
+    }
+
+    /// Get bitcoind's verbose block JSON (as-is), without `confirmations` and `nextblockhash`
+    /// fields (which may change, so the result can be cached).
+    pub(crate) fn get_block_verbose(&self, blockhash: BlockHash, verbosity: u8) -> Result<Value> {
+        self.account_client_rpc();
+        ensure!(
+            (1..=3).contains(&verbosity),
+            "invalid verbosity: {}",
+            verbosity
+        );
+        let key = (blockhash, verbosity);
+        if let Some(block) = self.verbose_blocks.lock().get(&key) {
+            return Ok(block.clone());
+        }
+        let mut block: Value = self
+            .block_rpc
+            .call("getblock", &[json!(blockhash), json!(verbosity)])
+            .context("failed to get verbose block")?;
+        if let Some(fields) = block.as_object_mut() {
+            fields.remove("confirmations");
+            fields.remove("nextblockhash");
+        }
+        self.verbose_blocks.lock().insert(key, block.clone());
+        Ok(block)
+    }
+
+    /// Call a bitcoind RPC method, only if it is whitelisted (see `rpc_passthrough_whitelist`).
+    pub(crate) fn call_whitelisted(&self, method: &str, params: &[Value]) -> Result<Value> {
+        self.account_client_rpc();
+        ensure!(
+            self.rpc_passthrough_whitelist.contains(method),
+            "{} is not whitelisted",
+            method
+        );
+        self.rpc
+            .call(method, params)
+            .with_context(|| format!("{} failed", method))
+    }
+
+    /// Confirmed transactions are read from their containing block file (avoiding an RPC).
+    pub(crate) fn get_transaction_hex(
+        &self,
+        txid: &Txid,
+        blockhash: Option<BlockHash>,
+    ) -> Result<Value> {
+        let tx_bytes = match blockhash {
+            Some(blockhash) => self.get_transaction_raw_from_block(txid, blockhash)?,
+            None => serialize(&self.get_transaction(txid, None)?),
+        };
+        Ok(json!(tx_bytes.to_hex()))
+    }
+
+    /// Unlike `get_transaction_hex`, the witnesses are stripped (using the legacy serialization),
+    /// so the result hashes to the txid (instead of the wtxid).
+    pub(crate) fn get_transaction_stripped_hex(
+        &self,
+        txid: &Txid,
+        blockhash: Option<BlockHash>,
+    ) -> Result<Value> {
+        let tx = match blockhash {
+            Some(blockhash) => deserialize(&self.get_transaction_raw_from_block(txid, blockhash)?)?,
+            None => self.get_transaction(txid, None)?,
+        };
+        Ok(json!(serialize_stripped(&tx).to_hex()))
+    }
+
+    /// Read the serialized transaction from its block file, falling back to `getrawtransaction` RPC
+    /// if the block file is missing (e.g. pruned).
+    pub(crate) fn get_transaction_raw_from_block(
+        &self,
+        txid: &Txid,
+        blockhash: BlockHash,
+    ) -> Result<Vec<u8>> {
+        match self.read_block_tx_raw(txid, blockhash) {
+            Err(e) if read_error_kind(&e) == "file_not_found" => {
+                debug!("reading tx {} via RPC: {:#}", txid, e);
+                Ok(serialize(&self.get_transaction(txid, Some(blockhash))?))
+            }
+            result => result,
+        }
+    }
+
+    fn read_block_tx_raw(&self, txid: &Txid, blockhash: BlockHash) -> Result<Vec<u8>> {
+        for tx in self.iter_block_transactions(blockhash)? {
+            let tx = tx?;
+            if tx.txid() == *txid {
+                // decoding rejects non-canonical encodings, so the original bytes are reproduced
+                return Ok(serialize(&tx));
+            }
+        }
+        bail!("tx {} not found in block {}", txid, blockhash)
+    }
+
+    /// Decode the transactions of `blockhash` from its block file one at a time (keeping only the
+    /// current one in memory, regardless of the block's size).
+    pub(crate) fn iter_block_transactions(
+        &self,
+        blockhash: BlockHash,
+    ) -> Result<impl Iterator<Item = Result<Transaction>>> {
+        let pos = self.get_block_location(blockhash)?;
+        BlockTransactions::new(self.open_file(pos)?, blockhash, pos)
+    }
+
+    pub(crate) fn get_transaction(
+        &self,
+        txid: &Txid,
+        blockhash: Option<BlockHash>,
+    ) -> Result<Transaction> {
+        self.account_client_rpc();
+        self.rpc
+            .get_raw_transaction(txid, blockhash.as_ref())
+            .context("failed to get transaction")
+    }
+
+    /// Mempool transactions use `getmempoolentry` virtual size, matching bitcoind's policy
+    /// accounting (e.g. for transactions with many signature operations).
+    pub(crate) fn get_tx_size_info(
+        &self,
+        txid: &Txid,
+        blockhash: Option<BlockHash>,
+    ) -> Result<TxSizeInfo> {
+        let tx = self.get_transaction(txid, blockhash)?;
+        let mut info = TxSizeInfo::new(&tx);
+        if blockhash.is_none() {
+            match self.rpc.get_mempool_entry(txid) {
+                Ok(entry) => {
+                    info.vsize = usize::try_from(entry.vsize)
+                        .with_context(|| format!("invalid {} mempool vsize", txid))?
+                }
+                Err(e) => debug!("no mempool entry for {}: {}", txid, e), // e.g. confirmed
+            }
+        }
+        Ok(info)
+    }
+
+    /// Fetch multiple transactions using batched `getrawtransaction` calls (requires `txindex`).
+    pub(crate) fn get_transactions(&self, txids: &[Txid]) -> Result<Vec<Transaction>> {
+        self.account_client_rpc();
+        let params_list: Vec<Vec<Value>> = txids.iter().map(|txid| vec![json!(txid)]).collect();
+        self.call_batch::<String>("getrawtransaction", &params_list)?
+            .into_iter()
+            .zip(txids)
+            .map(|(result, txid)| {
+                let tx_hex =
+                    result.with_context(|| format!("failed to get transaction {}", txid))?;
+                let tx_bytes = Vec::from_hex(&tx_hex).context("non-hex transaction")?;
+                deserialize(&tx_bytes).context("invalid transaction")
+            })
+            .collect()
+    }
+
+    /// Get the fee rate (as an amount per 1000 vbytes) paid by a confirmed transaction, using its
+    /// block's prevouts (from undo data, if available). Coinbase transactions pay no fee.
+    /// A confirmed transaction's fee rate never changes, so it's cached.
+    pub(crate) fn get_confirmed_tx_feerate(
+        &self,
+        txid: &Txid,
+        blockhash: BlockHash,
+    ) -> Result<Amount> {
+        if let Some(fee_rate) = self.tx_fee_rates.lock().get(txid) {
+            return Ok(*fee_rate);
+        }
+        let block = self.get_block(blockhash)?;
+        let tx = block
+            .txdata
+            .iter()
+            .find(|tx| tx.txid() == *txid)
+            .with_context(|| format!("tx {} not found in block {}", txid, blockhash))?;
+        let fee_rate = if tx.is_coin_base() {
+            Amount::from_sat(0)
+        } else {
+            let prevouts = self.get_block_prevouts(&block)?;
+            let input_value = tx
+                .input
+                .iter()
+                .map(|txin| {
+                    prevouts
+                        .get(&txin.previous_output)
+                        .map(|txo| txo.value)
+                        .with_context(|| format!("missing prevout {}", txin.previous_output))
+                })
+                .sum::<Result<u64>>()?;
+            let output_value: u64 = tx.output.iter().map(|txo| txo.value).sum();
+            let fee = input_value
+                .checked_sub(output_value)
+                .with_context(|| format!("tx {} outputs exceed its inputs", txid))?;
+            fee_rate(Amount::from_sat(fee), tx.get_vsize() as u64)
+        };
+        self.tx_fee_rates.lock().insert(*txid, fee_rate);
+        Ok(fee_rate)
+    }
+
+    /// Map each non-coinbase input of `block` to the output it spends.
+    pub(crate) fn get_block_prevouts(&self, block: &Block) -> Result<HashMap<OutPoint, TxOut>> {
+        let outpoints: Vec<OutPoint> = block
+            .txdata
+            .iter()
+            .filter(|tx| !tx.is_coin_base())
+            .flat_map(|tx| tx.input.iter().map(|txin| txin.previous_output))
+            .collect();
+
+        // undo data contains all spent outputs (in the same order), avoiding RPCs
+        match self.read_block_undo(block.block_hash()) {
+            Ok(txouts) if txouts.len() == outpoints.len() => {
+                return Ok(outpoints.into_iter().zip(txouts).collect());
+            }
+            Ok(txouts) => warn!(
+                "block {} undo data has {} outputs, instead of {}",
+                block.block_hash(),
+                txouts.len(),
+                outpoints.len()
+            ),
+            Err(e) => debug!("falling back to RPC for block prevouts: {:#}", e),
+        }
+
+        // parents from the same block don't need an RPC
+        let block_txs: HashMap<Txid, &Transaction> =
+            block.txdata.iter().map(|tx| (tx.txid(), tx)).collect();
+        let missing: Vec<Txid> = outpoints
+            .iter()
+            .map(|outpoint| outpoint.txid)
+            .filter(|txid| !block_txs.contains_key(txid))
+            .collect::<HashSet<Txid>>()
+            .into_iter()
+            .collect();
+        let fetched: HashMap<Txid, Transaction> = missing
+            .iter()
+            .copied()
+            .zip(self.get_transactions(&missing)?)
+            .collect();
+
+        outpoints
+            .into_iter()
+            .map(|outpoint| {
+                let txout = block_txs
+                    .get(&outpoint.txid)
+                    .copied()
+                    .or_else(|| fetched.get(&outpoint.txid))
+                    .and_then(|tx| tx.output.get(outpoint.vout as usize))
+                    .with_context(|| format!("missing prevout {}", outpoint))?;
+                Ok((outpoint, txout.clone()))
+            })
+            .collect()
+    }
+
+    /// Get bitcoind's merkle proof (a serialized `CMerkleBlock`) for a confirmed transaction.
+    pub(crate) fn get_tx_merkle_proof(
+        &self,
+        txid: &Txid,
+        blockhash: Option<BlockHash>,
+    ) -> Result<Vec<u8>> {
+        self.account_client_rpc();
+        let result: Result<String, _> = self
+            .rpc
+            .call("gettxoutproof", &[json!([txid]), json!(blockhash)]);
+        let proof_hex = match blockhash {
+            Some(_) => result.context("failed to get merkle proof")?,
+            None => result.context(
+                "failed to get merkle proof (without a block hash, bitcoind requires `txindex=1`)",
+            )?,
+        };
+        Vec::from_hex(&proof_hex).context("non-hex merkle proof")
+    }
+
+    /// Verify a merkle proof using bitcoind, returning the proven transactions.
+    pub(crate) fn verify_tx_out_proof(&self, proof: &[u8]) -> Result<Vec<Txid>> {
+        self.account_client_rpc();
+        self.rpc
+            .call("verifytxoutproof", &[json!(proof.to_hex())])
+            .context("failed to verify merkle proof")
+    }
+
+    pub(crate) fn get_block_txids(&self, blockhash: BlockHash) -> Result<Vec<Txid>> {
+        self.account_client_rpc();
+        Ok(self
+            .rpc
+            .get_block_info(&blockhash)
+            .context("failed to get block txids")?
+            .tx)
+    }
+
+    /// Get the txids of multiple blocks, using batched `getblock` RPCs (at verbosity 1).
+    /// Blocks that failed to load are skipped (and logged).
+    ///
+    /// Note: decoding the blocks from disk (via `read_block`) avoids the RPC, but is much slower
+    /// for large blocks, since it requires deserializing and hashing all of their transactions.
+    pub(crate) fn get_block_txids_batch(
+        &self,
+        blockhashes: &[BlockHash],
+    ) -> Result<HashMap<BlockHash, Vec<Txid>>> {
+        let params_list: Vec<Vec<Value>> = blockhashes
+            .iter()
+            .map(|blockhash| vec![json!(blockhash), json!(1)])
+            .collect();
+        let results = self.call_batch::<json::GetBlockResult>("getblock", &params_list)?;
+        Ok(blockhashes
+            .iter()
+            .zip(results)
+            .filter_map(|(blockhash, result)| match result {
+                Ok(block) => Some((*blockhash, block.tx)),
+                Err(e) => {
+                    warn!("failed to get block {} txids: {:#}", blockhash, e);
+                    None
+                }
+            })
+            .collect())
     }
 
     pub(crate) fn get_mempool_txids(&self) -> Result<Vec<Txid>> {
@@ -247,26 +1694,544 @@ impl Daemon {
             .context("failed to get mempool txids")
     }
 
+    /// Return which of `txids` are in bitcoind's mempool, using a single `getrawmempool` RPC
+    /// (e.g. for pruning a wallet's evicted or confirmed transactions). The mempool txids are reused
+    /// for a few seconds by subsequent calls, unless the best block (as cached) changes.
+    pub(crate) fn filter_in_mempool(&self, txids: &[Txid]) -> Result<HashSet<Txid>> {
+        let best_block_hash = self.blockchain_info.lock().1;
+        let mut cache = self.mempool_txids_cache.lock();
+        let is_fresh = cache.as_ref().map_or(false, |(blockhash, time, _)| {
+            *blockhash == best_block_hash && time.elapsed() < MEMPOOL_TXIDS_CACHE_TTL
+        });
+        if !is_fresh {
+            self.account_client_rpc();
+            let mempool_txids = self.get_mempool_txids()?.into_iter().collect();
+            *cache = Some((best_block_hash, Instant::now(), mempool_txids));
+        }
+        let (_, _, mempool_txids) = cache.as_ref().unwrap();
+        Ok(txids
+            .iter()
+            .filter(|txid| mempool_txids.contains(*txid))
+            .copied()
+            .collect())
+    }
+
+    /// Get the mempool transactions paying at least `min_fee_rate` (as an amount per 1000 vbytes),
+    /// sorted by decreasing effective fee rate (see `effective_fee_rate`), using a single
+    /// `getrawmempool` RPC.
+    pub(crate) fn get_mempool_txids_above_feerate(
+        &self,
+        min_fee_rate: Amount,
+    ) -> Result<Vec<Txid>> {
+        let mut fee_rates: Vec<(Amount, Txid)> = self
+            .get_raw_mempool_verbose()?
+            .into_iter()
+            .map(|(txid, entry)| (effective_fee_rate(&entry), txid))
+            .filter(|(fee_rate, _)| *fee_rate >= min_fee_rate)
+            .collect();
+        fee_rates.sort_unstable_by(|a, b| b.cmp(a));
+        Ok(fee_rates.into_iter().map(|(_, txid)| txid).collect())
+    }
+
+    /// Estimate the lowest fee rate (as an amount per 1000 vbytes) included in the next block, by
+    /// filling it with the mempool transactions having the highest effective fee rates (returning
+    /// the relay fee if the mempool doesn't fill a block). The estimate is cached until a new block
+    /// is synced, so it doesn't reflect mempool changes in between.
+    pub(crate) fn next_block_feerate_floor(&self) -> Result<Amount> {
+        let best_block_hash = self.blockchain_info.lock().1;
+        if let Some((blockhash, fee_rate)) = *self.next_block_fee_rate.lock() {
+            if blockhash == best_block_hash {
+                return Ok(fee_rate);
+            }
+        }
+        let fee_rates = self
+            .get_raw_mempool_verbose()?
+            .values()
+            .map(|entry| (effective_fee_rate(entry), entry.vsize))
+            .collect();
+        let fee_rate = match fee_rate_floor(fee_rates, MAX_BLOCK_VSIZE) {
+            Some(fee_rate) => fee_rate,
+            None => self.get_relay_fee()?,
+        };
+        *self.next_block_fee_rate.lock() = Some((best_block_hash, fee_rate));
+        Ok(fee_rate)
+    }
+
+    /// Get all mempool entries (fees, sizes, times and ancestors/descendants) using a single
+    /// `getrawmempool true` RPC, instead of a `getmempoolentry` RPC per transaction.
+    /// Note: the response may be large (~0.5kB of JSON per transaction, i.e. tens of MBs for a full
+    /// mempool), and it is buffered and deserialized at once by the JSON-RPC client - so returning
+    /// it lazily (or in chunks) wouldn't reduce the peak memory usage.
+    pub(crate) fn get_raw_mempool_verbose(
+        &self,
+    ) -> Result<HashMap<Txid, json::GetMempoolEntryResult>> {
+        self.account_client_rpc();
+        self.rpc
+            .call("getrawmempool", &[json!(true)])
+            .context("failed to get mempool entries")
+    }
+
+    /// Get the mempool transactions added and removed since the previous call
+    /// (so it should be used by a single mempool tracker).
+    pub(crate) fn get_mempool_delta(&self) -> Result<MempoolDelta> {
+        let txids: HashSet<Txid> = self.get_mempool_txids()?.into_iter().collect();
+        let mut prev_txids = self.mempool_txids.lock();
+        let delta = MempoolDelta::new(&prev_txids, &txids);
+        *prev_txids = txids;
+        Ok(delta)
+    }
+
+    /// Requires bitcoind 26+ (there are two chainstates while running from an assumeutxo snapshot).
+    pub(crate) fn get_chainstates(&self) -> Result<Value> {
+        self.rpc
+            .call("getchainstates", &[])
+            .context("failed to get chainstates")
+    }
+
     pub(crate) fn get_mempool_entry(&self, txid: &Txid) -> Result<json::GetMempoolEntryResult> {
         self.rpc
             .get_mempool_entry(txid)
             .context("failed to get mempool entry")
     }
 
-    fn get_block_locations(&self, blockhashes: &[BlockHash]) -> Result<Vec<FilePosition>> {
-        self.rpc
+    /// Assess an unconfirmed transaction using its `getmempoolentry`, and `spenders` of its inputs
+    /// (from electrs' mempool index, which may still contain recently replaced transactions).
+    pub(crate) fn zeroconf_assessment(
+        &self,
+        txid: &Txid,
+        spenders: impl Fn(&OutPoint) -> Vec<Txid>,
+    ) -> Result<ZeroConfInfo> {
+        self.account_client_rpc();
+        let entry = self.get_mempool_entry(txid)?;
+        let tx = self.get_transaction(txid, None)?;
+        let mut conflicts: Vec<Txid> = tx
+            .input
+            .iter()
+            .flat_map(|txin| spenders(&txin.previous_output))
+            .filter(|spender| spender != txid)
+            .collect();
+        conflicts.sort_unstable();
+        conflicts.dedup();
+        Ok(ZeroConfInfo {
+            bip125_replaceable: entry.bip125_replaceable,
+            fee_rate: effective_fee_rate(&entry),
+            fee_rate_floor: self.next_block_feerate_floor()?,
+            conflicts,
+            ancestor_count: entry.ancestor_count,
+        })
+    }
+
+    /// Get the mempool entries of multiple transactions using batched `getmempoolentry` calls
+    /// (`None` for transactions not in the mempool). Fails if more than `max_mempool_query_txids`
+    /// are requested, before sending any RPC.
+    pub(crate) fn get_mempool_entries(
+        &self,
+        txids: &[Txid],
+    ) -> Result<Vec<Option<json::GetMempoolEntryResult>>> {
+        if let Some(max_txids) = self.max_mempool_query_txids {
+            ensure!(
+                txids.len() <= max_txids,
+                "too many txids: {} (max_mempool_query_txids = {})",
+                txids.len(),
+                max_txids
+            );
+        }
+        self.account_client_rpc();
+        let params_list: Vec<Vec<Value>> = txids.iter().map(|txid| vec![json!(txid)]).collect();
+        Ok(self
+            .call_batch::<json::GetMempoolEntryResult>("getmempoolentry", &params_list)?
+            .into_iter()
+            .map(Result::ok) // not in mempool
+            .collect())
+    }
+
+    /// Send a batch of `method` calls (one per `params_list` item), splitting large batches.
+    /// Each call's result is returned separately, so a single failure doesn't fail the whole batch.
+    fn call_batch<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params_list: &[Vec<Value>],
+    ) -> Result<Vec<Result<T>>> {
+        let client = self.rpc.get_jsonrpc_client();
+        let mut results = Vec::with_capacity(params_list.len());
+        for chunk in params_list.chunks(RPC_BATCH_SIZE) {
+            let args = chunk
+                .iter()
+                .map(|params| params.iter().map(to_raw_value).collect())
+                .collect::<serde_json::Result<Vec<Vec<_>>>>()?;
+            let requests: Vec<_> = args
+                .iter()
+                .map(|params| client.build_request(method, params))
+                .collect();
+            let responses = client
+                .send_batch(&requests)
+                .with_context(|| format!("batched {} failed", method))?;
+            ensure!(
+                responses.len() == requests.len(),
+                "batched {} returned {} responses (instead of {})",
+                method,
+                responses.len(),
+                requests.len()
+            );
+            results.extend(responses.into_iter().map(|response| match response {
+                Some(response) => response.result::<T>().map_err(anyhow::Error::from),
+                None => Err(anyhow!("missing {} response", method)),
+            }));
+        }
+        Ok(results)
+    }
+
+    /// Blocks unknown to bitcoind (e.g. after a deep reorg) have no location.
+    fn get_block_locations(&self, blockhashes: &[BlockHash]) -> Result<Vec<Option<FilePosition>>> {
+        let locations: Vec<Option<FilePosition>> = self
+            .rpc
             .call("getblocklocations", &[json!(blockhashes)])
-            .context("failed to get block locations")
+            .context("failed to get block locations")?;
+        ensure!(
+            locations.len() == blockhashes.len(),
+            "got {} block locations (instead of {})",
+            locations.len(),
+            blockhashes.len()
+        );
+        if let Some(rest) = &self.rest {
+            let known = blockhashes.iter().zip(locations.iter());
+            rest.add_positions(known.filter_map(|(hash, pos)| Some(((*pos)?, *hash))));
+        }
+        Ok(locations)
+    }
+
+    pub(crate) fn get_block_location(&self, blockhash: BlockHash) -> Result<FilePosition> {
+        let locations = self
+            .rpc
+            .call("getblocklocations", &[json!([blockhash])])
+            .context("failed to get block location")?;
+        let pos = single_location(blockhash, locations)?;
+        if let Some(rest) = &self.rest {
+            rest.add_positions(std::iter::once((pos, blockhash)));
+        }
+        Ok(pos)
+    }
+
+    /// Register the positions of the chain's blocks, for reading them via REST (if enabled).
+    pub(crate) fn add_block_positions(&self, chain: &Chain) {
+        if let Some(rest) = &self.rest {
+            rest.add_positions(
+                (0..=chain.height())
+                    .filter_map(|height| chain.get_header_row(height))
+                    .map(|row| (row.pos, row.hash)),
+            );
+        }
+    }
+
+    fn get_block_undo_location(&self, blockhash: BlockHash) -> Result<FilePosition> {
+        #[derive(Deserialize)]
+        struct UndoLocation {
+            file: u16,
+            undo: Option<u32>, // missing for blocks without undo data (e.g. genesis)
+        }
+        let locations = self
+            .rpc
+            .call("getblocklocations", &[json!([blockhash])])
+            .context("failed to get block undo location")?;
+        let location: UndoLocation = single_location(blockhash, locations)?;
+        let offset = location
+            .undo
+            .with_context(|| format!("missing undo data for block {}", blockhash))?;
+        Ok(FilePosition {
+            file_id: location.file,
+            offset,
+        })
+    }
+
+    /// Read the outputs spent by the block's inputs (excluding coinbase), from `rev*.dat` files.
+    pub(crate) fn read_block_undo(&self, blockhash: BlockHash) -> Result<Vec<TxOut>> {
+        let pos = self.get_block_undo_location(blockhash)?;
+        let undo = parse_block_undo(&mut self.reader.open_undo(pos)?)
+            .with_context(|| format!("failed to parse undo data of block {}", blockhash))?;
+        Ok(undo.into_iter().flatten().collect())
+    }
+
+    /// Count the block's outputs by their scriptPubKey type.
+    pub(crate) fn get_block_output_types(
+        &self,
+        blockhash: BlockHash,
+    ) -> Result<HashMap<OutputType, usize>> {
+        let block = self.get_block(blockhash)?;
+        let mut counts = HashMap::new();
+        for txo in block.txdata.iter().flat_map(|tx| tx.output.iter()) {
+            *counts
+                .entry(OutputType::classify(&txo.script_pubkey))
+                .or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+
+    /// Decode only the `index`-th transaction of a block (skipping over the preceding ones).
+    pub(crate) fn get_block_tx_at(
+        &self,
+        blockhash: BlockHash,
+        index: usize,
+    ) -> Result<Transaction> {
+        let pos = self.get_block_location(blockhash)?;
+        let mut file = BufReader::new(self.open_file(pos)?);
+        BlockHeader::consensus_decode(&mut file)?;
+        let tx_count = VarInt::consensus_decode(&mut file)?.0;
+        ensure!(
+            (index as u64) < tx_count,
+            "block {} has only {} transactions",
+            blockhash,
+            tx_count
+        );
+        for i in 0..index {
+            skip_transaction(&mut file)
+                .with_context(|| format!("failed to skip tx #{} in block {}", i, blockhash))?;
+        }
+        Ok(Transaction::consensus_decode(&mut file)?)
+    }
+
+    /// Get the coinbase scriptSig following the BIP34 height (usually containing the mining pool's
+    /// tag), read from the block file. A block's coinbase never changes, so it's cached.
+    pub(crate) fn get_coinbase_message(&self, blockhash: BlockHash) -> Result<Vec<u8>> {
+        if let Some(message) = self.coinbase_messages.lock().get(&blockhash) {
+            return Ok(message.clone());
+        }
+        let coinbase = self.get_block_tx_at(blockhash, 0)?;
+        ensure!(
+            coinbase.is_coin_base(),
+            "block {} has no coinbase",
+            blockhash
+        );
+        let message = coinbase_message(coinbase.input[0].script_sig.as_bytes()).to_vec();
+        self.coinbase_messages
+            .lock()
+            .insert(blockhash, message.clone());
+        Ok(message)
+    }
+
+    /// Get the block's weight (including its header) and total signature operations' cost (see
+    /// `tx_sigop_cost`), using the spent outputs for P2SH and witness sigops (read from the undo
+    /// data, if available). A block never changes, so the results are cached.
+    pub(crate) fn get_block_weight_sigops(&self, blockhash: BlockHash) -> Result<(usize, usize)> {
+        if let Some(result) = self.block_weight_sigops.lock().get(&blockhash) {
+            return Ok(*result);
+        }
+        let (block, _pos) = self.read_block(blockhash)?;
+        let prevouts = self.get_block_prevouts(&block)?;
+        // header and transactions' count are non-witness data
+        let mut weight = 4 * (80 + VarInt(block.txdata.len() as u64).len());
+        let mut sigops = 0;
+        for tx in &block.txdata {
+            weight += tx.get_weight();
+            let tx_prevouts = if tx.is_coin_base() {
+                vec![]
+            } else {
+                tx.input
+                    .iter()
+                    .map(|txin| {
+                        prevouts
+                            .get(&txin.previous_output)
+                            .cloned()
+                            .with_context(|| format!("missing prevout {}", txin.previous_output))
+                    })
+                    .collect::<Result<Vec<TxOut>>>()?
+            };
+            sigops += tx_sigop_cost(tx, &tx_prevouts);
+        }
+        self.block_weight_sigops
+            .lock()
+            .insert(blockhash, (weight, sigops));
+        Ok((weight, sigops))
+    }
+
+    /// Fetch the block via bitcoind's REST interface (if configured), otherwise read it from
+    /// `blk*.dat` files (see `read_block`, which also returns the block's position).
+    pub(crate) fn get_block(&self, blockhash: BlockHash) -> Result<Block> {
+        match &self.rest {
+            Some(rest) => rest.get_block(blockhash),
+            None => self.read_block(blockhash).map(|(block, _pos)| block),
+        }
     }
 
-    fn read_block(&self, blockhash: BlockHash) -> Result<(Block, FilePosition)> {
-        let locations = self.get_block_locations(&[blockhash])?;
-        assert_eq!(locations.len(), 1);
-        let pos = locations[0];
-        let block = Block::consensus_decode(&mut self.open_file(pos)?)?;
+    pub(crate) fn read_block(&self, blockhash: BlockHash) -> Result<(Block, FilePosition)> {
+        let result = read_block_at(
+            self.reader.as_ref(),
+            blockhash,
+            self.read_buffer_size,
+            || self.get_block_location(blockhash),
+        );
+        let (block, pos) = observe_read_error(&self.read_errors, result)?;
+        if let Some(sizes) = &self.block_sizes {
+            self.validate_block_size(sizes, &block, pos);
+        }
         Ok((block, pos))
     }
 
+    /// Warn if the block doesn't match its `getblockstats` size, which indicates a wrong block
+    /// offset or a corrupted block file.
+    fn validate_block_size(
+        &self,
+        sizes: &Mutex<Lru<BlockHash, u64>>,
+        block: &Block,
+        pos: FilePosition,
+    ) {
+        let blockhash = block.block_hash();
+        let cached = sizes.lock().get(&blockhash).copied();
+        let expected = match cached {
+            Some(size) => size,
+            None => match self.get_block_stats_size(blockhash) {
+                Ok(size) => {
+                    sizes.lock().insert(blockhash, size);
+                    size
+                }
+                Err(e) => {
+                    debug!("failed to validate block {} size: {:#}", blockhash, e);
+                    return;
+                }
+            },
+        };
+        // `total_size` excludes the coinbase transaction
+        let actual: usize = block
+            .txdata
+            .iter()
+            .skip(1)
+            .map(|tx| serialize(tx).len())
+            .sum();
+        if actual as u64 != expected {
+            self.read_errors.inc("size_mismatch");
+            warn!(
+                "block {} at {:?} has {} bytes of non-coinbase transactions (instead of {}): \
+                 its block file may be corrupted",
+                blockhash, pos, actual, expected
+            );
+        }
+    }
+
+    fn get_block_stats_size(&self, blockhash: BlockHash) -> Result<u64> {
+        let stats: Value = self
+            .rpc
+            .call("getblockstats", &[json!(blockhash), json!(["total_size"])])
+            .context("failed to get block stats")?;
+        stats["total_size"]
+            .as_u64()
+            .context("missing block total size")
+    }
+
+    /// Get block's median time past (BIP113), using `chain` headers (if the block is indexed).
+    pub(crate) fn get_block_mtp(&self, blockhash: BlockHash, chain: &Chain) -> Result<u32> {
+        if let Some(mtp) = chain
+            .get_block_height(blockhash)
+            .and_then(|height| chain.get_median_time_past(height))
+        {
+            return Ok(mtp);
+        }
+        self.account_client_rpc();
+        let header: Value = self
+            .rpc
+            .call("getblockheader", &[json!(blockhash), json!(true)])
+            .context("failed to get block header")?;
+        let mtp = header["mediantime"]
+            .as_u64()
+            .context("missing block median time")?;
+        Ok(u32::try_from(mtp)?)
+    }
+
+    /// Get the block timestamp, using `chain` or reading only the header from the block file.
+    pub(crate) fn get_block_time(&self, blockhash: BlockHash, chain: &Chain) -> Result<u32> {
+        if let Some(header) = chain
+            .get_block_height(blockhash)
+            .and_then(|height| chain.get_block_header(height))
+        {
+            return Ok(header.time);
+        }
+        let pos = self.get_block_location(blockhash)?;
+        let header = BlockHeader::consensus_decode(&mut self.open_file(pos)?)
+            .with_context(|| format!("failed to read block {} header", blockhash))?;
+        if header.block_hash() != blockhash {
+            return Err(BlockHashMismatch {
+                expected: blockhash,
+                actual: header.block_hash(),
+                pos,
+            }
+            .into());
+        }
+        Ok(header.time)
+    }
+
+    /// Build a block locator starting at `from` (see `Chain::locator`), using bitcoind's block
+    /// hashes only for heights where `chain` is not synced yet. If `from` is on a stale fork, its
+    /// ancestors are used (up to the fork point).
+    pub(crate) fn get_block_locator(
+        &self,
+        chain: &Chain,
+        from: BlockHash,
+    ) -> Result<Vec<BlockHash>> {
+        if let Some(height) = chain.get_block_height(from) {
+            return Ok(chain.locator_from(height));
+        }
+        self.account_client_rpc();
+        let get_header = |blockhash: &BlockHash| {
+            self.rpc
+                .get_block_header_info(blockhash)
+                .with_context(|| format!("failed to get block {} header", blockhash))
+        };
+        let mut header = get_header(&from)?;
+        let from_height = header.height;
+        // walk back the blocks which are neither in `chain` nor in bitcoind's active chain
+        let mut fork = HashMap::new();
+        while header.confirmations < 0 && chain.get_block_height(header.hash).is_none() {
+            fork.insert(header.height, header.hash);
+            let prev_blockhash = header
+                .previous_block_hash
+                .with_context(|| format!("missing block {} parent", header.hash))?;
+            header = get_header(&prev_blockhash)?;
+        }
+        locator_heights(from_height)
+            .into_iter()
+            .map(|height| {
+                if height == from_height {
+                    return Ok(from); // may be missing from both `chain` and bitcoind's active chain
+                }
+                if let Some(blockhash) = fork.get(&height) {
+                    return Ok(*blockhash);
+                }
+                match chain.get_block_hash(height) {
+                    Some(blockhash) => Ok(blockhash),
+                    None => self
+                        .rpc
+                        .get_block_hash(height as u64)
+                        .with_context(|| format!("failed to get block hash at {}", height)),
+                }
+            })
+            .collect()
+    }
+
+    /// Read the block at `height` (using bitcoind's block hash if `chain` is not synced yet).
+    pub(crate) fn read_block_at_height(
+        &self,
+        height: usize,
+        chain: &Chain,
+    ) -> Result<(Block, FilePosition)> {
+        let blockhash = match chain.get_block_hash(height) {
+            Some(blockhash) => blockhash,
+            None => {
+                let tip_height = self
+                    .rpc
+                    .get_block_count()
+                    .context("failed to get block count")?;
+                ensure!(
+                    height as u64 <= tip_height,
+                    "block height {} is above the chain tip height {}",
+                    height,
+                    tip_height
+                );
+                self.rpc
+                    .get_block_hash(height as u64)
+                    .with_context(|| format!("failed to get block hash at {}", height))?
+            }
+        };
+        self.read_block(blockhash)
+    }
+
     pub(crate) fn verify_blocks(&self, blockhashes: &[BlockHash]) -> Result<()> {
         for blockhash in blockhashes {
             let (block, pos) = self.read_block(*blockhash)?;
@@ -276,6 +2241,47 @@ impl Daemon {
         Ok(())
     }
 
+    /// Like `verify_blocks`, but reports all blocks failing due to block files' read errors or
+    /// hash mismatches (instead of stopping at the first one). Other errors (e.g. RPC failures)
+    /// are returned.
+    pub(crate) fn verify_blocks_report(
+        &self,
+        blockhashes: &[BlockHash],
+    ) -> Result<Vec<VerifyFailure>> {
+        let mut failures = vec![];
+        for blockhash in blockhashes {
+            let err = match self.read_block(*blockhash) {
+                Ok((_block, pos)) => {
+                    debug!("verified block {} at {:?}", blockhash, pos);
+                    continue;
+                }
+                Err(err) => err,
+            };
+            let kind = read_error_kind(&err);
+            if kind == "other" {
+                return Err(err);
+            }
+            let pos = match err
+                .chain()
+                .find_map(|e| e.downcast_ref::<BlockHashMismatch>())
+            {
+                Some(mismatch) => Some(mismatch.pos),
+                None => self.get_block_location(*blockhash).ok(),
+            };
+            warn!(
+                "failed to verify block {} at {:?}: {:#}",
+                blockhash, pos, err
+            );
+            failures.push(VerifyFailure {
+                blockhash: *blockhash,
+                pos,
+                kind,
+                error: format!("{:#}", err),
+            });
+        }
+        Ok(failures)
+    }
+
     pub(crate) fn get_genesis(&self) -> Result<HeaderRow> {
         let hash = self.rpc.get_block_hash(0)?;
         let (block, pos) = self.read_block(hash)?;
@@ -289,24 +2295,895 @@ impl Daemon {
     }
 
     pub(crate) fn get_new_headers(&self, chain: &Chain) -> Result<Vec<BlockHashPosition>> {
-        let blockhashes = self.p2p.lock().get_new_headers(chain)?;
+        let headers = self.p2p.lock().get_new_headers(chain)?;
+        if let Some(first) = headers.first() {
+            if first.prev_blockhash != chain.tip() {
+                if !self.is_reorg_allowed(chain, &headers)? {
+                    return Ok(vec![]);
+                }
+                if let Some(fork_height) = chain.get_block_height(first.prev_blockhash) {
+                    let count = chain.height() - fork_height;
+                    let disconnected = chain
+                        .get_ancestors(chain.tip(), count)
+                        .expect("missing tip");
+                    info!("disconnecting {} blocks: {:?}", count, disconnected);
+                }
+            }
+        }
+        let mut blockhashes: Vec<BlockHash> = headers.iter().map(BlockHeader::block_hash).collect();
+        if let Some(first) = blockhashes.first() {
+            // blocks above the background-validated chainstate may be missing
+            if let Some(validated_height) = self.background_validation_height()? {
+                let first_height = self.rpc.get_block_header_info(first)?.height;
+                let available = (validated_height + 1).saturating_sub(first_height);
+                if available < blockhashes.len() {
+                    info!(
+                        "deferring indexing of {} blocks, until bitcoind validates them (now at height {})",
+                        blockhashes.len() - available,
+                        validated_height
+                    );
+                    blockhashes.truncate(available);
+                }
+            }
+        }
+        self.get_block_positions(blockhashes)
+    }
+
+    /// Don't roll back the index onto a chain with less work (e.g. due to a transient header relay
+    /// anomaly) - the reorg will be retried after the next block.
+    fn is_reorg_allowed(&self, chain: &Chain, headers: &[BlockHeader]) -> Result<bool> {
+        let tip = chain.tip();
+        let new_tip = if headers.len() < MAX_HEADERS {
+            headers.last().unwrap().block_hash()
+        } else {
+            // more headers will follow, so use bitcoind's best block
+            self.rpc
+                .get_best_block_hash()
+                .context("failed to get best block hash")?
+        };
+        let (work, new_work) = (self.get_chainwork(tip)?, self.get_chainwork(new_tip)?);
+        info!(
+            "reorg from {} (chainwork {:?}) to {} (chainwork {:?})",
+            tip, work, new_tip, new_work
+        );
+        if new_work <= work {
+            warn!("not reorging onto {}, since it has less work", new_tip);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Get the total work of the chain ending at `blockhash` (as reported by `getblockheader`).
+    pub(crate) fn get_chainwork(&self, blockhash: BlockHash) -> Result<Uint256> {
+        let mut chainwork = self
+            .rpc
+            .get_block_header_info(&blockhash)
+            .with_context(|| format!("failed to get block {} header", blockhash))?
+            .chainwork;
+        ensure!(
+            chainwork.len() == 32,
+            "invalid chainwork: {}",
+            chainwork.to_hex()
+        );
+        chainwork.reverse(); // big-endian hex
+        Ok(deserialize(&chainwork)?)
+    }
+
+    /// Get the positions of `chain` blocks at heights `start..end` (clamping `end` to the chain
+    /// tip), using a single `getblocklocations` RPC.
+    pub(crate) fn get_block_positions_range(
+        &self,
+        chain: &Chain,
+        start: usize,
+        end: usize,
+    ) -> Result<Vec<BlockHashPosition>> {
+        let limit = chain.height() + 1;
+        let end = if end > limit {
+            info!(
+                "clamping block range end {} to chain tip height {}",
+                end,
+                chain.height()
+            );
+            limit
+        } else {
+            end
+        };
+        let blockhashes = (start..end)
+            .map(|height| chain.get_block_hash(height).expect("missing block hash"))
+            .collect();
+        self.get_block_positions(blockhashes)
+    }
+
+    /// Fails if a block is unknown to bitcoind (e.g. it was reorged out since its header was received).
+    fn get_block_positions(&self, blockhashes: Vec<BlockHash>) -> Result<Vec<BlockHashPosition>> {
         let positions = self.get_block_locations(&blockhashes)?;
-        assert_eq!(blockhashes.len(), positions.len());
-        Ok(blockhashes
+        blockhashes
             .into_iter()
             .zip(positions.into_iter())
-            .map(|(blockhash, position)| BlockHashPosition::new(blockhash, position))
-            .collect())
+            .map(|(blockhash, position)| match position {
+                Some(position) => Ok(BlockHashPosition::new(blockhash, position)),
+                None => bail!(BlockNotFound(blockhash)),
+            })
+            .collect()
     }
 
-    pub(crate) fn open_file(&self, pos: FilePosition) -> Result<File> {
-        self.reader.open(pos)
+    pub(crate) fn open_file(&self, pos: FilePosition) -> Result<Box<dyn BlockFile>> {
+        observe_read_error(&self.read_errors, self.reader.open(pos))
     }
 
-    pub(crate) fn new_block_notification(&self) -> Receiver<()> {
-        self.p2p.lock().new_block_notification()
+    /// Open the blocks at `positions` (in order). If enabled, a background thread reads up to
+    /// `block_prefetch_window` blocks ahead of the caller, so block files' IO doesn't block
+    /// indexing. The thread exits when the returned iterator is dropped.
+    pub(crate) fn open_blocks(
+        &self,
+        positions: Vec<FilePosition>,
+    ) -> Box<dyn Iterator<Item = Result<Box<dyn BlockFile>>> + '_> {
+        if self.prefetch_window == 0 {
+            // indexing seeks within the block (discarding a `BufReader`), so read it as a whole
+            return Box::new(
+                positions
+                    .into_iter()
+                    .map(move |pos| match self.read_buffer_size {
+                        0 => self.open_file(pos),
+                        _ => observe_read_error(&self.read_errors, self.reader.read_block(pos)),
+                    }),
+            );
+        }
+        let (tx, rx) = bounded(self.prefetch_window);
+        let reader = Arc::clone(&self.reader);
+        let read_errors = self.read_errors.clone();
+        spawn("prefetch", move || {
+            for pos in positions {
+                let block = observe_read_error(&read_errors, reader.read_block(pos));
+                let failed = block.is_err();
+                if tx.send(block).is_err() || failed {
+                    break; // the receiver has been dropped (or the error has been reported)
+                }
+            }
+            Ok(())
+        });
+        Box::new(rx.into_iter())
     }
-}
+
+    pub(crate) fn read_range(&self, pos: FilePosition, len: usize) -> Result<Vec<u8>> {
+        observe_read_error(&self.read_errors, self.reader.read_range(pos, len))
+    }
+
+    /// Warn if the latest block is too old (e.g. bitcoind may be disconnected from the network).
+    pub(crate) fn check_stale_tip(&self, chain: &Chain) -> bool {
+        let threshold = match self.stale_tip_threshold {
+            Some(threshold) => threshold,
+            None => return false,
+        };
+        let age = tip_age(chain);
+        let is_stale = age > threshold.as_secs();
+        self.tip_status.set("seconds_since_last_block", age as f64);
+        self.tip_status
+            .set("is_stale", if is_stale { 1.0 } else { 0.0 });
+
+        let was_stale = self.is_stale.swap(is_stale, Ordering::Relaxed);
+        if is_stale && !was_stale {
+            warn!(
+                "stale chain tip {}: the latest block was received {} seconds ago",
+                chain.tip(),
+                age
+            );
+        } else if was_stale && !is_stale {
+            info!("chain tip {} is not stale anymore", chain.tip());
+        }
+        is_stale
+    }
+
+    /// Reconnect to bitcoind p2p port (re-handshaking) if the chain tip is stale, while bitcoind's
+    /// best block (as cached) has not been relayed to electrs since the previous check - the p2p
+    /// connection may have silently stopped relaying new blocks, although its socket looks alive.
+    pub(crate) fn check_p2p_watchdog(&self, chain: &Chain) {
+        let threshold = match self.p2p_watchdog_threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let (initial_block_download, best_block_hash) = *self.blockchain_info.lock();
+        let mut suspect = self.p2p_suspect.lock();
+        let (unrelayed, last_reconnect) = &mut *suspect;
+        if initial_block_download
+            || best_block_hash == chain.tip()
+            || chain.get_block_height(best_block_hash).is_some()
+            || self.background_validation.load(Ordering::Relaxed) // new blocks may be deferred
+            || tip_age(chain) <= threshold.as_secs()
+        {
+            *unrelayed = None;
+            return;
+        }
+        // bitcoind's best block may have been found after the last sync
+        if unrelayed.replace(best_block_hash) != Some(best_block_hash) {
+            return;
+        }
+        if last_reconnect.map_or(false, |t| t.elapsed() < threshold) {
+            return; // reconnect at most once per threshold
+        }
+        *last_reconnect = Some(Instant::now());
+        warn!(
+            "chain tip {} is stale, but bitcoind's best block {} was not relayed: reconnecting p2p",
+            chain.tip(),
+            best_block_hash
+        );
+        let mut p2p = self.p2p.lock();
+        match p2p.reconnect() {
+            Ok(conn) => {
+                let mut shutdown = self.p2p_shutdown.lock();
+                *shutdown = conn.shutdown_handle();
+                if self.is_shutdown.load(Ordering::Relaxed) {
+                    shutdown.shutdown(); // `shutdown()` was called during reconnection
+                }
+                *p2p = conn; // the previous connection is closed on drop
+                self.p2p_reconnects.inc("success");
+                info!("reconnected to bitcoind p2p port");
+            }
+            Err(e) => {
+                self.p2p_reconnects.inc("failure");
+                warn!("failed to reconnect to bitcoind p2p port: {:#}", e);
+            }
+        }
+    }
+
+    /// Warn if electrs' tip is behind bitcoind's tip for too long (e.g. if indexing is stuck).
+    pub(crate) fn check_tip_lag(&self, chain: &Chain) {
+        let threshold = match self.tip_lag_threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let daemon_height = match self.rpc.get_block_count() {
+            Ok(height) => height as usize,
+            Err(e) => {
+                debug!("failed to get block count: {}", e);
+                return;
+            }
+        };
+        let gap = daemon_height.saturating_sub(chain.height());
+        self.tip_status.set("height_gap", gap as f64);
+
+        let mut lagging_since = self.lagging_since.lock();
+        if gap <= threshold {
+            if let Some((_, true)) = lagging_since.take() {
+                info!("chain tip {} has caught up with bitcoind", chain.tip());
+            }
+            return;
+        }
+        let (since, warned) = lagging_since.get_or_insert_with(|| (Instant::now(), false));
+        if !*warned && since.elapsed() > self.tip_lag_grace_period {
+            warn!(
+                "chain tip {} is {} blocks behind bitcoind for more than {:?}",
+                chain.tip(),
+                gap,
+                self.tip_lag_grace_period
+            );
+            *warned = true;
+        }
+    }
+
+    /// Update the cached bitcoind IBD state and best block (used by `is_synced`).
+    pub(crate) fn update_blockchain_info(&self) {
+        match self.rpc.get_blockchain_info() {
+            Ok(info) => {
+                *self.blockchain_info.lock() = (info.initial_block_download, info.best_block_hash)
+            }
+            Err(e) => debug!("failed to get blockchain info: {}", e),
+        }
+    }
+
+    /// Return true if bitcoind is out of IBD, and `chain` tip (if indexed) is its best block (as cached).
+    pub(crate) fn is_synced(&self, chain: Option<&Chain>) -> bool {
+        let (initial_block_download, best_block_hash) = *self.blockchain_info.lock();
+        !initial_block_download && chain.map_or(true, |chain| chain.tip() == best_block_hash)
+    }
+
+    pub(crate) fn get_index_info(&self) -> Result<Value> {
+        self.rpc
+            .call("getindexinfo", &[])
+            .context("failed to get index info")
+    }
+
+    fn unsynced_indexes(&self) -> Result<Vec<String>> {
+        let info = self.get_index_info()?;
+        let indexes = info.as_object().context("invalid index info")?;
+        let mut unsynced = vec![];
+        for (name, status) in indexes {
+            let synced = status["synced"].as_bool().unwrap_or(false);
+            self.index_status.set(name, if synced { 1.0 } else { 0.0 });
+            if !synced {
+                unsynced.push(format!(
+                    "{} (at height {})",
+                    name, status["best_block_height"]
+                ));
+            }
+        }
+        Ok(unsynced)
+    }
+
+    /// Detect bitcoind restarts (using its uptime), re-checking its version and capabilities.
+    /// A failed re-check is retried by the next call.
+    pub(crate) fn check_restart(&self) -> Result<()> {
+        let uptime: u64 = match self.rpc.call("uptime", &[]) {
+            Ok(uptime) => uptime,
+            Err(e) => {
+                debug!("failed to get bitcoind uptime: {}", e);
+                return Ok(());
+            }
+        };
+        let prev_uptime = self.uptime.swap(uptime, Ordering::Relaxed);
+        if uptime < prev_uptime {
+            warn!(
+                "bitcoind has restarted (uptime decreased from {}s to {}s)",
+                prev_uptime, uptime
+            );
+            self.restarts.inc("restart");
+        } else if !self.refresh_pending.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let result = self.refresh_capabilities();
+        self.refresh_pending
+            .store(result.is_err(), Ordering::Relaxed);
+        result.context("failed to re-check capabilities after bitcoind restart")
+    }
+
+    /// Re-run the connect-time probes (e.g. after bitcoind was upgraded or an index was enabled),
+    /// updating the cached version, indexes' and assumeutxo snapshot status.
+    pub(crate) fn refresh_capabilities(&self) -> Result<()> {
+        let network_info = self
+            .rpc
+            .get_network_info()
+            .context("failed to get network info")?;
+        let mut changes = vec![];
+        let prev_version = self.version.swap(network_info.version, Ordering::Relaxed);
+        if prev_version != network_info.version {
+            changes.push(format!(
+                "version changed from {} to {} ({})",
+                prev_version, network_info.version, network_info.subversion
+            ));
+        }
+        let indexes_synced = match self.unsynced_indexes() {
+            Ok(unsynced) => unsynced.is_empty(),
+            Err(e) => {
+                warn!("skipping bitcoind indexes' check: {:#}", e);
+                true
+            }
+        };
+        if self.indexes_synced.swap(indexes_synced, Ordering::Relaxed) != indexes_synced {
+            changes.push(format!("indexes synced: {}", indexes_synced));
+        }
+        if self.verify_block_locations {
+            if network_info.version >= 26_00_00 {
+                let prev = self.background_validation.load(Ordering::Relaxed);
+                self.detect_snapshot();
+                let current = self.background_validation.load(Ordering::Relaxed);
+                if prev != current {
+                    changes.push(format!("running from assumeutxo snapshot: {}", current));
+                }
+            }
+            // Make sure `getblocklocations` RPC is still available
+            let tip = self.rpc.get_best_block_hash()?;
+            self.verify_blocks(&[tip])
+                .context("failed to verify latest block")?;
+        }
+        if changes.is_empty() {
+            info!("bitcoind capabilities are unchanged");
+        } else {
+            info!("bitcoind capabilities changed: {}", changes.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Re-check bitcoind indexes, until all of them are synced.
+    pub(crate) fn check_indexes(&self) -> bool {
+        if self.indexes_synced.load(Ordering::Relaxed) {
+            return true;
+        }
+        match self.unsynced_indexes() {
+            Ok(unsynced) if unsynced.is_empty() => {
+                info!("bitcoind indexes are synced");
+                self.indexes_synced.store(true, Ordering::Relaxed);
+                true
+            }
+            Ok(unsynced) => {
+                debug!("bitcoind indexes are not synced: {}", unsynced.join(", "));
+                false
+            }
+            Err(e) => {
+                debug!("failed to check bitcoind indexes: {:#}", e);
+                false
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for a new best block, returning it (or the current best block on
+    /// timeout). The call uses a dedicated RPC connection, so other RPCs may have shorter timeouts.
+    pub(crate) fn wait_for_new_block(&self, timeout: Duration) -> Result<json::BlockRef> {
+        ensure!(
+            timeout < self.wait_timeout,
+            "timeout must be shorter than jsonrpc_wait_timeout_secs ({:?})",
+            self.wait_timeout
+        );
+        let timeout_ms = u64::try_from(timeout.as_millis())?;
+        ensure!(timeout_ms > 0, "timeout must be positive"); // 0 waits indefinitely
+        self.wait_rpc
+            .wait_for_new_block(timeout_ms)
+            .context("failed to wait for new block")
+    }
+
+    pub(crate) fn new_block_notification(&self) -> Receiver<()> {
+        self.p2p.lock().new_block_notification()
+    }
+
+    /// Close bitcoind p2p connection (can be called multiple times, also during in-flight requests).
+    /// In-flight and subsequent p2p requests will fail, and RPC connections are closed on drop.
+    pub(crate) fn shutdown(&self) {
+        if self.is_shutdown.swap(true, Ordering::Relaxed) {
+            return; // already closed
+        }
+        info!("closing bitcoind connections");
+        self.p2p_shutdown.lock().shutdown();
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// The number of seconds since the chain tip's timestamp.
+fn tip_age(chain: &Chain) -> u64 {
+    let tip_time = chain
+        .get_block_header(chain.height())
+        .expect("missing tip header")
+        .time;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    now.saturating_sub(u64::from(tip_time))
+}
+
+fn is_allowed_network(config: &Config, network: Network) -> bool {
+    config.allowed_networks.is_empty() || config.allowed_networks.contains(&network)
+}
+
+/// Map bitcoind's `getblockchaininfo` chain name to its network.
+fn daemon_network(chain: &str) -> Option<Network> {
+    match chain {
+        "main" => Some(Network::Bitcoin),
+        "test" => Some(Network::Testnet),
+        "regtest" => Some(Network::Regtest),
+        "signet" => Some(Network::Signet),
+        _ => None,
+    }
+}
+
+/// Return the background and snapshot chainstates (from `getchainstates` result),
+/// if bitcoind is running from an assumeutxo snapshot.
+fn snapshot_chainstates(chainstates: &Value) -> Option<(&Value, &Value)> {
+    match chainstates["chainstates"].as_array().map(Vec::as_slice) {
+        Some([background, snapshot]) => Some((background, snapshot)),
+        _ => None,
+    }
+}
+
+/// Read-only, non-wallet bitcoind RPC methods, which may be whitelisted by default (see
+/// `call_whitelisted`). Other methods (e.g. wallet, sending and node control ones) require
+/// `rpc_passthrough_allow_unsafe`.
+const SAFE_RPC_METHODS: &[&str] = &[
+    "decodepsbt",
+    "decoderawtransaction",
+    "decodescript",
+    "estimaterawfee",
+    "estimatesmartfee",
+    "getaddednodeinfo",
+    "getbestblockhash",
+    "getblock",
+    "getblockchaininfo",
+    "getblockcount",
+    "getblockfilter",
+    "getblockhash",
+    "getblockheader",
+    "getblocklocations",
+    "getblockstats",
+    "getchainstates",
+    "getchaintips",
+    "getchaintxstats",
+    "getconnectioncount",
+    "getdeploymentinfo",
+    "getdifficulty",
+    "getindexinfo",
+    "getmemoryinfo",
+    "getmempoolancestors",
+    "getmempooldescendants",
+    "getmempoolentry",
+    "getmempoolinfo",
+    "getmininginfo",
+    "getnettotals",
+    "getnetworkhashps",
+    "getnetworkinfo",
+    "getnodeaddresses",
+    "getpeerinfo",
+    "getrawmempool",
+    "getrawtransaction",
+    "getrpcinfo",
+    "gettxout",
+    "gettxoutproof",
+    "gettxoutsetinfo",
+    "gettxspendingprevout",
+    "getzmqnotifications",
+    "help",
+    "listbanned",
+    "testmempoolaccept",
+    "uptime",
+    "validateaddress",
+    "verifymessage",
+    "verifytxoutproof",
+];
+
+/// Returns true for RPC methods which are not known to be read-only and unrelated to bitcoind's
+/// wallet (e.g. `sendtoaddress`, `stop` or `invalidateblock`).
+pub(crate) fn is_unsafe_rpc_method(method: &str) -> bool {
+    let method = method.to_ascii_lowercase();
+    !SAFE_RPC_METHODS.contains(&method.as_str())
+}
+
+/// Streaming decoder of a block's transactions (see `Daemon::iter_block_transactions`).
+struct BlockTransactions {
+    file: BufReader<Box<dyn BlockFile>>,
+    remaining: u64,
+}
+
+impl BlockTransactions {
+    /// `file` should be positioned at the header of `blockhash`.
+    fn new(file: Box<dyn BlockFile>, blockhash: BlockHash, pos: FilePosition) -> Result<Self> {
+        let mut file = BufReader::new(file);
+        let header = BlockHeader::consensus_decode(&mut file)?;
+        if header.block_hash() != blockhash {
+            return Err(BlockHashMismatch {
+                expected: blockhash,
+                actual: header.block_hash(),
+                pos,
+            }
+            .into());
+        }
+        let remaining = VarInt::consensus_decode(&mut file)?.0;
+        Ok(Self { file, remaining })
+    }
+}
+
+impl Iterator for BlockTransactions {
+    type Item = Result<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        match Transaction::consensus_decode(&mut self.file) {
+            Ok(tx) => {
+                self.remaining -= 1;
+                Some(Ok(tx))
+            }
+            Err(e) => {
+                self.remaining = 0; // the following transactions can't be located
+                Some(Err(e.into()))
+            }
+        }
+    }
+}
+
+/// Read a block from the location returned by `locate`, making sure it's the requested block.
+/// bitcoind may re-write a block after a reorg, so if a different (stale) block is found,
+/// its location is queried again.
+///
+/// Note: a matching hash also implies that the block links to the correct previous header.
+fn read_block_at(
+    reader: &dyn BlockSource,
+    blockhash: BlockHash,
+    buffer_size: usize,
+    mut locate: impl FnMut() -> Result<FilePosition>,
+) -> Result<(Block, FilePosition)> {
+    let decode = |pos| -> Result<Block> {
+        let mut file = reader.open(pos)?;
+        Ok(match buffer_size {
+            0 => Block::consensus_decode(&mut file)?,
+            _ => Block::consensus_decode(BufReader::with_capacity(buffer_size, file))?,
+        })
+    };
+    let pos = locate()?;
+    let block = decode(pos)?;
+    if block.block_hash() == blockhash {
+        return Ok((block, pos));
+    }
+    warn!(
+        "unexpected block {} at {:?} (instead of {}), re-querying its location",
+        block.block_hash(),
+        pos,
+        blockhash
+    );
+    let pos = locate()?;
+    let block = decode(pos)?;
+    if block.block_hash() != blockhash {
+        return Err(BlockHashMismatch {
+            expected: blockhash,
+            actual: block.block_hash(),
+            pos,
+        }
+        .into());
+    }
+    Ok((block, pos))
+}
+
+/// Seeking inside a block file has failed.
+#[derive(Debug)]
+struct SeekFailed(FilePosition);
+
+impl fmt::Display for SeekFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to seek to {:?}", self.0)
+    }
+}
+
+/// A different block was found at the requested block's location.
+#[derive(Debug)]
+struct BlockHashMismatch {
+    expected: BlockHash,
+    actual: BlockHash,
+    pos: FilePosition,
+}
+
+impl fmt::Display for BlockHashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unexpected block {} at {:?} (instead of {})",
+            self.actual, self.pos, self.expected
+        )
+    }
+}
+
+impl error::Error for BlockHashMismatch {}
+
+/// Get the location of a single block, which may be null (or missing) if it's unknown to bitcoind.
+fn single_location<T>(blockhash: BlockHash, locations: Vec<Option<T>>) -> Result<T> {
+    ensure!(locations.len() <= 1, "unexpected block locations");
+    match locations.into_iter().next().flatten() {
+        Some(location) => Ok(location),
+        None => Err(BlockNotFound(blockhash).into()),
+    }
+}
+
+/// Classify block files' read errors (for monitoring storage problems).
+fn read_error_kind(err: &anyhow::Error) -> &'static str {
+    use std::io::ErrorKind;
+    let io_error_kind = |e: &std::io::Error| match e.kind() {
+        ErrorKind::NotFound => "file_not_found",
+        ErrorKind::PermissionDenied => "permission_denied",
+        ErrorKind::UnexpectedEof => "short_read",
+        _ => "io_error",
+    };
+    if err.downcast_ref::<SeekFailed>().is_some() {
+        return "seek_error"; // used as a context, so it's not a part of the error chain
+    }
+    for e in err.chain() {
+        if e.is::<BlockHashMismatch>() {
+            return "hash_mismatch";
+        }
+        if e.is::<BlockNotFound>() {
+            return "block_not_found";
+        }
+        if let Some(e) = e.downcast_ref::<std::io::Error>() {
+            return io_error_kind(e);
+        }
+        if let Some(e) = e.downcast_ref::<encode::Error>() {
+            return match e {
+                encode::Error::Io(e) => io_error_kind(e),
+                _ => "decode_error",
+            };
+        }
+    }
+    "other"
+}
+
+/// Count block files' read errors by their kind.
+fn observe_read_error<T>(errors: &Counter, result: Result<T>) -> Result<T> {
+    if let Err(e) = &result {
+        errors.inc(read_error_kind(e));
+    }
+    result
+}
+
+/// Strip the BIP34 height push from a coinbase scriptSig.
+fn coinbase_message(script_sig: &[u8]) -> &[u8] {
+    let height_len = match script_sig.first() {
+        Some(&len @ 0x01..=0x4b) => 1 + usize::from(len),
+        Some(0x4c) => 2 + script_sig.get(1).map_or(0, |&len| usize::from(len)), // OP_PUSHDATA1
+        Some(_) => 1, // OP_0 and OP_1..OP_16 are used for small heights (e.g. on regtest)
+        None => 0,
+    };
+    script_sig.get(height_len..).unwrap_or(&[])
+}
+
+/// Skip over a serialized transaction, without decoding its inputs, outputs and witnesses.
+fn skip_transaction(r: &mut impl Read) -> Result<()> {
+    skip_bytes(r, 4)?; // version
+    let mut inputs = VarInt::consensus_decode(r)?.0;
+    let segwit = inputs == 0; // BIP144 marker
+    if segwit {
+        ensure!(u8::consensus_decode(r)? == 1, "invalid segwit flag");
+        inputs = VarInt::consensus_decode(r)?.0;
+    }
+    for _ in 0..inputs {
+        skip_bytes(r, 36)?; // previous outpoint
+        skip_var_bytes(r)?; // scriptSig
+        skip_bytes(r, 4)?; // sequence
+    }
+    let outputs = VarInt::consensus_decode(r)?.0;
+    for _ in 0..outputs {
+        skip_bytes(r, 8)?; // value
+        skip_var_bytes(r)?; // scriptPubKey
+    }
+    if segwit {
+        for _ in 0..inputs {
+            let items = VarInt::consensus_decode(r)?.0;
+            for _ in 0..items {
+                skip_var_bytes(r)?;
+            }
+        }
+    }
+    skip_bytes(r, 4) // lock time
+}
+
+/// Serialize a transaction without its witnesses (as hashed by its txid).
+pub(crate) fn serialize_stripped(tx: &Transaction) -> Vec<u8> {
+    let mut tx = tx.clone();
+    for txin in &mut tx.input {
+        txin.witness.clear();
+    }
+    serialize(&tx)
+}
+
+fn skip_var_bytes(r: &mut impl Read) -> Result<()> {
+    let len = VarInt::consensus_decode(r)?.0;
+    skip_bytes(r, len)
+}
+
+fn skip_bytes(r: &mut impl Read, len: u64) -> Result<()> {
+    let skipped = std::io::copy(&mut r.by_ref().take(len), &mut std::io::sink())?;
+    ensure!(skipped == len, "unexpected EOF");
+    Ok(())
+}
+
+/// Convert a fee into a fee rate (as an amount per 1000 vbytes).
+fn fee_rate(fee: Amount, vsize: u64) -> Amount {
+    Amount::from_sat(fee.as_sat() * 1000 / vsize)
+}
+
+/// The fee rate a miner would include a mempool transaction at: since its unconfirmed ancestors
+/// must be mined first, they may lower it (in which case the package's fee rate is used).
+/// Modified fees are used, taking `prioritisetransaction` into account (like bitcoind's miner).
+fn effective_fee_rate(entry: &json::GetMempoolEntryResult) -> Amount {
+    let own = fee_rate(entry.fees.modified, entry.vsize);
+    let package = fee_rate(entry.fees.ancestor, entry.ancestor_size);
+    std::cmp::min(own, package)
+}
+
+/// Return the fee rate of the first transaction not fitting into `max_vsize`, when sorted by
+/// decreasing fee rate (or `None` if they all fit).
+fn fee_rate_floor(mut fee_rates: Vec<(Amount, u64)>, max_vsize: u64) -> Option<Amount> {
+    fee_rates.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+    let mut total_vsize = 0;
+    for (fee_rate, vsize) in fee_rates {
+        total_vsize += vsize;
+        if total_vsize > max_vsize {
+            return Some(fee_rate);
+        }
+    }
+    None
+}
+
+/// Returns true if bitcoind already has the transaction (in its mempool or in a block).
+fn is_already_known(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|e| e.downcast_ref::<bitcoincore_rpc::Error>())
+        .any(is_already_broadcast)
+}
+
+/// Match bitcoind's "already in block chain" and "already in mempool" broadcast errors.
+fn is_already_broadcast(err: &bitcoincore_rpc::Error) -> bool {
+    const RPC_VERIFY_REJECTED: i32 = -26;
+    const RPC_VERIFY_ALREADY_IN_CHAIN: i32 = -27;
+    match extract_bitcoind_error(err) {
+        Some(e) if e.code == RPC_VERIFY_ALREADY_IN_CHAIN => true,
+        Some(e) if e.code == RPC_VERIFY_REJECTED => {
+            e.message == "txn-already-in-mempool" || e.message == "txn-already-known"
+        }
+        _ => false,
+    }
+}
+
+/// Returns true for RPC connection failures and timeouts (which may succeed on retry).
+fn is_transport_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| {
+        matches!(
+            e.downcast_ref::<bitcoincore_rpc::Error>(),
+            Some(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(_)))
+        )
+    })
+}
+
+/// A transaction rejected by bitcoind's `testmempoolaccept`.
+#[derive(Debug)]
+pub(crate) struct TransactionRejected {
+    txid: Txid,
+    reason: String,
+}
+
+impl fmt::Display for TransactionRejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "transaction {} rejected: {}", self.txid, self.reason)
+    }
+}
+
+impl error::Error for TransactionRejected {}
+
+/// A block which is not known to bitcoind.
+#[derive(Debug)]
+pub(crate) struct BlockNotFound(pub(crate) BlockHash);
+
+impl fmt::Display for BlockNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "block {} not found", self.0)
+    }
+}
+
+impl error::Error for BlockNotFound {}
+
+/// A transaction that would be rejected by bitcoind regardless of the current chain state.
+#[derive(Debug)]
+pub(crate) struct InvalidTransaction(&'static str);
+
+impl fmt::Display for InvalidTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid transaction: {}", self.0)
+    }
+}
+
+impl error::Error for InvalidTransaction {}
+
+const MAX_TX_WEIGHT: usize = 4_000_000; // a transaction must fit in a block
+const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+
+/// Cheap structural checks (a subset of bitcoind's `CheckTransaction`), to avoid an RPC
+/// for transactions which are guaranteed to be rejected.
+fn check_transaction(tx: &Transaction) -> Result<(), InvalidTransaction> {
+    if tx.input.is_empty() {
+        return Err(InvalidTransaction("no inputs"));
+    }
+    if tx.output.is_empty() {
+        return Err(InvalidTransaction("no outputs"));
+    }
+    if tx.get_weight() > MAX_TX_WEIGHT {
+        return Err(InvalidTransaction("too large"));
+    }
+    let mut total: u64 = 0;
+    for txout in &tx.output {
+        total = total
+            .checked_add(txout.value)
+            .filter(|total| *total <= MAX_MONEY && txout.value <= MAX_MONEY)
+            .ok_or(InvalidTransaction("output value out of range"))?;
+    }
+    let mut outpoints = HashSet::with_capacity(tx.input.len());
+    for txin in &tx.input {
+        if txin.previous_output.is_null() {
+            return Err(InvalidTransaction("coinbase input"));
+        }
+        if !outpoints.insert(txin.previous_output) {
+            return Err(InvalidTransaction("duplicate inputs"));
+        }
+    }
+    Ok(())
+}
 
 pub(crate) type RpcError = bitcoincore_rpc::jsonrpc::error::RpcError;
 
@@ -319,3 +3196,596 @@ pub(crate) fn extract_bitcoind_error(err: &bitcoincore_rpc::Error) -> Option<&Rp
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_transaction, coinbase_message, effective_fee_rate, fee_rate_floor,
+        is_already_broadcast, is_unsafe_rpc_method, read_block_at, read_error_kind, read_xor_key,
+        serialize_stripped, single_location, skip_transaction, snapshot_chainstates, BlockFile,
+        BlockSource, BlockSourceKind, BlockTransactions, FileReader, Lru, MempoolDelta, RpcError,
+    };
+    use crate::types::FilePosition;
+    use anyhow::{Context, Result};
+    use bitcoin::{
+        blockdata::constants::genesis_block,
+        consensus::{serialize, Decodable},
+        hashes::Hash,
+        Amount, BlockHash, Network, OutPoint, Script, Transaction, TxIn, TxOut, Txid,
+    };
+    use parking_lot::Mutex;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::fs::OpenOptions;
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    fn read_all(reader: &FileReader, pos: FilePosition) -> Vec<u8> {
+        let mut buf = vec![];
+        reader.open(pos).unwrap().read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_skip_transaction() {
+        let mut segwit = tx(vec![input(0), input(1)], vec![output(1), output(2)]);
+        segwit.input[1].witness = vec![vec![1, 2, 3], vec![]];
+        let legacy = tx(vec![input(2)], vec![output(3)]);
+        for first in &[&segwit, &legacy] {
+            let bytes = [serialize(*first), serialize(&legacy), serialize(&segwit)].concat();
+            let mut r = &bytes[..];
+            skip_transaction(&mut r).unwrap();
+            assert_eq!(Transaction::consensus_decode(&mut r).unwrap(), legacy);
+            skip_transaction(&mut r).unwrap();
+            assert!(r.is_empty());
+        }
+        let bytes = serialize(&segwit);
+        assert!(skip_transaction(&mut &bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_coinbase_message() {
+        assert_eq!(coinbase_message(b"\x03\xa0\xbb\x0d/pool/"), b"/pool/");
+        assert_eq!(coinbase_message(b"\x51\x00"), b"\x00"); // regtest height 1
+        assert_eq!(coinbase_message(b"\x4c\x01\x02tag"), b"tag");
+        assert_eq!(coinbase_message(b"\x04\x01"), b""); // truncated push
+        assert_eq!(coinbase_message(b""), b"");
+    }
+
+    #[test]
+    fn test_serialize_stripped() {
+        let mut segwit = tx(vec![input(0), input(1)], vec![output(1)]);
+        segwit.input[1].witness = vec![vec![1, 2, 3]];
+        let stripped = serialize_stripped(&segwit);
+        assert_eq!(Txid::hash(&stripped), segwit.txid());
+        assert_ne!(Txid::hash(&serialize(&segwit)), segwit.txid());
+
+        let legacy = tx(vec![input(2)], vec![output(3)]);
+        assert_eq!(serialize_stripped(&legacy), serialize(&legacy));
+    }
+
+    #[test]
+    fn test_mempool_delta() {
+        let txid = |i: u8| Txid::from_inner([i; 32]);
+        let prev = [txid(1), txid(2)].iter().copied().collect();
+        let curr = [txid(2), txid(3)].iter().copied().collect();
+        let delta = MempoolDelta::new(&prev, &curr);
+        assert_eq!(delta.added, vec![txid(3)]);
+        assert_eq!(delta.removed, vec![txid(1)]);
+
+        let delta = MempoolDelta::new(&curr, &curr);
+        assert!(delta.added.is_empty() && delta.removed.is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_chainstates() {
+        let single =
+            json!({"headers": 800_000, "chainstates": [{"blocks": 800_000, "validated": true}]});
+        assert!(snapshot_chainstates(&single).is_none());
+
+        let snapshot = json!({"headers": 800_000, "chainstates": [
+            {"blocks": 1000, "validated": true},
+            {"blocks": 800_000, "validated": false, "snapshot_blockhash": "00"},
+        ]});
+        let (background, snapshot) = snapshot_chainstates(&snapshot).unwrap();
+        assert_eq!(background["blocks"], 1000);
+        assert_eq!(snapshot["snapshot_blockhash"], "00");
+    }
+
+    #[test]
+    fn test_file_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blk00001.dat");
+        std::fs::write(&path, b"0123456789").unwrap();
+
+        let file_reader = FileReader {
+            blocks_dirs: vec![dir.path().to_owned()],
+            mappings: None,
+            handles: None,
+            xor_key: None,
+        };
+        let mmap_reader = FileReader {
+            blocks_dirs: vec![dir.path().to_owned()],
+            mappings: Some(Mutex::new(Lru::new(1))),
+            handles: None,
+            xor_key: None,
+        };
+        let cached_reader = FileReader {
+            blocks_dirs: vec![dir.path().to_owned()],
+            mappings: None,
+            handles: Some(Mutex::new(Lru::new(1))),
+            xor_key: None,
+        };
+        let pos = FilePosition {
+            file_id: 1,
+            offset: 4,
+        };
+        for reader in &[&file_reader, &mmap_reader, &cached_reader] {
+            assert_eq!(read_all(reader, pos), b"456789");
+            assert_eq!(reader.read_range(pos, 3).unwrap(), b"456");
+            assert_eq!(reader.read_range(pos, 100).unwrap(), b"456789");
+            assert!(reader.open(pos.with_offset(0)).is_ok());
+        }
+        let missing = FilePosition {
+            file_id: 2,
+            offset: 0,
+        };
+        assert!(file_reader.open(missing).is_err());
+        assert!(mmap_reader.open(missing).is_err());
+        assert!(cached_reader.open(missing).is_err());
+
+        // new data is appended by bitcoind
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"abc").unwrap();
+        assert_eq!(read_all(&file_reader, pos), b"456789abc");
+        assert_eq!(read_all(&mmap_reader, pos), b"456789abc");
+        assert_eq!(read_all(&cached_reader, pos), b"456789abc");
+
+        // concurrent readers of a cached handle don't share their positions
+        let mut first = cached_reader.open(pos).unwrap();
+        let mut second = cached_reader.open(pos.with_offset(1)).unwrap();
+        let mut buf = [0u8; 2];
+        first.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"45");
+        second.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"12");
+        assert_eq!(first.seek(SeekFrom::End(-1)).unwrap(), 12);
+        assert!(first.seek(SeekFrom::Current(-13)).is_err());
+    }
+
+    #[test]
+    fn test_multiple_blocks_dirs() {
+        let fast = tempfile::tempdir().unwrap();
+        let archive = tempfile::tempdir().unwrap();
+        std::fs::write(fast.path().join("blk00001.dat"), b"fast").unwrap();
+        std::fs::write(archive.path().join("blk00000.dat"), b"archived").unwrap();
+        std::fs::write(archive.path().join("blk00001.dat"), b"stale").unwrap();
+
+        let reader = FileReader {
+            blocks_dirs: vec![fast.path().to_owned(), archive.path().to_owned()],
+            mappings: None,
+            handles: None,
+            xor_key: None,
+        };
+        let pos = |file_id| FilePosition { file_id, offset: 0 };
+        assert_eq!(read_all(&reader, pos(0)), b"archived");
+        assert_eq!(read_all(&reader, pos(1)), b"fast"); // the first directory is preferred
+        assert!(reader.open(pos(2)).is_err());
+    }
+
+    #[test]
+    fn test_obfuscated_block_files() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(read_xor_key(dir.path()).unwrap(), None); // older bitcoind
+        std::fs::write(dir.path().join("xor.dat"), [0u8; 8]).unwrap();
+        assert_eq!(read_xor_key(dir.path()).unwrap(), None); // obfuscation is disabled
+        std::fs::write(dir.path().join("xor.dat"), [1u8, 2, 3]).unwrap();
+        assert!(read_xor_key(dir.path()).is_err());
+
+        let key: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+        std::fs::write(dir.path().join("xor.dat"), key).unwrap();
+        assert_eq!(read_xor_key(dir.path()).unwrap(), Some(key));
+        let data = b"0123456789";
+        let obfuscated: Vec<u8> = data
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ key[i % 8])
+            .collect();
+        std::fs::write(dir.path().join("blk00001.dat"), &obfuscated).unwrap();
+        std::fs::write(dir.path().join("rev00001.dat"), &obfuscated).unwrap();
+
+        let cached_reader = FileReader {
+            blocks_dirs: vec![dir.path().to_owned()],
+            mappings: None,
+            handles: Some(Mutex::new(Lru::new(1))),
+            xor_key: Some(key),
+        };
+        let mmap_reader = FileReader {
+            blocks_dirs: vec![dir.path().to_owned()],
+            mappings: Some(Mutex::new(Lru::new(1))),
+            handles: None,
+            xor_key: Some(key),
+        };
+        let pos = FilePosition {
+            file_id: 1,
+            offset: 7, // spanning the key boundary
+        };
+        for reader in &[&cached_reader, &mmap_reader] {
+            assert_eq!(read_all(reader, pos), b"789");
+            assert_eq!(reader.read_range(pos.with_offset(0), 4).unwrap(), b"0123");
+            let mut undo = vec![];
+            reader
+                .open_undo(pos)
+                .unwrap()
+                .read_to_end(&mut undo)
+                .unwrap();
+            assert_eq!(undo, b"789");
+        }
+    }
+
+    #[test]
+    fn test_read_stale_block() {
+        let stale = genesis_block(Network::Regtest);
+        let block = genesis_block(Network::Bitcoin);
+        let stale_bytes = serialize(&stale);
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("blk00000.dat"),
+            [stale_bytes.clone(), serialize(&block)].concat(),
+        )
+        .unwrap();
+        let reader = FileReader {
+            blocks_dirs: vec![dir.path().to_owned()],
+            mappings: None,
+            handles: None,
+            xor_key: None,
+        };
+        let stale_pos = FilePosition {
+            file_id: 0,
+            offset: 0,
+        };
+        let current_pos = stale_pos.with_offset(stale_bytes.len() as u32);
+
+        // the first location is stale, but the second one is correct
+        let mut locations = vec![current_pos, stale_pos];
+        let (result, pos) = read_block_at(&reader, block.block_hash(), 0, || {
+            Ok(locations.pop().unwrap())
+        })
+        .unwrap();
+        assert_eq!(result.block_hash(), block.block_hash());
+        assert_eq!(pos, current_pos);
+
+        // the location is still stale after re-querying
+        let err = read_block_at(&reader, block.block_hash(), 0, || Ok(stale_pos)).unwrap_err();
+        assert_eq!(read_error_kind(&err), "hash_mismatch");
+        let (result, _) = read_block_at(&reader, stale.block_hash(), 0, || Ok(stale_pos)).unwrap();
+        assert_eq!(result.block_hash(), stale.block_hash());
+
+        // using a buffer smaller than the block
+        let (result, _) =
+            read_block_at(&reader, block.block_hash(), 64, || Ok(current_pos)).unwrap();
+        assert_eq!(result.block_hash(), block.block_hash());
+    }
+
+    #[test]
+    fn test_read_block() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("blk00000.dat"),
+            b"magi\x03\x00\x00\x00abcdef",
+        )
+        .unwrap();
+        let reader = FileReader {
+            blocks_dirs: vec![dir.path().to_owned()],
+            mappings: None,
+            handles: None,
+            xor_key: None,
+        };
+        let pos = FilePosition {
+            file_id: 0,
+            offset: 8,
+        };
+        let mut block = reader.read_block(pos).unwrap();
+        assert_eq!(block.seek(SeekFrom::Current(0)).unwrap(), 8);
+        let mut buf = vec![];
+        block.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"abc"); // only the block's bytes are read
+        assert_eq!(block.seek(SeekFrom::Current(0)).unwrap(), 11);
+        assert_eq!(block.seek(SeekFrom::Start(9)).unwrap(), 9);
+        assert!(block.seek(SeekFrom::Start(7)).is_err());
+
+        assert!(reader.read_block(pos.with_offset(2)).is_err());
+        assert!(reader.read_block(pos.with_offset(10)).is_err()); // invalid size
+
+        std::fs::write(dir.path().join("blk00000.dat"), b"magi\x03\x00\x00\x00ab").unwrap();
+        let err = reader.read_block(pos).map(|_| ()).unwrap_err();
+        assert_eq!(read_error_kind(&err), "short_read");
+        let missing = FilePosition {
+            file_id: 1,
+            offset: 8,
+        };
+        let err = reader.open(missing).map(|_| ()).unwrap_err();
+        assert_eq!(read_error_kind(&err), "file_not_found");
+    }
+
+    #[test]
+    fn test_single_location() {
+        let blockhash = BlockHash::from_inner([1; 32]);
+        let locations: Vec<Option<FilePosition>> =
+            serde_json::from_value(json!([{"file": 1, "data": 8}])).unwrap();
+        assert_eq!(
+            single_location(blockhash, locations).unwrap(),
+            FilePosition {
+                file_id: 1,
+                offset: 8
+            }
+        );
+
+        // unknown blocks' locations are null (or missing)
+        for unknown in &[json!([null]), json!([])] {
+            let locations: Vec<Option<FilePosition>> =
+                serde_json::from_value(unknown.clone()).unwrap();
+            let err = single_location(blockhash, locations).unwrap_err();
+            assert_eq!(err.to_string(), format!("block {} not found", blockhash));
+            assert_eq!(read_error_kind(&err), "block_not_found");
+        }
+    }
+
+    /// In-memory block source, holding the contents of each `blk*.dat` file.
+    struct MemorySource(HashMap<u16, Vec<u8>>);
+
+    impl BlockSource for MemorySource {
+        fn open(&self, pos: FilePosition) -> Result<Box<dyn BlockFile>> {
+            let data = self.0.get(&pos.file_id).context("missing file")?;
+            let mut file = Cursor::new(data.clone());
+            file.set_position(u64::from(pos.offset));
+            Ok(Box::new(file))
+        }
+
+        fn open_undo(&self, _pos: FilePosition) -> Result<Box<dyn Read + Send>> {
+            bail!("missing undo data")
+        }
+    }
+
+    #[test]
+    fn test_block_source_kind() {
+        assert_eq!(
+            "files".parse::<BlockSourceKind>().unwrap(),
+            BlockSourceKind::Files
+        );
+        assert_eq!(
+            "REST".parse::<BlockSourceKind>().unwrap(),
+            BlockSourceKind::Rest
+        );
+        assert!("rpc".parse::<BlockSourceKind>().is_err());
+        assert_eq!(BlockSourceKind::default().to_string(), "files");
+    }
+
+    #[test]
+    fn test_block_source() {
+        let block = genesis_block(Network::Regtest);
+        let block_bytes = serialize(&block);
+        let file = [
+            b"magi".to_vec(),
+            serialize(&(block_bytes.len() as u32)),
+            block_bytes.clone(),
+        ]
+        .concat();
+        let source = MemorySource(std::iter::once((0, file)).collect());
+        let pos = FilePosition {
+            file_id: 0,
+            offset: 8,
+        };
+        let (result, _) = read_block_at(&source, block.block_hash(), 0, || Ok(pos)).unwrap();
+        assert_eq!(result, block);
+
+        let mut buf = vec![];
+        source
+            .read_block(pos)
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(buf, block_bytes);
+        assert_eq!(source.read_range(pos, 4).unwrap(), &block_bytes[..4]);
+        let missing = FilePosition {
+            file_id: 1,
+            offset: 0,
+        };
+        assert!(source.open(missing).is_err());
+    }
+
+    #[test]
+    fn test_block_transactions() {
+        let mut block = genesis_block(Network::Regtest);
+        block.txdata.push(tx(vec![input(0)], vec![output(1000)]));
+        block
+            .txdata
+            .push(tx(vec![input(1)], vec![output(2000), output(3000)]));
+        let block_bytes = serialize(&block);
+        let blockhash = block.block_hash();
+        let pos = FilePosition {
+            file_id: 0,
+            offset: 0,
+        };
+        let open = |bytes: &[u8]| -> Box<dyn BlockFile> { Box::new(Cursor::new(bytes.to_vec())) };
+
+        let txs = BlockTransactions::new(open(&block_bytes), blockhash, pos).unwrap();
+        assert_eq!(txs.collect::<Result<Vec<_>>>().unwrap(), block.txdata);
+
+        // a truncated block fails at its last transaction
+        let truncated = &block_bytes[..block_bytes.len() - 1];
+        let mut txs = BlockTransactions::new(open(truncated), blockhash, pos).unwrap();
+        assert_eq!(txs.next().unwrap().unwrap(), block.txdata[0]);
+        assert_eq!(txs.next().unwrap().unwrap(), block.txdata[1]);
+        assert!(txs.next().unwrap().is_err());
+        assert!(txs.next().is_none());
+
+        let other = genesis_block(Network::Bitcoin).block_hash();
+        let err = BlockTransactions::new(open(&block_bytes), other, pos)
+            .err()
+            .unwrap();
+        assert_eq!(read_error_kind(&err), "hash_mismatch");
+    }
+
+    fn input(vout: u32) -> TxIn {
+        TxIn {
+            previous_output: OutPoint::new(Txid::default(), vout),
+            script_sig: Script::new(),
+            sequence: 0xFFFFFFFF,
+            witness: vec![],
+        }
+    }
+
+    fn output(value: u64) -> TxOut {
+        TxOut {
+            value,
+            script_pubkey: Script::new(),
+        }
+    }
+
+    fn tx(input: Vec<TxIn>, output: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input,
+            output,
+        }
+    }
+
+    #[test]
+    fn test_check_transaction() {
+        assert!(check_transaction(&tx(vec![input(0), input(1)], vec![output(1000)])).is_ok());
+
+        let err = |tx| check_transaction(&tx).unwrap_err().to_string();
+        assert_eq!(
+            err(tx(vec![], vec![output(1000)])),
+            "invalid transaction: no inputs"
+        );
+        assert_eq!(
+            err(tx(vec![input(0)], vec![])),
+            "invalid transaction: no outputs"
+        );
+        assert_eq!(
+            err(tx(vec![input(0), input(0)], vec![output(1000)])),
+            "invalid transaction: duplicate inputs"
+        );
+        assert_eq!(
+            err(tx(vec![input(0)], vec![output(u64::MAX), output(1)])),
+            "invalid transaction: output value out of range"
+        );
+        let mut coinbase = input(0);
+        coinbase.previous_output = OutPoint::null();
+        assert_eq!(
+            err(tx(vec![coinbase], vec![output(1000)])),
+            "invalid transaction: coinbase input"
+        );
+        // non-positive versions are non-standard, but still valid
+        let mut version_zero = tx(vec![input(0)], vec![output(1000)]);
+        version_zero.version = 0;
+        assert!(check_transaction(&version_zero).is_ok());
+    }
+
+    #[test]
+    fn test_effective_fee_rate() {
+        let entry = |vsize, fee: f64, ancestor_size, ancestor_fee: f64| {
+            serde_json::from_value(json!({
+                "vsize": vsize,
+                "weight": vsize * 4,
+                "time": 1700000000,
+                "height": 800000,
+                "descendantcount": 1,
+                "descendantsize": vsize,
+                "ancestorcount": 2,
+                "ancestorsize": ancestor_size,
+                "wtxid": Txid::default(),
+                "fees": {
+                    "base": fee,
+                    "modified": fee,
+                    "ancestor": ancestor_fee,
+                    "descendant": fee,
+                },
+                "depends": [Txid::default()],
+                "spentby": [],
+                "bip125-replaceable": false,
+            }))
+            .unwrap()
+        };
+        let sat_per_vb = |rate| Amount::from_sat(rate * 1000);
+
+        // child paying for its parent (200 vB at 1 sat/vB)
+        let child = entry(100, 0.00002, 300, 0.00002200);
+        assert_eq!(effective_fee_rate(&child), Amount::from_sat(7333)); // 2200 sat / 300 vB
+
+        // high fee rate parent (200 vB at 50 sat/vB)
+        let child = entry(100, 0.00002, 300, 0.00012);
+        assert_eq!(effective_fee_rate(&child), sat_per_vb(20));
+    }
+
+    #[test]
+    fn test_fee_rate_floor() {
+        let sat_per_vb = |rate| Amount::from_sat(rate * 1000);
+        let fee_rates = vec![
+            (sat_per_vb(5), 400),
+            (sat_per_vb(20), 300),
+            (sat_per_vb(1), 500),
+            (sat_per_vb(10), 200),
+        ];
+        assert_eq!(fee_rate_floor(fee_rates.clone(), 499), Some(sat_per_vb(10)));
+        assert_eq!(fee_rate_floor(fee_rates.clone(), 500), Some(sat_per_vb(5)));
+        assert_eq!(fee_rate_floor(fee_rates.clone(), 900), Some(sat_per_vb(1)));
+        assert_eq!(fee_rate_floor(fee_rates.clone(), 1400), None);
+        assert_eq!(fee_rate_floor(vec![], 1000), None);
+    }
+
+    #[test]
+    fn test_already_broadcast() {
+        use bitcoincore_rpc::{jsonrpc, Error};
+
+        let err = |code, message: &str| {
+            Error::JsonRpc(jsonrpc::Error::Rpc(RpcError {
+                code,
+                message: message.to_owned(),
+                data: None,
+            }))
+        };
+        assert!(is_already_broadcast(&err(
+            -27,
+            "Transaction already in block chain"
+        )));
+        assert!(is_already_broadcast(&err(
+            -27,
+            "Transaction outputs already in utxo set"
+        )));
+        assert!(is_already_broadcast(&err(-26, "txn-already-in-mempool")));
+        assert!(is_already_broadcast(&err(-26, "txn-already-known")));
+
+        assert!(!is_already_broadcast(&err(-26, "txn-mempool-conflict")));
+        assert!(!is_already_broadcast(&err(
+            -25,
+            "bad-txns-inputs-missingorspent"
+        )));
+        assert!(!is_already_broadcast(&err(-22, "TX decode failed")));
+        assert!(!is_already_broadcast(&Error::UnexpectedStructure));
+    }
+
+    #[test]
+    fn test_unsafe_rpc_methods() {
+        for method in &[
+            "sendrawtransaction",
+            "sendtoaddress",
+            "walletpassphrase",
+            "stop",
+            "invalidateblock",
+            "pruneblockchain",
+            "getnewaddress",
+            "fundrawtransaction",
+            "unknownmethod",
+        ] {
+            assert!(is_unsafe_rpc_method(method), "{}", method);
+        }
+        assert!(is_unsafe_rpc_method("DumpPrivKey"));
+        assert!(!is_unsafe_rpc_method("GetBlockCount"));
+        for method in &["getblockchaininfo", "getchaintips", "getmempoolinfo"] {
+            assert!(!is_unsafe_rpc_method(method), "{}", method);
+        }
+    }
+}
@@ -1,7 +1,13 @@
 use anyhow::{Context, Result};
 use electrs_rocksdb as rocksdb;
 
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 pub(crate) type Row = Box<[u8]>;
@@ -13,6 +19,9 @@ pub(crate) struct WriteBatch {
     pub(crate) funding_rows: Vec<Row>,
     pub(crate) spending_rows: Vec<Row>,
     pub(crate) txid_rows: Vec<Row>,
+    pub(crate) filter_rows: Vec<(Row, Row)>, // (blockhash, filter)
+    pub(crate) balance_rows: Vec<(Row, i64)>, // (scripthash, balance change in satoshis)
+    pub(crate) stale_rows: StaleRows,        // deleted before writing the new rows
 }
 
 impl WriteBatch {
@@ -21,6 +30,94 @@ impl WriteBatch {
         self.funding_rows.sort_unstable();
         self.spending_rows.sort_unstable();
         self.txid_rows.sort_unstable();
+        self.filter_rows.sort_unstable();
+        self.balance_rows.sort_unstable();
+    }
+}
+
+/// Index rows to be deleted (e.g. when re-indexing blocks)
+#[derive(Default)]
+pub(crate) struct StaleRows {
+    pub(crate) funding_rows: Vec<Row>,
+    pub(crate) spending_rows: Vec<Row>,
+    pub(crate) txid_rows: Vec<Row>,
+}
+
+impl StaleRows {
+    pub(crate) fn len(&self) -> usize {
+        self.funding_rows.len() + self.spending_rows.len() + self.txid_rows.len()
+    }
+}
+
+/// Durability of index DB writes, trading it for sync speed (see `doc/config.md`):
+/// - safe: writes are fsync-ed after the initial sync (which is not resumable after a crash anyway)
+/// - fast: writes are logged but not fsync-ed, and the initial sync uses larger write batches
+/// - nosync: writes skip the log and fsync (an unclean shutdown may require a reindex)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DbSyncMode {
+    Safe,
+    Fast,
+    NoSync,
+}
+
+// Fast sync modes write this many `index_batch_size` chunks at once during the initial sync
+const FAST_BATCH_FACTOR: usize = 4;
+
+impl DbSyncMode {
+    /// Return whether writes are fsync-ed, and whether they use RocksDB's write-ahead log.
+    fn durability(self, bulk_import: bool) -> (bool, bool) {
+        match self {
+            _ if bulk_import => (false, false), // a full compaction is required after bulk import
+            DbSyncMode::Safe => (true, true),
+            DbSyncMode::Fast => (false, true),
+            DbSyncMode::NoSync => (false, false),
+        }
+    }
+
+    /// Return the number of blocks to index before writing them as a single batch.
+    fn batch_size(self, index_batch_size: usize, bulk_import: bool) -> usize {
+        match self {
+            DbSyncMode::Fast | DbSyncMode::NoSync if bulk_import => {
+                index_batch_size.saturating_mul(FAST_BATCH_FACTOR)
+            }
+            _ => index_batch_size,
+        }
+    }
+}
+
+impl Default for DbSyncMode {
+    fn default() -> Self {
+        DbSyncMode::Safe
+    }
+}
+
+impl FromStr for DbSyncMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode.to_ascii_lowercase().as_str() {
+            "safe" => Ok(DbSyncMode::Safe),
+            "fast" => Ok(DbSyncMode::Fast),
+            "nosync" => Ok(DbSyncMode::NoSync),
+            _ => bail!("unknown DB sync mode: {:?}", mode),
+        }
+    }
+}
+
+impl fmt::Display for DbSyncMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self {
+            DbSyncMode::Safe => "safe",
+            DbSyncMode::Fast => "fast",
+            DbSyncMode::NoSync => "nosync",
+        };
+        write!(f, "{}", mode)
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for DbSyncMode {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "either 'safe', 'fast' or 'nosync'")
     }
 }
 
@@ -28,6 +125,7 @@ impl WriteBatch {
 pub struct DBStore {
     db: rocksdb::DB,
     bulk_import: AtomicBool,
+    sync_mode: DbSyncMode,
 }
 
 const CONFIG_CF: &str = "config";
@@ -35,12 +133,25 @@ const HEADERS_CF: &str = "headers";
 const TXID_CF: &str = "txid";
 const FUNDING_CF: &str = "funding";
 const SPENDING_CF: &str = "spending";
-
-const COLUMN_FAMILIES: &[&str] = &[CONFIG_CF, HEADERS_CF, TXID_CF, FUNDING_CF, SPENDING_CF];
+const FILTERS_CF: &str = "filters";
+const BALANCES_CF: &str = "balances";
+
+const COLUMN_FAMILIES: &[&str] = &[
+    CONFIG_CF,
+    HEADERS_CF,
+    TXID_CF,
+    FUNDING_CF,
+    SPENDING_CF,
+    FILTERS_CF,
+    BALANCES_CF,
+];
 
 const CONFIG_KEY: &str = "C";
 const TIP_KEY: &[u8] = b"T";
 
+// Scan prefix length of the funding, spending and txid rows (see `HashPrefixRow`)
+const PREFIX_LEN: usize = 8;
+
 // Taken from https://github.com/facebook/rocksdb/blob/master/include/rocksdb/db.h#L654-L689
 const DB_PROPERIES: &[&str] = &[
     "rocksdb.num-immutable-mem-table",
@@ -82,6 +193,8 @@ const DB_PROPERIES: &[&str] = &[
 struct Config {
     compacted: bool,
     format: u64,
+    #[serde(default)]
+    balances: bool, // whether all indexed blocks have updated the balances
 }
 
 const CURRENT_FORMAT: u64 = 1;
@@ -91,6 +204,7 @@ impl Default for Config {
         Config {
             compacted: false,
             format: CURRENT_FORMAT,
+            balances: false,
         }
     }
 }
@@ -105,7 +219,7 @@ fn default_opts() -> rocksdb::Options {
     opts.set_write_buffer_size(256 << 20);
     opts.set_disable_auto_compactions(true); // for initial bulk load
     opts.set_advise_random_on_open(false); // bulk load uses sequential I/O
-    opts.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(8));
+    opts.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(PREFIX_LEN));
     opts
 }
 
@@ -117,7 +231,7 @@ impl DBStore {
             .collect()
     }
 
-    fn open_internal(path: &Path) -> Result<Self> {
+    fn open_internal(path: &Path, sync_mode: DbSyncMode) -> Result<Self> {
         let mut db_opts = default_opts();
         db_opts.create_if_missing(true);
         db_opts.create_missing_column_families(true);
@@ -135,6 +249,7 @@ impl DBStore {
         let store = DBStore {
             db,
             bulk_import: AtomicBool::new(true),
+            sync_mode,
         };
         Ok(store)
     }
@@ -147,9 +262,26 @@ impl DBStore {
             .is_some()
     }
 
-    /// Opens a new RocksDB at the specified location.
-    pub fn open(path: &Path, auto_reindex: bool) -> Result<Self> {
-        let mut store = Self::open_internal(path)?;
+    /// Opens a new RocksDB at the specified location. Scripthashes' balances are indexed only if
+    /// `balances` is set, requiring a re-index if the DB was indexed without them.
+    pub fn open(
+        path: &Path,
+        auto_reindex: bool,
+        sync_mode: DbSyncMode,
+        balances: bool,
+    ) -> Result<Self> {
+        if sync_mode != DbSyncMode::Safe {
+            warn!(
+                "DB sync mode is {}: an unclean shutdown may lose recent writes{}",
+                sync_mode,
+                if sync_mode == DbSyncMode::NoSync {
+                    " (and require a reindex)"
+                } else {
+                    ""
+                }
+            );
+        }
+        let mut store = Self::open_internal(path, sync_mode)?;
         let config = store.get_config();
         debug!("DB {:?}", config);
         let mut config = config.unwrap_or_default(); // use default config when DB is empty
@@ -161,6 +293,8 @@ impl DBStore {
                 "unsupported format {} != {}",
                 config.format, CURRENT_FORMAT
             ))
+        } else if balances && !config.balances && store.get_tip().is_some() {
+            Some("missing balances".to_owned())
         } else {
             None
         };
@@ -181,12 +315,13 @@ impl DBStore {
                     path.display()
                 )
             })?;
-            store = Self::open_internal(path)?;
+            store = Self::open_internal(path, sync_mode)?;
             config = Config::default(); // re-init config after dropping DB
         }
         if config.compacted {
             store.start_compactions();
         }
+        config.balances = balances; // disabling them invalidates the existing balances
         store.set_config(config);
         Ok(store)
     }
@@ -211,6 +346,14 @@ impl DBStore {
         self.db.cf_handle(HEADERS_CF).expect("missing HEADERS_CF")
     }
 
+    fn filters_cf(&self) -> &rocksdb::ColumnFamily {
+        self.db.cf_handle(FILTERS_CF).expect("missing FILTERS_CF")
+    }
+
+    fn balances_cf(&self) -> &rocksdb::ColumnFamily {
+        self.db.cf_handle(BALANCES_CF).expect("missing BALANCES_CF")
+    }
+
     pub(crate) fn iter_funding(&self, prefix: Row) -> impl Iterator<Item = Row> + '_ {
         self.iter_prefix_cf(self.funding_cf(), prefix)
     }
@@ -246,6 +389,22 @@ impl DBStore {
             .collect()
     }
 
+    pub(crate) fn get_filter(&self, blockhash: &[u8]) -> Option<Vec<u8>> {
+        self.db
+            .get_cf(self.filters_cf(), blockhash)
+            .expect("get_filter failed")
+    }
+
+    /// Get the indexed balance (in satoshis) of a scripthash (see `BalanceRow`).
+    pub(crate) fn get_balance(&self, scripthash: &[u8]) -> i64 {
+        self.db
+            .get_cf(self.balances_cf(), scripthash)
+            .expect("get_balance failed")
+            .map_or(0, |value| {
+                i64::from_le_bytes(value[..].try_into().expect("invalid balance"))
+            })
+    }
+
     pub(crate) fn get_tip(&self) -> Option<Vec<u8>> {
         self.db
             .get_cf(self.headers_cf(), TIP_KEY)
@@ -254,6 +413,16 @@ impl DBStore {
 
     pub(crate) fn write(&self, batch: &WriteBatch) {
         let mut db_batch = rocksdb::WriteBatch::default();
+        // deleted first, since re-indexed blocks may write the same rows again
+        for key in &batch.stale_rows.funding_rows {
+            db_batch.delete_cf(self.funding_cf(), key);
+        }
+        for key in &batch.stale_rows.spending_rows {
+            db_batch.delete_cf(self.spending_cf(), key);
+        }
+        for key in &batch.stale_rows.txid_rows {
+            db_batch.delete_cf(self.txid_cf(), key);
+        }
         for key in &batch.funding_rows {
             db_batch.put_cf(self.funding_cf(), key, b"");
         }
@@ -266,13 +435,64 @@ impl DBStore {
         for key in &batch.header_rows {
             db_batch.put_cf(self.headers_cf(), key, b"");
         }
+        for (key, value) in &batch.filter_rows {
+            db_batch.put_cf(self.filters_cf(), key, value);
+        }
+        // balances are updated only by `write` calls (so there are no concurrent updates)
+        for (key, delta) in &batch.balance_rows {
+            match self.get_balance(key) + delta {
+                0 => db_batch.delete_cf(self.balances_cf(), key),
+                balance => db_batch.put_cf(self.balances_cf(), key, balance.to_le_bytes()),
+            }
+        }
         db_batch.put_cf(self.headers_cf(), TIP_KEY, &batch.tip_row);
+        // balance deltas are not idempotent: they must be persisted atomically with the new tip,
+        // so they are not re-applied after an unclean shutdown
+        let opts = if batch.balance_rows.is_empty() {
+            self.write_opts()
+        } else {
+            durable_write_opts()
+        };
+        self.db.write_opt(db_batch, &opts).unwrap();
+    }
+
+    /// Find the existing funding, spending and txid rows selected by `is_stale`, scanning only the
+    /// prefixes of `batch` new rows (e.g. the rows of re-indexed blocks).
+    pub(crate) fn find_stale_rows(
+        &self,
+        batch: &WriteBatch,
+        is_stale: impl Fn(&[u8]) -> bool,
+    ) -> StaleRows {
+        let find = |cf: &rocksdb::ColumnFamily, rows: &[Row]| -> Vec<Row> {
+            let prefixes: BTreeSet<&[u8]> = rows.iter().map(|row| &row[..PREFIX_LEN]).collect();
+            prefixes
+                .into_iter()
+                .flat_map(|prefix| self.iter_prefix_cf(cf, prefix.to_vec().into_boxed_slice()))
+                .filter(|row| is_stale(row))
+                .collect()
+        };
+        StaleRows {
+            funding_rows: find(self.funding_cf(), &batch.funding_rows),
+            spending_rows: find(self.spending_cf(), &batch.spending_rows),
+            txid_rows: find(self.txid_cf(), &batch.txid_rows),
+        }
+    }
 
+    /// Write options for the DB sync mode (and bulk import state).
+    fn write_opts(&self) -> rocksdb::WriteOptions {
         let mut opts = rocksdb::WriteOptions::new();
-        let bulk_import = self.bulk_import.load(Ordering::Relaxed);
-        opts.set_sync(!bulk_import);
-        opts.disable_wal(bulk_import);
-        self.db.write_opt(db_batch, &opts).unwrap();
+        let (sync, wal) = self
+            .sync_mode
+            .durability(self.bulk_import.load(Ordering::Relaxed));
+        opts.set_sync(sync);
+        opts.disable_wal(!wal);
+        opts
+    }
+
+    /// Return the number of blocks to index in a single `write` (depending on the sync mode).
+    pub(crate) fn batch_size(&self, index_batch_size: usize) -> usize {
+        self.sync_mode
+            .batch_size(index_batch_size, self.bulk_import.load(Ordering::Relaxed))
     }
 
     pub(crate) fn flush(&self) {
@@ -304,6 +524,19 @@ impl DBStore {
         }
     }
 
+    /// Return the disk space (in bytes) available on the DB volume.
+    pub(crate) fn free_disk_space(&self) -> Result<u64> {
+        let path = self.db.path();
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .with_context(|| format!("invalid DB path: {}", path.display()))?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("failed to get free disk space: {}", path.display()));
+        }
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+
     pub(crate) fn get_properties(
         &self,
     ) -> impl Iterator<Item = (&'static str, &'static str, u64)> + '_ {
@@ -331,12 +564,9 @@ impl DBStore {
     }
 
     fn set_config(&self, config: Config) {
-        let mut opts = rocksdb::WriteOptions::default();
-        opts.set_sync(true);
-        opts.disable_wal(false);
         let value = serde_json::to_vec(&config).expect("failed to serialize config");
         self.db
-            .put_cf_opt(self.config_cf(), CONFIG_KEY, value, &opts)
+            .put_cf_opt(self.config_cf(), CONFIG_KEY, value, &durable_write_opts())
             .expect("DB::put failed");
     }
 
@@ -348,6 +578,14 @@ impl DBStore {
     }
 }
 
+/// Write options using the write-ahead log and syncing it (regardless of the DB sync mode).
+fn durable_write_opts() -> rocksdb::WriteOptions {
+    let mut opts = rocksdb::WriteOptions::new();
+    opts.set_sync(true);
+    opts.disable_wal(false);
+    opts
+}
+
 impl Drop for DBStore {
     fn drop(&mut self) {
         info!("closing DB at {}", self.db.path().display());
@@ -356,19 +594,22 @@ impl Drop for DBStore {
 
 #[cfg(test)]
 mod tests {
-    use super::{rocksdb, DBStore, WriteBatch, CURRENT_FORMAT};
+    use super::{rocksdb, DBStore, DbSyncMode, WriteBatch, CURRENT_FORMAT};
 
     #[test]
     fn test_reindex_new_format() {
         let dir = tempfile::tempdir().unwrap();
         {
-            let store = DBStore::open(dir.path(), false).unwrap();
+            let store = DBStore::open(dir.path(), false, DbSyncMode::Safe, false).unwrap();
             let mut config = store.get_config().unwrap();
             config.format += 1;
             store.set_config(config);
         };
         assert_eq!(
-            DBStore::open(dir.path(), false).err().unwrap().to_string(),
+            DBStore::open(dir.path(), false, DbSyncMode::Safe, false)
+                .err()
+                .unwrap()
+                .to_string(),
             format!(
                 "re-index required due to unsupported format {} != {}",
                 CURRENT_FORMAT + 1,
@@ -376,7 +617,7 @@ mod tests {
             )
         );
         {
-            let store = DBStore::open(dir.path(), true).unwrap();
+            let store = DBStore::open(dir.path(), true, DbSyncMode::Safe, false).unwrap();
             store.flush();
             let config = store.get_config().unwrap();
             assert_eq!(config.format, CURRENT_FORMAT);
@@ -394,11 +635,14 @@ mod tests {
             db.put(b"F", b"").unwrap(); // insert legacy DB compaction marker (in 'default' column family)
         };
         assert_eq!(
-            DBStore::open(dir.path(), false).err().unwrap().to_string(),
+            DBStore::open(dir.path(), false, DbSyncMode::Safe, false)
+                .err()
+                .unwrap()
+                .to_string(),
             format!("re-index required due to legacy format",)
         );
         {
-            let store = DBStore::open(dir.path(), true).unwrap();
+            let store = DBStore::open(dir.path(), true, DbSyncMode::Safe, false).unwrap();
             store.flush();
             let config = store.get_config().unwrap();
             assert_eq!(config.format, CURRENT_FORMAT);
@@ -408,7 +652,7 @@ mod tests {
     #[test]
     fn test_db_prefix_scan() {
         let dir = tempfile::tempdir().unwrap();
-        let store = DBStore::open(dir.path(), true).unwrap();
+        let store = DBStore::open(dir.path(), true, DbSyncMode::Safe, false).unwrap();
 
         let items: &[&[u8]] = &[
             b"ab",
@@ -429,6 +673,103 @@ mod tests {
         assert_eq!(rows.collect::<Vec<_>>(), to_rows(&items[1..5]));
     }
 
+    #[test]
+    fn test_db_sync_mode() {
+        assert_eq!("safe".parse::<DbSyncMode>().unwrap(), DbSyncMode::Safe);
+        assert_eq!("Fast".parse::<DbSyncMode>().unwrap(), DbSyncMode::Fast);
+        assert_eq!("nosync".parse::<DbSyncMode>().unwrap(), DbSyncMode::NoSync);
+        assert!("sync".parse::<DbSyncMode>().is_err());
+
+        // (sync, wal) during and after the initial sync
+        assert_eq!(DbSyncMode::Safe.durability(true), (false, false));
+        assert_eq!(DbSyncMode::Safe.durability(false), (true, true));
+        assert_eq!(DbSyncMode::Fast.durability(true), (false, false));
+        assert_eq!(DbSyncMode::Fast.durability(false), (false, true));
+        assert_eq!(DbSyncMode::NoSync.durability(false), (false, false));
+
+        assert_eq!(DbSyncMode::Safe.batch_size(10, true), 10);
+        assert_eq!(DbSyncMode::Fast.batch_size(10, true), 40);
+        assert_eq!(DbSyncMode::Fast.batch_size(10, false), 10);
+        assert_eq!(DbSyncMode::NoSync.batch_size(10, true), 40);
+        assert_eq!(DbSyncMode::NoSync.batch_size(usize::MAX, true), usize::MAX);
+    }
+
+    #[test]
+    fn test_batch_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), true, DbSyncMode::Fast, false).unwrap();
+        assert_eq!(store.batch_size(10), 40); // initial sync
+        store.flush();
+        assert_eq!(store.batch_size(10), 10);
+    }
+
+    #[test]
+    fn test_balances() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), false, DbSyncMode::Safe, true).unwrap();
+        let (a, b): (&[u8], &[u8]) = (b"aaaaaaaa", b"bbbbbbbb");
+        let write = |deltas: &[(&[u8], i64)]| {
+            let mut batch = WriteBatch::default();
+            batch.balance_rows = deltas
+                .iter()
+                .map(|(key, delta)| (key.to_vec().into_boxed_slice(), *delta))
+                .collect();
+            store.write(&batch);
+        };
+        write(&[(a, 1000)]);
+        write(&[(a, -400), (b, 400)]);
+        assert_eq!((store.get_balance(a), store.get_balance(b)), (600, 400));
+        write(&[(a, -600)]);
+        assert_eq!(store.get_balance(a), 0);
+        assert!(store.db.get_cf(store.balances_cf(), a).unwrap().is_none());
+        drop(store);
+
+        // enabling balances requires a re-index (unless the DB is empty)
+        let store = DBStore::open(dir.path(), false, DbSyncMode::Safe, false).unwrap();
+        drop(store);
+        let err = DBStore::open(dir.path(), false, DbSyncMode::Safe, true)
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "re-index required due to missing balances");
+        let store = DBStore::open(dir.path(), true, DbSyncMode::Safe, true).unwrap();
+        assert_eq!(store.get_balance(b), 0);
+        assert!(store.get_tip().is_none());
+    }
+
+    #[test]
+    fn test_stale_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), true, DbSyncMode::Safe, false).unwrap();
+        let mut batch = WriteBatch::default();
+        batch.funding_rows = to_rows(&[b"aaaaaaaa1", b"aaaaaaaa2", b"bbbbbbbb1"]);
+        batch.spending_rows = to_rows(&[b"cccccccc2"]);
+        batch.txid_rows = to_rows(&[b"dddddddd1", b"dddddddd2"]);
+        store.write(&batch);
+
+        // only the new rows' prefixes are scanned
+        let mut batch = WriteBatch::default();
+        batch.funding_rows = to_rows(&[b"aaaaaaaa3"]);
+        batch.spending_rows = to_rows(&[b"cccccccc3"]);
+        batch.txid_rows = to_rows(&[b"dddddddd2"]);
+        assert_eq!(store.find_stale_rows(&batch, |_| true).len(), 5);
+        let stale = store.find_stale_rows(&batch, |row| row.last() == Some(&b'2'));
+        assert_eq!(stale.funding_rows, to_rows(&[b"aaaaaaaa2"]));
+        assert_eq!(stale.spending_rows, to_rows(&[b"cccccccc2"]));
+        assert_eq!(stale.txid_rows, to_rows(&[b"dddddddd2"]));
+
+        // stale rows are deleted before the new ones are written
+        batch.stale_rows = stale;
+        store.write(&batch);
+        let prefix = |p: &[u8]| p.to_vec().into_boxed_slice();
+        let funding: Vec<_> = store.iter_funding(prefix(b"aaaaaaaa")).collect();
+        assert_eq!(funding, to_rows(&[b"aaaaaaaa1", b"aaaaaaaa3"]));
+        assert_eq!(store.iter_funding(prefix(b"bbbbbbbb")).count(), 1);
+        let spending: Vec<_> = store.iter_spending(prefix(b"cccccccc")).collect();
+        assert_eq!(spending, to_rows(&[b"cccccccc3"]));
+        let txids: Vec<_> = store.iter_txid(prefix(b"dddddddd")).collect();
+        assert_eq!(txids, to_rows(&[b"dddddddd1", b"dddddddd2"]));
+    }
+
     fn to_rows(values: &[&[u8]]) -> Vec<Box<[u8]>> {
         values
             .iter()
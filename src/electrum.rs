@@ -1,31 +1,36 @@
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use bitcoin::{
     consensus::{deserialize, serialize},
     hashes::hex::{FromHex, ToHex},
-    BlockHash, Txid,
+    BlockHash, Network, OutPoint, Transaction, Txid,
 };
 use crossbeam_channel::Receiver;
 use rayon::prelude::*;
 use serde_derive::Deserialize;
 use serde_json::{self, json, Value};
 
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 use std::iter::FromIterator;
+use std::sync::Arc;
+use std::time::Duration;
 
 use crate::{
     cache::Cache,
     config::{Config, ELECTRS_VERSION},
-    daemon::{self, extract_bitcoind_error, Daemon},
+    daemon::{self, extract_bitcoind_error, serialize_stripped, Daemon, TxSizeInfo},
+    fees::FeeRateUnit,
     merkle::Proof,
     metrics::{self, Histogram, Metrics},
+    script::{script_to_asm, scriptpubkey_to_addresses, validate_address},
     signals::Signal,
-    status::ScriptHashStatus,
-    tracker::Tracker,
+    status::{HistoryCursor, ScriptHashStatus},
+    tracker::{self, Tracker},
     types::ScriptHash,
 };
 
 const PROTOCOL_VERSION: &str = "1.4";
 const UNKNOWN_FEE: isize = -1; // (allowed by Electrum protocol)
+const MAX_MEMPOOL_WATCHED: usize = 10_000; // per client
 
 const UNSUBSCRIBED_QUERY_MESSAGE: &str = "your wallet uses less efficient method of querying electrs, consider contacting the developer of your wallet. Reason:";
 
@@ -34,6 +39,18 @@ const UNSUBSCRIBED_QUERY_MESSAGE: &str = "your wallet uses less efficient method
 pub struct Client {
     tip: Option<BlockHash>,
     scripthashes: HashMap<ScriptHash, ScriptHashStatus>,
+    mempool_watched: HashSet<ScriptHash>, // notified about new mempool transactions
+    fee_rate_unit: Option<FeeRateUnit>,   // overrides the default fee rate unit
+    tag: Option<Arc<str>>,                // used for accounting bitcoind RPCs (e.g. client subnet)
+}
+
+impl Client {
+    pub fn new(tag: Option<Arc<str>>) -> Self {
+        Self {
+            tag,
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -64,13 +81,55 @@ enum Version {
 enum TxGetArgs {
     Txid((Txid,)),
     TxidVerbose(Txid, bool),
+    TxidVerbosePrevouts(Txid, bool, bool),
+}
+
+/// A block, specified by its hash or height.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BlockId {
+    Hash(BlockHash),
+    Height(usize),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EstimateRawFeeArgs {
+    Blocks((u16,)),
+    BlocksThreshold(u16, f64),
+}
+
+impl From<&EstimateRawFeeArgs> for (u16, Option<f64>) {
+    fn from(args: &EstimateRawFeeArgs) -> Self {
+        match args {
+            EstimateRawFeeArgs::Blocks((nblocks,)) => (*nblocks, None),
+            EstimateRawFeeArgs::BlocksThreshold(nblocks, threshold) => (*nblocks, Some(*threshold)),
+        }
+    }
 }
 
-impl From<&TxGetArgs> for (Txid, bool) {
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TxIdFromPosArgs {
+    HeightPos(usize, usize),
+    HeightPosMerkle(usize, usize, bool),
+}
+
+impl From<&TxIdFromPosArgs> for (usize, usize, bool) {
+    fn from(args: &TxIdFromPosArgs) -> Self {
+        match args {
+            TxIdFromPosArgs::HeightPos(height, tx_pos) => (*height, *tx_pos, false),
+            TxIdFromPosArgs::HeightPosMerkle(height, tx_pos, merkle) => (*height, *tx_pos, *merkle),
+        }
+    }
+}
+
+impl From<&TxGetArgs> for (Txid, bool, bool) {
     fn from(args: &TxGetArgs) -> Self {
         match args {
-            TxGetArgs::Txid((txid,)) => (*txid, false),
-            TxGetArgs::TxidVerbose(txid, verbose) => (*txid, *verbose),
+            TxGetArgs::Txid((txid,)) => (*txid, false, false),
+            TxGetArgs::TxidVerbose(txid, verbose) => (*txid, *verbose, false),
+            TxGetArgs::TxidVerbosePrevouts(txid, verbose, prevouts) => (*txid, *verbose, *prevouts),
         }
     }
 }
@@ -89,6 +148,8 @@ enum RpcError {
     BadRequest(anyhow::Error),
     DaemonError(daemon::RpcError),
     UnavailableIndex,
+    MempoolOnly,
+    MempoolLoading,
 }
 
 impl RpcError {
@@ -112,6 +173,12 @@ impl RpcError {
                 // Internal JSON-RPC error (https://www.jsonrpc.org/specification#error_object)
                 json!({"code": -32603, "message": "unavailable index"})
             }
+            RpcError::MempoolOnly => {
+                json!({"code": -32603, "message": "unavailable in mempool-only mode (blocks are not indexed)"})
+            }
+            RpcError::MempoolLoading => {
+                json!({"code": -32603, "message": "mempool still loading"})
+            }
         }
     }
 }
@@ -125,6 +192,14 @@ pub struct Rpc {
     signal: Signal,
     banner: String,
     port: u16,
+    enable_admin_rpc: bool,
+    verify_continue_on_error: bool,
+    enable_estimate_raw_fee: bool,
+    enable_utxo_set_info: bool,
+    fee_rate_unit: FeeRateUnit,
+    max_history_txs: Option<usize>,
+    network: Network,
+    script_asm: bool,
 }
 
 impl Rpc {
@@ -139,7 +214,11 @@ impl Rpc {
 
         let signal = Signal::new();
         let daemon = Daemon::connect(config, signal.exit_flag(), &metrics)?;
-        let cache = Cache::new(&metrics);
+        let cache = Cache::new(
+            &metrics,
+            config.cache_min_confirmations,
+            config.tx_info_compression_threshold,
+        );
         let tracker = Tracker::new(config, &daemon, &metrics)?;
         Ok(Self {
             tracker,
@@ -149,6 +228,14 @@ impl Rpc {
             signal,
             banner: config.server_banner.clone(),
             port: config.electrum_rpc_addr.port(),
+            enable_admin_rpc: config.enable_admin_rpc,
+            verify_continue_on_error: config.verify_continue_on_error,
+            enable_estimate_raw_fee: config.enable_estimate_raw_fee,
+            enable_utxo_set_info: config.utxo_set_info_ttl.is_some(),
+            fee_rate_unit: config.fee_rate_unit,
+            max_history_txs: config.max_history_txs,
+            network: config.network,
+            script_asm: config.script_asm,
         })
     }
 
@@ -156,12 +243,30 @@ impl Rpc {
         &self.signal
     }
 
+    pub(crate) fn shutdown(&self) {
+        self.daemon.shutdown();
+    }
+
     pub fn new_block_notification(&self) -> Receiver<()> {
         self.daemon.new_block_notification()
     }
 
     pub fn sync(&mut self) -> Result<bool> {
-        self.tracker.sync(&self.daemon, self.signal.exit_flag())
+        let done = self.tracker.sync(&self.daemon, self.signal.exit_flag())?;
+        if done {
+            self.daemon.update_blockchain_info();
+            if let Err(e) = self.daemon.check_restart() {
+                warn!("{:#}", e); // retried on the next sync
+            }
+            if !matches!(self.tracker.status(), Err(tracker::Error::MempoolOnly)) {
+                // blocks are not indexed in mempool-only mode
+                self.daemon.check_stale_tip(self.tracker.chain());
+                self.daemon.check_p2p_watchdog(self.tracker.chain());
+                self.daemon.check_tip_lag(self.tracker.chain());
+            }
+            self.daemon.check_indexes();
+        }
+        Ok(done)
     }
 
     pub fn update_client(&self, client: &mut Client) -> Result<Vec<String>> {
@@ -185,6 +290,18 @@ impl Rpc {
             .collect::<Result<Vec<Value>>>()
             .context("failed to update status")?;
 
+        if !client.mempool_watched.is_empty() {
+            let new_txs = self
+                .tracker
+                .filter_new_mempool_txs(&client.mempool_watched, &self.daemon)?;
+            notifications.extend(new_txs.into_iter().map(|(scripthash, txid)| {
+                notification(
+                    "blockchain.scripthash.mempool",
+                    &[json!(scripthash), json!(txid)],
+                )
+            }));
+        }
+
         if let Some(old_tip) = client.tip {
             let new_tip = self.tracker.chain().tip();
             if old_tip != new_tip {
@@ -208,6 +325,85 @@ impl Rpc {
         Ok(json!({"hex": serialize(header).to_hex(), "height": height}))
     }
 
+    fn admin_block_positions(&self, (start, end): &(usize, usize)) -> Result<Value> {
+        let chain = self.tracker.chain();
+        Ok(json!(self
+            .daemon
+            .get_block_positions_range(chain, *start, *end)?))
+    }
+
+    fn admin_dump_block(&self, (blockhash, len): &(BlockHash, usize)) -> Result<Value> {
+        let pos = self.daemon.get_block_location(*blockhash)?;
+        // include the preceding magic and size fields (if available)
+        let prefix = std::cmp::min(pos.offset, 8);
+        let start = pos.with_offset(pos.offset - prefix);
+        let len = len
+            .checked_add(prefix as usize)
+            .ok_or_else(|| anyhow!("too many bytes requested: {}", len))?;
+        let bytes = self.daemon.read_range(start, len)?;
+        Ok(json!({
+            "file": pos.file_id,
+            "offset": pos.offset,
+            "dump": hex_dump(start.offset, &bytes),
+        }))
+    }
+
+    fn admin_refresh_capabilities(&self) -> Result<Value> {
+        self.daemon.refresh_capabilities()?;
+        Ok(Value::Null)
+    }
+
+    fn admin_rpc(&self, (method, params): &(String, Vec<Value>)) -> Result<Value> {
+        self.daemon.call_whitelisted(method, params)
+    }
+
+    /// Verify the blocks at heights `start..end` (truncated at the tip), reporting all failures
+    /// if `verify_continue_on_error` is set (instead of failing on the first one).
+    fn admin_verify_blocks(&self, (start, end): &(usize, usize)) -> Result<Value> {
+        let chain = self.tracker.chain();
+        let end = std::cmp::min(*end, chain.height() + 1);
+        let blockhashes: Vec<BlockHash> = (*start..end)
+            .map(|height| chain.get_block_hash(height).expect("missing block hash"))
+            .collect();
+        if !self.verify_continue_on_error {
+            self.daemon.verify_blocks(&blockhashes)?;
+            return Ok(json!({"verified": blockhashes.len(), "failures": []}));
+        }
+        let failures: Vec<Value> = self
+            .daemon
+            .verify_blocks_report(&blockhashes)?
+            .into_iter()
+            .map(|failure| {
+                json!({
+                    "hash": failure.blockhash,
+                    "file": failure.pos.map(|pos| pos.file_id),
+                    "offset": failure.pos.map(|pos| pos.offset),
+                    "kind": failure.kind,
+                    "error": failure.error,
+                })
+            })
+            .collect();
+        Ok(json!({"verified": blockhashes.len(), "failures": failures}))
+    }
+
+    /// Note: blocks the server until the whole range is re-indexed (see `Index::reindex_range`).
+    fn admin_reindex_range(&self, (start, end): &(usize, usize)) -> Result<Value> {
+        let deleted = self.tracker.reindex_range(&self.daemon, *start, *end)?;
+        Ok(json!({"reindexed": end - start, "deleted_rows": deleted}))
+    }
+
+    /// Note: blocks the server until a new block arrives (or `timeout_secs` expires).
+    fn admin_wait_for_new_block(&self, (timeout_secs,): (u64,)) -> Result<Value> {
+        let block = self
+            .daemon
+            .wait_for_new_block(Duration::from_secs(timeout_secs))?;
+        Ok(json!({"hash": block.hash, "height": block.height}))
+    }
+
+    fn address_validate(&self, (address,): &(String,)) -> Result<Value> {
+        Ok(json!(validate_address(address, self.network)?))
+    }
+
     fn block_header(&self, (height,): (usize,)) -> Result<Value> {
         let chain = self.tracker.chain();
         let header = match chain.get_block_header(height) {
@@ -233,16 +429,158 @@ impl Rpc {
         Ok(json!({"count": count, "hex": hex_headers, "max": max_count}))
     }
 
-    fn estimate_fee(&self, (nblocks,): (u16,)) -> Result<Value> {
+    fn block_is_active(&self, (blockhash,): &(BlockHash,)) -> Result<Value> {
+        Ok(json!(self.daemon.is_block_on_active_chain(*blockhash)?))
+    }
+
+    fn block_locator(&self, (blockhash,): &(BlockHash,)) -> Result<Value> {
+        Ok(json!(self
+            .daemon
+            .get_block_locator(self.tracker.chain(), *blockhash)?))
+    }
+
+    fn block_output_types(&self, (blockhash,): &(BlockHash,)) -> Result<Value> {
+        Ok(json!(self.daemon.get_block_output_types(*blockhash)?))
+    }
+
+    fn block_hash(&self, block_id: &BlockId) -> Result<BlockHash> {
+        Ok(match block_id {
+            BlockId::Hash(blockhash) => *blockhash,
+            BlockId::Height(height) => match self.tracker.chain().get_block_hash(*height) {
+                None => bail!("missing block at {}", height),
+                Some(blockhash) => blockhash,
+            },
+        })
+    }
+
+    fn block_coinbase_message(&self, (block_id,): &(BlockId,)) -> Result<Value> {
+        let blockhash = self.block_hash(block_id)?;
+        Ok(json!(self.daemon.get_coinbase_message(blockhash)?.to_hex()))
+    }
+
+    fn block_find_by_time(&self, (time,): &(u32,)) -> Result<Value> {
+        let chain = self.tracker.chain();
+        Ok(match chain.find_block_by_time(*time) {
+            None => Value::Null,
+            Some(height) => {
+                let header = chain.get_block_header(height).unwrap();
+                json!({"height": height, "hash": header.block_hash(), "time": header.time})
+            }
+        })
+    }
+
+    fn block_at_time(&self, (time,): &(u32,)) -> Result<Value> {
+        let chain = self.tracker.chain();
+        let (height, blockhash) = chain.block_at_time(*time);
+        Ok(json!({
+            "height": height,
+            "hash": blockhash,
+            "time": chain.get_block_header(height).unwrap().time,
+            "mediantime": chain.get_median_time_past(height).unwrap(),
+        }))
+    }
+
+    fn block_interval_stats(&self, (window,): &(usize,)) -> Result<Value> {
+        ensure!(*window > 0, "empty block interval window");
+        let stats = self
+            .tracker
+            .chain()
+            .block_interval_stats(*window)
+            .context("no block intervals before the first block")?;
+        Ok(json!({
+            "count": stats.count,
+            "mean": stats.mean,
+            "median": stats.median,
+            "min": stats.min,
+            "max": stats.max,
+            "non_monotonic": stats.non_monotonic,
+        }))
+    }
+
+    fn block_mtp(&self, (block_id,): &(BlockId,)) -> Result<Value> {
+        let blockhash = self.block_hash(block_id)?;
+        Ok(json!(self
+            .daemon
+            .get_block_mtp(blockhash, self.tracker.chain())?))
+    }
+
+    fn block_weight_sigops(&self, (block_id,): &(BlockId,)) -> Result<Value> {
+        let blockhash = self.block_hash(block_id)?;
+        let (weight, sigops) = self.daemon.get_block_weight_sigops(blockhash)?;
+        Ok(json!({"weight": weight, "sigops": sigops}))
+    }
+
+    fn block_prevouts(&self, (block_id,): &(BlockId,)) -> Result<Value> {
+        let (block, _pos) = match block_id {
+            BlockId::Hash(blockhash) => self.daemon.read_block(*blockhash)?,
+            BlockId::Height(height) => self
+                .daemon
+                .read_block_at_height(*height, self.tracker.chain())?,
+        };
+        let prevouts = self.daemon.get_block_prevouts(&block)?;
+        Ok(json!(prevouts
+            .into_iter()
+            .map(|(outpoint, txout)| {
+                let mut value = json!({
+                    "value": txout.value,
+                    "scriptpubkey": txout.script_pubkey.to_hex(),
+                    "addresses": scriptpubkey_to_addresses(&txout.script_pubkey, self.network),
+                });
+                if self.script_asm {
+                    value["scriptpubkey_asm"] = json!(script_to_asm(&txout.script_pubkey));
+                }
+                (outpoint.to_string(), value)
+            })
+            .collect::<HashMap<String, Value>>()))
+    }
+
+    fn block_time(&self, (block_id,): &(BlockId,)) -> Result<Value> {
+        let blockhash = self.block_hash(block_id)?;
+        Ok(json!(self
+            .daemon
+            .get_block_time(blockhash, self.tracker.chain())?))
+    }
+
+    fn block_get_verbose(&self, (blockhash, verbosity): &(BlockHash, u8)) -> Result<Value> {
+        let mut block = self.daemon.get_block_verbose(*blockhash, *verbosity)?;
+        // use electrs' chain for the fields that may change (similar to bitcoind)
+        let chain = self.tracker.chain();
+        let height = chain.get_block_height(*blockhash);
+        let confirmations = height.map_or(-1, |height| (chain.height() + 1 - height) as isize);
+        block["confirmations"] = json!(confirmations);
+        if let Some(next) = height.and_then(|height| chain.get_block_hash(height + 1)) {
+            block["nextblockhash"] = json!(next);
+        }
+        Ok(block)
+    }
+
+    fn block_txids(&self, (blockhashes,): &(Vec<BlockHash>,)) -> Result<Value> {
+        Ok(json!(self.daemon.get_block_txids_batch(blockhashes)?))
+    }
+
+    fn estimate_fee(&self, client: &Client, (nblocks,): (u16,)) -> Result<Value> {
+        let unit = client.fee_rate_unit.unwrap_or(self.fee_rate_unit);
         Ok(self
             .daemon
             .estimate_fee(nblocks)?
-            .map(|fee_rate| json!(fee_rate.as_btc()))
+            .map(|fee_rate| json!(unit.convert(fee_rate)))
             .unwrap_or_else(|| json!(UNKNOWN_FEE)))
     }
 
-    fn relayfee(&self) -> Result<Value> {
-        Ok(json!(self.daemon.get_relay_fee()?.as_btc())) // [BTC/kB]
+    fn estimate_raw_fee(&self, args: &EstimateRawFeeArgs) -> Result<Value> {
+        let (nblocks, threshold) = args.into();
+        self.daemon.estimate_raw_fee(nblocks, threshold)
+    }
+
+    fn relayfee(&self, client: &Client) -> Result<Value> {
+        let unit = client.fee_rate_unit.unwrap_or(self.fee_rate_unit);
+        Ok(json!(unit.convert(self.daemon.get_relay_fee()?)))
+    }
+
+    fn fee_rate_unit(&self, client: &mut Client, (unit,): &(String,)) -> Result<Value> {
+        let unit: FeeRateUnit = unit.parse()?;
+        client.fee_rate_unit = Some(unit);
+        Ok(json!(unit.to_string()))
     }
 
     fn scripthash_get_balance(
@@ -250,35 +588,89 @@ impl Rpc {
         client: &Client,
         (scripthash,): &(ScriptHash,),
     ) -> Result<Value> {
-        let balance = match client.scripthashes.get(scripthash) {
-            Some(status) => self.tracker.get_balance(status),
+        if let Some(status) = client.scripthashes.get(scripthash) {
+            return Ok(json!(self.tracker.get_balance(status)));
+        }
+        if let Some(balance) = self.tracker.get_indexed_balance(scripthash)? {
+            return Ok(json!(balance)); // no need to sync the scripthash history
+        }
+        info!(
+            "{} blockchain.scripthash.get_balance called for unsubscribed scripthash: {}",
+            UNSUBSCRIBED_QUERY_MESSAGE, scripthash
+        );
+        let status = self.new_status(*scripthash)?;
+        Ok(json!(self.tracker.get_balance(&status)))
+    }
+
+    fn scripthash_first_height(&self, (scripthash,): &(ScriptHash,)) -> Result<Value> {
+        Ok(json!(self
+            .tracker
+            .get_scripthash_first_height(&self.daemon, *scripthash)?))
+    }
+
+    fn scripthash_get_history(
+        &self,
+        client: &Client,
+        (scripthash,): &(ScriptHash,),
+    ) -> Result<Value> {
+        let new_status;
+        let history = match client.scripthashes.get(scripthash) {
+            Some(status) => status.get_history(),
             None => {
                 info!(
-                    "{} blockchain.scripthash.get_balance called for unsubscribed scripthash: {}",
+                    "{} blockchain.scripthash.get_history called for unsubscribed scripthash: {}",
                     UNSUBSCRIBED_QUERY_MESSAGE, scripthash
                 );
-                self.tracker.get_balance(&self.new_status(*scripthash)?)
+                self.check_history_size(*scripthash)?;
+                new_status = self.new_status(*scripthash)?;
+                new_status.get_history()
             }
         };
-        Ok(json!(balance))
+        if let Some(max_txs) = self.max_history_txs {
+            ensure!(
+                history.len() <= max_txs,
+                "history too large: {} entries (max_history_txs = {})",
+                history.len(),
+                max_txs
+            );
+        }
+        Ok(json!(history))
     }
 
-    fn scripthash_get_history(
+    fn scripthash_get_history_page(
         &self,
         client: &Client,
-        (scripthash,): &(ScriptHash,),
+        (scripthash, cursor, limit): &(ScriptHash, Option<HistoryCursor>, usize),
     ) -> Result<Value> {
-        let history_entries = match client.scripthashes.get(scripthash) {
-            Some(status) => json!(status.get_history()),
+        ensure!(*limit > 0, "empty history page");
+        if let Some(max_txs) = self.max_history_txs {
+            ensure!(
+                *limit <= max_txs,
+                "history page too large: {} entries (max_history_txs = {})",
+                limit,
+                max_txs
+            );
+        }
+        let new_status;
+        let status = match client.scripthashes.get(scripthash) {
+            Some(status) => status,
             None => {
                 info!(
-                    "{} blockchain.scripthash.get_history called for unsubscribed scripthash: {}",
+                    "{} blockchain.scripthash.get_history_page called for unsubscribed scripthash: {}",
                     UNSUBSCRIBED_QUERY_MESSAGE, scripthash
                 );
-                json!(self.new_status(*scripthash)?.get_history())
+                self.check_history_size(*scripthash)?;
+                new_status = self.new_status(*scripthash)?;
+                &new_status
             }
         };
-        Ok(history_entries)
+        let (history, next) = status.get_history_page(*cursor, *limit);
+        let mut result = json!({"history": history, "next": next});
+        if next.is_none() {
+            // the last confirmed page is followed by the mempool entries
+            result["mempool"] = json!(status.get_unconfirmed_history());
+        }
+        Ok(result)
     }
 
     fn scripthash_list_unspent(
@@ -309,6 +701,37 @@ impl Rpc {
             .unwrap()
     }
 
+    fn scripthash_watch_mempool(
+        &self,
+        client: &mut Client,
+        (scripthashes,): &(Vec<ScriptHash>,),
+    ) -> Result<Value> {
+        let new_count = scripthashes
+            .iter()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .filter(|scripthash| !client.mempool_watched.contains(scripthash))
+            .count();
+        ensure!(
+            client.mempool_watched.len() + new_count <= MAX_MEMPOOL_WATCHED,
+            "too many watched scripthashes (max {})",
+            MAX_MEMPOOL_WATCHED
+        );
+        client.mempool_watched.extend(scripthashes);
+        Ok(json!(true))
+    }
+
+    fn scripthash_unwatch_mempool(
+        &self,
+        client: &mut Client,
+        (scripthashes,): &(Vec<ScriptHash>,),
+    ) -> Result<Value> {
+        for scripthash in scripthashes {
+            client.mempool_watched.remove(scripthash);
+        }
+        Ok(json!(true))
+    }
+
     fn scripthashes_subscribe<'a>(
         &self,
         client: &'a mut Client,
@@ -339,6 +762,14 @@ impl Rpc {
         })
     }
 
+    /// Fail (before syncing a new status) if the scripthash history exceeds `max_history_txs`.
+    fn check_history_size(&self, scripthash: ScriptHash) -> Result<()> {
+        match self.max_history_txs {
+            Some(max_txs) => self.tracker.check_funding_txs(scripthash, max_txs),
+            None => Ok(()),
+        }
+    }
+
     fn new_status(&self, scripthash: ScriptHash) -> Result<ScriptHashStatus> {
         let mut status = ScriptHashStatus::new(scripthash);
         self.tracker
@@ -349,19 +780,51 @@ impl Rpc {
     fn transaction_broadcast(&self, (tx_hex,): &(String,)) -> Result<Value> {
         let tx_bytes = Vec::from_hex(tx_hex).context("non-hex transaction")?;
         let tx = deserialize(&tx_bytes).context("invalid transaction")?;
-        let txid = self.daemon.broadcast(&tx)?;
+        let txid = self.daemon.broadcast_all(&tx)?;
         Ok(json!(txid))
     }
 
+    fn transaction_broadcast_checked(&self, (tx_hex,): &(String,)) -> Result<Value> {
+        let tx_bytes = Vec::from_hex(tx_hex).context("non-hex transaction")?;
+        let tx = deserialize(&tx_bytes).context("invalid transaction")?;
+        let txid = self.daemon.broadcast_checked(&tx)?;
+        Ok(json!(txid))
+    }
+
+    fn transaction_test_accept(&self, (tx_hex,): &(String,)) -> Result<Value> {
+        let tx_bytes = Vec::from_hex(tx_hex).context("non-hex transaction")?;
+        let tx: Transaction = deserialize(&tx_bytes).context("invalid transaction")?;
+        let wtxid = tx.wtxid();
+        let tip = self.tracker.chain().tip();
+        if let Some(result) = self.cache.get_mempool_accept(&wtxid, tip) {
+            return Ok(result);
+        }
+        let result = self.daemon.test_mempool_accept(&tx)?;
+        self.cache.add_mempool_accept(wtxid, tip, result.clone());
+        Ok(result)
+    }
+
     fn transaction_get(&self, args: &TxGetArgs) -> Result<Value> {
         let chain = self.tracker.chain();
-        let (txid, verbose) = args.into();
+        let (txid, verbose, prevouts) = args.into();
         if verbose {
+            if !prevouts {
+                if let Some(info) = self.cache.get_tx_info(&txid, chain) {
+                    return Ok(info);
+                }
+            }
             let blockhash = self
                 .tracker
                 .lookup_transaction(&self.daemon, chain, txid)?
                 .map(|(blockhash, _tx)| blockhash);
-            return self.daemon.get_transaction_info(&txid, blockhash);
+            if prevouts {
+                return self
+                    .daemon
+                    .get_transaction_info_with_prevouts(&txid, blockhash);
+            }
+            let info = self.daemon.get_transaction_info(&txid, blockhash)?;
+            self.cache.add_tx_info(txid, &info, chain);
+            return Ok(info);
         }
         if let Some(tx) = self.cache.get_tx(&txid, |tx| serialize(tx)) {
             return Ok(json!(tx.to_hex()));
@@ -379,6 +842,68 @@ impl Rpc {
         Ok(json!(self.daemon.get_transaction_hex(&txid, None)?))
     }
 
+    fn transaction_get_stripped(&self, (txid,): &(Txid,)) -> Result<Value> {
+        let chain = self.tracker.chain();
+        // use internal index to load confirmed transaction without an RPC
+        if let Some((_blockhash, tx)) =
+            self.tracker
+                .lookup_transaction(&self.daemon, chain, *txid)?
+        {
+            return Ok(json!(serialize_stripped(&tx).to_hex()));
+        }
+        self.daemon.get_transaction_stripped_hex(txid, None)
+    }
+
+    fn transaction_get_fee_rate(&self, client: &Client, (txid,): &(Txid,)) -> Result<Value> {
+        let unit = client.fee_rate_unit.unwrap_or(self.fee_rate_unit);
+        let chain = self.tracker.chain();
+        let blockhash = match self
+            .tracker
+            .lookup_transaction(&self.daemon, chain, *txid)?
+        {
+            Some((blockhash, _tx)) => blockhash,
+            None => bail!("transaction {} is not confirmed", txid),
+        };
+        let fee_rate = self.daemon.get_confirmed_tx_feerate(txid, blockhash)?;
+        Ok(json!(unit.convert(fee_rate)))
+    }
+
+    fn transaction_get_size_info(&self, (txid,): &(Txid,)) -> Result<Value> {
+        let chain = self.tracker.chain();
+        // use internal index to load confirmed transaction without an RPC
+        if let Some((_blockhash, tx)) =
+            self.tracker
+                .lookup_transaction(&self.daemon, chain, *txid)?
+        {
+            return Ok(json!(TxSizeInfo::new(&tx)));
+        }
+        Ok(json!(self.daemon.get_tx_size_info(txid, None)?))
+    }
+
+    fn transaction_get_blocks(&self, (txid, candidates): &(Txid, Vec<BlockHash>)) -> Result<Value> {
+        let chain = self.tracker.chain();
+        let mut candidates = candidates.clone();
+        if let Some((blockhash, _tx)) =
+            self.tracker
+                .lookup_transaction(&self.daemon, chain, *txid)?
+        {
+            if !candidates.contains(&blockhash) {
+                candidates.push(blockhash);
+            }
+        }
+        Ok(json!(self.daemon.get_transaction_blocks(
+            txid,
+            &candidates,
+            chain
+        )?))
+    }
+
+    fn transaction_get_confirmations(&self, (txids,): &(Vec<Txid>,)) -> Result<Value> {
+        Ok(json!(self
+            .tracker
+            .get_confirmations(&self.daemon, txids)?))
+    }
+
     fn transaction_get_merkle(&self, (txid, height): &(Txid, usize)) -> Result<Value> {
         let chain = self.tracker.chain();
         let blockhash = match chain.get_block_hash(*height) {
@@ -399,10 +924,136 @@ impl Rpc {
         }
     }
 
+    fn transaction_id_from_pos(&self, args: &TxIdFromPosArgs) -> Result<Value> {
+        let (height, tx_pos, merkle) = args.into();
+        let blockhash = match self.tracker.chain().get_block_hash(height) {
+            None => bail!("missing block at {}", height),
+            Some(blockhash) => blockhash,
+        };
+        if !merkle {
+            // avoid decoding the whole block
+            let tx = self.daemon.get_block_tx_at(blockhash, tx_pos)?;
+            return Ok(json!(tx.txid()));
+        }
+        let txids = self.daemon.get_block_txids(blockhash)?;
+        ensure!(
+            tx_pos < txids.len(),
+            "block {} has only {} transactions",
+            blockhash,
+            txids.len()
+        );
+        let proof = Proof::create(&txids, tx_pos);
+        Ok(json!({
+            "tx_hash": txids[tx_pos],
+            "merkle": proof.to_hex(),
+        }))
+    }
+
+    fn transaction_get_tx_out_proof(&self, (txid,): &(Txid,)) -> Result<Value> {
+        let chain = self.tracker.chain();
+        let blockhash = self
+            .tracker
+            .lookup_transaction(&self.daemon, chain, *txid)?
+            .map(|(blockhash, _tx)| blockhash);
+        let proof = self.daemon.get_tx_merkle_proof(txid, blockhash)?;
+        Ok(json!(proof.to_hex()))
+    }
+
+    fn transaction_verify_tx_out_proof(&self, (proof_hex,): &(String,)) -> Result<Value> {
+        let proof = Vec::from_hex(proof_hex).context("non-hex merkle proof")?;
+        Ok(json!(self.daemon.verify_tx_out_proof(&proof)?))
+    }
+
     fn get_fee_histogram(&self) -> Result<Value> {
         Ok(json!(self.tracker.fees_histogram()))
     }
 
+    fn mempool_get_entries(&self, (txids,): &(Vec<Txid>,)) -> Result<Value> {
+        let entries = self.daemon.get_mempool_entries(txids)?;
+        Ok(json!(txids
+            .iter()
+            .zip(entries)
+            .map(|(txid, entry)| {
+                entry.map(|entry| {
+                    json!({
+                        "txid": txid,
+                        "vsize": entry.vsize,
+                        "fee": entry.fees.base.as_sat(),
+                        "time": entry.time,
+                        "height": entry.height,
+                        "depends": entry.depends,
+                    })
+                })
+            })
+            .collect::<Vec<Option<Value>>>()))
+    }
+
+    fn mempool_next_block_fee_rate(&self, client: &Client) -> Result<Value> {
+        let unit = client.fee_rate_unit.unwrap_or(self.fee_rate_unit);
+        Ok(json!(unit.convert(self.daemon.next_block_feerate_floor()?)))
+    }
+
+    fn mempool_zeroconf(&self, client: &Client, (txid,): (Txid,)) -> Result<Value> {
+        let unit = client.fee_rate_unit.unwrap_or(self.fee_rate_unit);
+        let info = self.daemon.zeroconf_assessment(&txid, |outpoint| {
+            self.tracker.get_mempool_spenders(outpoint)
+        })?;
+        Ok(json!({
+            "txid": txid,
+            "bip125_replaceable": info.bip125_replaceable,
+            "fee_rate": unit.convert(info.fee_rate),
+            "next_block_fee_rate": unit.convert(info.fee_rate_floor),
+            "conflicts": info.conflicts,
+            "ancestor_count": info.ancestor_count,
+        }))
+    }
+
+    fn mempool_filter_txids(&self, (txids,): &(Vec<Txid>,)) -> Result<Value> {
+        let in_mempool = self.daemon.filter_in_mempool(txids)?;
+        let txids: Vec<&Txid> = txids
+            .iter()
+            .filter(|txid| in_mempool.contains(*txid))
+            .collect(); // keep the requested order
+        Ok(json!(txids))
+    }
+
+    fn mempool_orphaned_txids(&self) -> Result<Value> {
+        Ok(json!(self.tracker.get_orphaned_txids()))
+    }
+
+    fn mempool_spenders(&self, (txid, vout): (Txid, u32)) -> Result<Value> {
+        let outpoint = OutPoint::new(txid, vout);
+        Ok(json!(self.tracker.get_mempool_spenders(&outpoint)))
+    }
+
+    fn mempool_top_txids(&self, client: &Client, (min_fee_rate,): (f64,)) -> Result<Value> {
+        let unit = client.fee_rate_unit.unwrap_or(self.fee_rate_unit);
+        let min_fee_rate = unit.to_amount(min_fee_rate)?;
+        Ok(json!(self
+            .daemon
+            .get_mempool_txids_above_feerate(min_fee_rate)?))
+    }
+
+    /// Return true if bitcoind is out of IBD, electrs has indexed its best block,
+    /// and the initial mempool scan is over.
+    fn is_fully_synced(&self) -> bool {
+        let chain_synced = match self.tracker.status() {
+            Ok(()) => self.daemon.is_synced(Some(self.tracker.chain())),
+            Err(tracker::Error::NotReady) => false,
+            Err(tracker::Error::MempoolOnly) => self.daemon.is_synced(None), // blocks are not indexed
+        };
+        chain_synced && !self.tracker.is_mempool_loading()
+    }
+
+    fn sync_status(&self) -> Result<Value> {
+        let chain = self.tracker.chain();
+        Ok(json!({
+            "synced": self.is_fully_synced(),
+            "height": chain.height(),
+            "tip": chain.tip(),
+        }))
+    }
+
     fn server_id(&self) -> String {
         format!("electrs/{}", ELECTRS_VERSION)
     }
@@ -436,15 +1087,18 @@ impl Rpc {
     }
 
     pub fn handle_requests(&self, client: &mut Client, lines: &[String]) -> Vec<String> {
-        lines
-            .iter()
-            .map(|line| {
-                parse_requests(line)
-                    .map(Calls::parse)
-                    .map_err(error_msg_no_id)
-            })
-            .map(|calls| self.handle_calls(client, calls).to_string())
-            .collect()
+        let tag = client.tag.clone();
+        self.daemon.with_client_tag(tag, || {
+            lines
+                .iter()
+                .map(|line| {
+                    parse_requests(line)
+                        .map(Calls::parse)
+                        .map_err(error_msg_no_id)
+                })
+                .map(|calls| self.handle_calls(client, calls).to_string())
+                .collect()
+        })
     }
 
     fn handle_calls(&self, client: &mut Client, calls: Result<Calls, Value>) -> Value {
@@ -504,35 +1158,146 @@ impl Rpc {
             Err(response) => return response, // params parsing may fail - the response contains request id
         };
         self.rpc_duration.observe_duration(&call.method, || {
-            if self.tracker.status().is_err() {
-                // Allow only a few RPC (for sync status notification) not requiring index DB being compacted.
-                match &call.params {
-                    Params::BlockHeader(_)
-                    | Params::BlockHeaders(_)
-                    | Params::HeadersSubscribe
-                    | Params::Version(_) => (),
-                    _ => return error_msg(&call.id, RpcError::UnavailableIndex),
-                };
+            if call.params.is_admin() && !self.enable_admin_rpc {
+                warn!("{} called with disabled admin RPC", call.method);
+                return error_msg(&call.id, RpcError::Standard(StandardError::MethodNotFound));
+            }
+            let is_enabled = match call.params {
+                Params::EstimateRawFee(_) => self.enable_estimate_raw_fee,
+                Params::UtxoSetInfo => self.enable_utxo_set_info,
+                _ => true,
+            };
+            if !is_enabled {
+                warn!("{} called while disabled", call.method);
+                return error_msg(&call.id, RpcError::Standard(StandardError::MethodNotFound));
+            }
+            match self.tracker.status() {
+                Ok(()) => (),
+                Err(tracker::Error::NotReady) => {
+                    // Allow only a few RPC (for sync status notification) not requiring index DB being compacted.
+                    match &call.params {
+                        Params::BlockHeader(_)
+                        | Params::BlockHeaders(_)
+                        | Params::HeadersSubscribe
+                        | Params::SyncStatus
+                        | Params::Version(_) => (),
+                        _ => return error_msg(&call.id, RpcError::UnavailableIndex),
+                    };
+                }
+                Err(tracker::Error::MempoolOnly) => {
+                    // Allow only RPCs not requiring the index (see `doc/config.md`).
+                    match &call.params {
+                        Params::AddressValidate(_)
+                        | Params::Banner
+                        | Params::Donation
+                        | Params::EstimateFee(_)
+                        | Params::EstimateRawFee(_)
+                        | Params::FeeRateUnit(_)
+                        | Params::Features
+                        | Params::MempoolFeeHistogram
+                        | Params::MempoolFilterTxids(_)
+                        | Params::MempoolGetEntries(_)
+                        | Params::MempoolNextBlockFeeRate
+                        | Params::MempoolOrphanedTxids
+                        | Params::MempoolSpenders(_)
+                        | Params::MempoolTopTxids(_)
+                        | Params::MempoolZeroConf(_)
+                        | Params::PeersSubscribe
+                        | Params::Ping
+                        | Params::RelayFee
+                        | Params::ScriptHashUnwatchMempool(_)
+                        | Params::ScriptHashWatchMempool(_)
+                        | Params::SyncStatus
+                        | Params::TransactionBroadcast(_)
+                        | Params::TransactionBroadcastChecked(_)
+                        | Params::TransactionGet(_)
+                        | Params::TransactionGetSizeInfo(_)
+                        | Params::TransactionGetStripped(_)
+                        | Params::TransactionTestAccept(_)
+                        | Params::UtxoSetInfo
+                        | Params::Version(_) => (),
+                        _ => return error_msg(&call.id, RpcError::MempoolOnly),
+                    };
+                }
+            }
+            if call.params.is_mempool() && self.tracker.is_mempool_loading() {
+                return error_msg(&call.id, RpcError::MempoolLoading);
             }
             let result = match &call.params {
+                Params::AddressValidate(args) => self.address_validate(args),
+                Params::AdminBlockPositions(args) => self.admin_block_positions(args),
+                Params::AdminDumpBlock(args) => self.admin_dump_block(args),
+                Params::AdminRefreshCapabilities => self.admin_refresh_capabilities(),
+                Params::AdminReindexRange(args) => self.admin_reindex_range(args),
+                Params::AdminRpc(args) => self.admin_rpc(args),
+                Params::AdminVerifyBlocks(args) => self.admin_verify_blocks(args),
+                Params::AdminWaitForNewBlock(args) => self.admin_wait_for_new_block(*args),
                 Params::Banner => Ok(json!(self.banner)),
                 Params::BlockHeader(args) => self.block_header(*args),
                 Params::BlockHeaders(args) => self.block_headers(*args),
+                Params::BlockGetVerbose(args) => self.block_get_verbose(args),
+                Params::BlockIsActive(args) => self.block_is_active(args),
+                Params::BlockLocator(args) => self.block_locator(args),
+                Params::BlockCoinbaseMessage(args) => self.block_coinbase_message(args),
+                Params::BlockAtTime(args) => self.block_at_time(args),
+                Params::BlockFindByTime(args) => self.block_find_by_time(args),
+                Params::BlockIntervalStats(args) => self.block_interval_stats(args),
+                Params::BlockMtp(args) => self.block_mtp(args),
+                Params::BlockOutputTypes(args) => self.block_output_types(args),
+                Params::BlockPrevouts(args) => self.block_prevouts(args),
+                Params::BlockTime(args) => self.block_time(args),
+                Params::BlockTxids(args) => self.block_txids(args),
+                Params::BlockWeightSigops(args) => self.block_weight_sigops(args),
                 Params::Donation => Ok(Value::Null),
-                Params::EstimateFee(args) => self.estimate_fee(*args),
+                Params::EstimateFee(args) => self.estimate_fee(client, *args),
+                Params::EstimateRawFee(args) => self.estimate_raw_fee(args),
+                Params::FeeRateUnit(args) => self.fee_rate_unit(client, args),
                 Params::Features => self.features(),
                 Params::HeadersSubscribe => self.headers_subscribe(client),
                 Params::MempoolFeeHistogram => self.get_fee_histogram(),
+                Params::MempoolFilterTxids(args) => self.mempool_filter_txids(args),
+                Params::MempoolGetEntries(args) => self.mempool_get_entries(args),
+                Params::MempoolNextBlockFeeRate => self.mempool_next_block_fee_rate(client),
+                Params::MempoolOrphanedTxids => self.mempool_orphaned_txids(),
+                Params::MempoolSpenders(args) => self.mempool_spenders(*args),
+                Params::MempoolTopTxids(args) => self.mempool_top_txids(client, *args),
+                Params::MempoolZeroConf(args) => self.mempool_zeroconf(client, *args),
                 Params::PeersSubscribe => Ok(json!([])),
                 Params::Ping => Ok(Value::Null),
-                Params::RelayFee => self.relayfee(),
+                Params::RelayFee => self.relayfee(client),
+                Params::ScriptHashFirstHeight(args) => self.scripthash_first_height(args),
                 Params::ScriptHashGetBalance(args) => self.scripthash_get_balance(client, args),
                 Params::ScriptHashGetHistory(args) => self.scripthash_get_history(client, args),
+                Params::ScriptHashGetHistoryPage(args) => {
+                    self.scripthash_get_history_page(client, args)
+                }
                 Params::ScriptHashListUnspent(args) => self.scripthash_list_unspent(client, args),
                 Params::ScriptHashSubscribe(args) => self.scripthash_subscribe(client, args),
+                Params::ScriptHashUnwatchMempool(args) => {
+                    self.scripthash_unwatch_mempool(client, args)
+                }
+                Params::ScriptHashWatchMempool(args) => self.scripthash_watch_mempool(client, args),
+                Params::SyncStatus => self.sync_status(),
                 Params::TransactionBroadcast(args) => self.transaction_broadcast(args),
+                Params::TransactionBroadcastChecked(args) => {
+                    self.transaction_broadcast_checked(args)
+                }
                 Params::TransactionGet(args) => self.transaction_get(args),
+                Params::TransactionGetBlocks(args) => self.transaction_get_blocks(args),
+                Params::TransactionGetConfirmations(args) => {
+                    self.transaction_get_confirmations(args)
+                }
+                Params::TransactionGetFeeRate(args) => self.transaction_get_fee_rate(client, args),
                 Params::TransactionGetMerkle(args) => self.transaction_get_merkle(args),
+                Params::TransactionGetSizeInfo(args) => self.transaction_get_size_info(args),
+                Params::TransactionGetStripped(args) => self.transaction_get_stripped(args),
+                Params::TransactionIdFromPos(args) => self.transaction_id_from_pos(args),
+                Params::TransactionGetTxOutProof(args) => self.transaction_get_tx_out_proof(args),
+                Params::TransactionTestAccept(args) => self.transaction_test_accept(args),
+                Params::TransactionVerifyTxOutProof(args) => {
+                    self.transaction_verify_tx_out_proof(args)
+                }
+                Params::UtxoSetInfo => self.daemon.get_utxo_set_info(),
                 Params::Version(args) => self.version(args),
             };
             call.response(result)
@@ -542,48 +1307,160 @@ impl Rpc {
 
 #[derive(Deserialize)]
 enum Params {
+    AddressValidate((String,)),
+    AdminBlockPositions((usize, usize)),
+    AdminDumpBlock((BlockHash, usize)),
+    AdminRefreshCapabilities,
+    AdminReindexRange((usize, usize)),
+    AdminRpc((String, Vec<Value>)),
+    AdminVerifyBlocks((usize, usize)),
+    AdminWaitForNewBlock((u64,)),
     Banner,
     BlockHeader((usize,)),
     BlockHeaders((usize, usize)),
+    BlockGetVerbose((BlockHash, u8)),
+    BlockIsActive((BlockHash,)),
+    BlockLocator((BlockHash,)),
+    BlockCoinbaseMessage((BlockId,)),
+    BlockAtTime((u32,)),
+    BlockFindByTime((u32,)),
+    BlockIntervalStats((usize,)),
+    BlockMtp((BlockId,)),
+    BlockOutputTypes((BlockHash,)),
+    BlockPrevouts((BlockId,)),
+    BlockTime((BlockId,)),
+    BlockTxids((Vec<BlockHash>,)),
+    BlockWeightSigops((BlockId,)),
     TransactionBroadcast((String,)),
+    TransactionBroadcastChecked((String,)),
     Donation,
     EstimateFee((u16,)),
+    EstimateRawFee(EstimateRawFeeArgs),
+    FeeRateUnit((String,)),
     Features,
     HeadersSubscribe,
     MempoolFeeHistogram,
+    MempoolFilterTxids((Vec<Txid>,)),
+    MempoolGetEntries((Vec<Txid>,)),
+    MempoolNextBlockFeeRate,
+    MempoolOrphanedTxids,
+    MempoolSpenders((Txid, u32)),
+    MempoolTopTxids((f64,)),
+    MempoolZeroConf((Txid,)),
     PeersSubscribe,
     Ping,
     RelayFee,
+    ScriptHashFirstHeight((ScriptHash,)),
     ScriptHashGetBalance((ScriptHash,)),
     ScriptHashGetHistory((ScriptHash,)),
+    ScriptHashGetHistoryPage((ScriptHash, Option<HistoryCursor>, usize)),
     ScriptHashListUnspent((ScriptHash,)),
     ScriptHashSubscribe((ScriptHash,)),
+    ScriptHashUnwatchMempool((Vec<ScriptHash>,)),
+    ScriptHashWatchMempool((Vec<ScriptHash>,)),
+    SyncStatus,
     TransactionGet(TxGetArgs),
+    TransactionGetBlocks((Txid, Vec<BlockHash>)),
+    TransactionGetConfirmations((Vec<Txid>,)),
+    TransactionGetFeeRate((Txid,)),
     TransactionGetMerkle((Txid, usize)),
+    TransactionGetSizeInfo((Txid,)),
+    TransactionGetStripped((Txid,)),
+    TransactionIdFromPos(TxIdFromPosArgs),
+    TransactionTestAccept((String,)),
+    TransactionGetTxOutProof((Txid,)),
+    TransactionVerifyTxOutProof((String,)),
+    UtxoSetInfo,
     Version((String, Version)),
 }
 
 impl Params {
     fn parse(method: &str, params: Value) -> std::result::Result<Params, StandardError> {
         Ok(match method {
+            "admin.block_positions" => Params::AdminBlockPositions(convert(params)?),
+            "admin.dump_block" => Params::AdminDumpBlock(convert(params)?),
+            "admin.refresh_capabilities" => Params::AdminRefreshCapabilities,
+            "admin.reindex_range" => Params::AdminReindexRange(convert(params)?),
+            "admin.rpc" => Params::AdminRpc(convert(params)?),
+            "admin.verify_blocks" => Params::AdminVerifyBlocks(convert(params)?),
+            "admin.wait_for_new_block" => Params::AdminWaitForNewBlock(convert(params)?),
+            "blockchain.address.validate" => Params::AddressValidate(convert(params)?),
             "blockchain.block.header" => Params::BlockHeader(convert(params)?),
             "blockchain.block.headers" => Params::BlockHeaders(convert(params)?),
+            "blockchain.block.get_verbose" => Params::BlockGetVerbose(convert(params)?),
+            "blockchain.block.is_active" => Params::BlockIsActive(convert(params)?),
+            "blockchain.block.locator" => Params::BlockLocator(convert(params)?),
+            "blockchain.block.coinbase_message" => Params::BlockCoinbaseMessage(convert(params)?),
+            "blockchain.block.at_time" => Params::BlockAtTime(convert(params)?),
+            "blockchain.block.find_by_time" => Params::BlockFindByTime(convert(params)?),
+            "blockchain.block.interval_stats" => Params::BlockIntervalStats(convert(params)?),
+            "blockchain.block.mtp" => Params::BlockMtp(convert(params)?),
+            "blockchain.block.output_types" => Params::BlockOutputTypes(convert(params)?),
+            "blockchain.block.prevouts" => Params::BlockPrevouts(convert(params)?),
+            "blockchain.block.time" => Params::BlockTime(convert(params)?),
+            "blockchain.block.txids" => Params::BlockTxids(convert(params)?),
+            "blockchain.block.weight_sigops" => Params::BlockWeightSigops(convert(params)?),
             "blockchain.estimatefee" => Params::EstimateFee(convert(params)?),
+            "blockchain.estimaterawfee" => Params::EstimateRawFee(convert(params)?),
             "blockchain.headers.subscribe" => Params::HeadersSubscribe,
             "blockchain.relayfee" => Params::RelayFee,
+            "blockchain.scripthash.first_height" => Params::ScriptHashFirstHeight(convert(params)?),
             "blockchain.scripthash.get_balance" => Params::ScriptHashGetBalance(convert(params)?),
             "blockchain.scripthash.get_history" => Params::ScriptHashGetHistory(convert(params)?),
+            "blockchain.scripthash.get_history_page" => {
+                Params::ScriptHashGetHistoryPage(convert(params)?)
+            }
             "blockchain.scripthash.listunspent" => Params::ScriptHashListUnspent(convert(params)?),
             "blockchain.scripthash.subscribe" => Params::ScriptHashSubscribe(convert(params)?),
+            "blockchain.scripthash.unwatch_mempool" => {
+                Params::ScriptHashUnwatchMempool(convert(params)?)
+            }
+            "blockchain.scripthash.watch_mempool" => {
+                Params::ScriptHashWatchMempool(convert(params)?)
+            }
             "blockchain.transaction.broadcast" => Params::TransactionBroadcast(convert(params)?),
+            "blockchain.transaction.broadcast_checked" => {
+                Params::TransactionBroadcastChecked(convert(params)?)
+            }
             "blockchain.transaction.get" => Params::TransactionGet(convert(params)?),
+            "blockchain.transaction.get_blocks" => Params::TransactionGetBlocks(convert(params)?),
+            "blockchain.transaction.get_confirmations" => {
+                Params::TransactionGetConfirmations(convert(params)?)
+            }
+            "blockchain.transaction.get_fee_rate" => {
+                Params::TransactionGetFeeRate(convert(params)?)
+            }
             "blockchain.transaction.get_merkle" => Params::TransactionGetMerkle(convert(params)?),
+            "blockchain.transaction.get_size_info" => {
+                Params::TransactionGetSizeInfo(convert(params)?)
+            }
+            "blockchain.transaction.get_stripped" => {
+                Params::TransactionGetStripped(convert(params)?)
+            }
+            "blockchain.transaction.id_from_pos" => Params::TransactionIdFromPos(convert(params)?),
+            "blockchain.transaction.get_tx_out_proof" => {
+                Params::TransactionGetTxOutProof(convert(params)?)
+            }
+            "blockchain.transaction.test_accept" => Params::TransactionTestAccept(convert(params)?),
+            "blockchain.transaction.verify_tx_out_proof" => {
+                Params::TransactionVerifyTxOutProof(convert(params)?)
+            }
+            "blockchain.utxo_set_info" => Params::UtxoSetInfo,
+            "mempool.filter_txids" => Params::MempoolFilterTxids(convert(params)?),
             "mempool.get_fee_histogram" => Params::MempoolFeeHistogram,
+            "mempool.get_entries" => Params::MempoolGetEntries(convert(params)?),
+            "mempool.get_next_block_fee_rate" => Params::MempoolNextBlockFeeRate,
+            "mempool.get_orphaned_txids" => Params::MempoolOrphanedTxids,
+            "mempool.spenders" => Params::MempoolSpenders(convert(params)?),
+            "mempool.get_top_txids" => Params::MempoolTopTxids(convert(params)?),
+            "mempool.zeroconf" => Params::MempoolZeroConf(convert(params)?),
             "server.banner" => Params::Banner,
             "server.donation_address" => Params::Donation,
             "server.features" => Params::Features,
+            "server.fee_rate_unit" => Params::FeeRateUnit(convert(params)?),
             "server.peers.subscribe" => Params::PeersSubscribe,
             "server.ping" => Params::Ping,
+            "server.sync_status" => Params::SyncStatus,
             "server.version" => Params::Version(convert(params)?),
             _ => {
                 warn!("unknown method {}", method);
@@ -593,6 +1470,33 @@ impl Params {
     }
 }
 
+impl Params {
+    fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Params::AdminBlockPositions(_)
+                | Params::AdminDumpBlock(_)
+                | Params::AdminRefreshCapabilities
+                | Params::AdminReindexRange(_)
+                | Params::AdminRpc(_)
+                | Params::AdminVerifyBlocks(_)
+                | Params::AdminWaitForNewBlock(_)
+        )
+    }
+
+    fn is_mempool(&self) -> bool {
+        matches!(
+            self,
+            Params::MempoolFeeHistogram
+                | Params::MempoolNextBlockFeeRate
+                | Params::MempoolOrphanedTxids
+                | Params::MempoolSpenders(_)
+                | Params::MempoolTopTxids(_)
+                | Params::MempoolZeroConf(_)
+        )
+    }
+}
+
 struct Call {
     id: Value,
     method: String,
@@ -655,6 +1559,27 @@ where
     })
 }
 
+/// Format `bytes` like `hexdump -C` (16 bytes per line).
+fn hex_dump(offset: u32, bytes: &[u8]) -> Vec<String> {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+                .collect();
+            format!(
+                "{:08x}  {:<47}  |{}|",
+                u64::from(offset) + 16 * i as u64,
+                hex.join(" "),
+                ascii
+            )
+        })
+        .collect()
+}
+
 fn notification(method: &str, params: &[Value]) -> Value {
     json!({"jsonrpc": "2.0", "method": method, "params": params})
 }
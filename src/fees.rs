@@ -0,0 +1,135 @@
+use anyhow::{Error, Result};
+use bitcoin::Amount;
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Fee rate unit, used by `blockchain.estimatefee` and `blockchain.relayfee` responses.
+///
+/// bitcoind reports fee rates in BTC/kvB (having at most 8 decimal digits), so they are
+/// converted exactly into an integer number of sat/kvB - and then divided once by the unit's
+/// size (in kvB), without any intermediate rounding:
+/// - BTC/kvB: sat/kvB / 10^8 (the Electrum protocol default)
+/// - sat/vB: sat/kvB / 1000 (e.g. 1234 sat/kvB = 1.234 sat/vB)
+/// - sat/kwu: sat/kvB / 4 (since 1 vB = 4 weight units)
+///
+/// The result is not rounded, so clients should round it up (if needed) to avoid underpaying.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeeRateUnit {
+    BtcPerKvB,
+    SatPerVB,
+    SatPerKwu,
+}
+
+impl FeeRateUnit {
+    /// Convert a fee rate (given as an amount per 1000 vbytes) into this unit.
+    pub(crate) fn convert(&self, fee_rate: Amount) -> f64 {
+        let sat_per_kvb = fee_rate.as_sat() as f64;
+        match self {
+            FeeRateUnit::BtcPerKvB => fee_rate.as_btc(),
+            FeeRateUnit::SatPerVB => sat_per_kvb / 1000.0,
+            FeeRateUnit::SatPerKwu => sat_per_kvb / 4.0,
+        }
+    }
+
+    /// Convert a fee rate given in this unit into an amount per 1000 vbytes (rounded to the
+    /// nearest satoshi).
+    pub(crate) fn to_amount(&self, fee_rate: f64) -> Result<Amount> {
+        ensure!(
+            fee_rate.is_finite() && fee_rate >= 0.0,
+            "invalid fee rate: {}",
+            fee_rate
+        );
+        let sat_per_kvb = match self {
+            FeeRateUnit::BtcPerKvB => fee_rate * 100_000_000.0,
+            FeeRateUnit::SatPerVB => fee_rate * 1000.0,
+            FeeRateUnit::SatPerKwu => fee_rate * 4.0,
+        };
+        Ok(Amount::from_sat(sat_per_kvb.round() as u64))
+    }
+}
+
+impl Default for FeeRateUnit {
+    fn default() -> Self {
+        FeeRateUnit::BtcPerKvB
+    }
+}
+
+impl FromStr for FeeRateUnit {
+    type Err = Error;
+
+    fn from_str(unit: &str) -> Result<Self> {
+        match unit.to_ascii_lowercase().as_str() {
+            "btc/kvb" => Ok(FeeRateUnit::BtcPerKvB),
+            "sat/vb" => Ok(FeeRateUnit::SatPerVB),
+            "sat/kwu" => Ok(FeeRateUnit::SatPerKwu),
+            _ => bail!("unknown fee rate unit: {:?}", unit),
+        }
+    }
+}
+
+impl fmt::Display for FeeRateUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = match self {
+            FeeRateUnit::BtcPerKvB => "btc/kvb",
+            FeeRateUnit::SatPerVB => "sat/vb",
+            FeeRateUnit::SatPerKwu => "sat/kwu",
+        };
+        write!(f, "{}", unit)
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for FeeRateUnit {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "either 'btc/kvb', 'sat/vb' or 'sat/kwu'")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FeeRateUnit;
+    use bitcoin::Amount;
+
+    #[test]
+    fn test_convert() {
+        let fee_rate = Amount::from_sat(1234); // per kvB
+        let convert = |unit: FeeRateUnit| unit.convert(fee_rate).to_string();
+        assert_eq!(convert(FeeRateUnit::BtcPerKvB), "0.00001234");
+        assert_eq!(convert(FeeRateUnit::SatPerVB), "1.234");
+        assert_eq!(convert(FeeRateUnit::SatPerKwu), "308.5");
+    }
+
+    #[test]
+    fn test_to_amount() {
+        let fee_rate = Amount::from_sat(1234); // per kvB
+        for unit in &[
+            FeeRateUnit::BtcPerKvB,
+            FeeRateUnit::SatPerVB,
+            FeeRateUnit::SatPerKwu,
+        ] {
+            assert_eq!(unit.to_amount(unit.convert(fee_rate)).unwrap(), fee_rate);
+        }
+        assert_eq!(
+            FeeRateUnit::SatPerVB.to_amount(0.0006).unwrap(),
+            Amount::from_sat(1) // rounded
+        );
+        assert!(FeeRateUnit::SatPerVB.to_amount(-1.0).is_err());
+        assert!(FeeRateUnit::SatPerVB.to_amount(f64::NAN).is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        for unit in &[
+            FeeRateUnit::BtcPerKvB,
+            FeeRateUnit::SatPerVB,
+            FeeRateUnit::SatPerKwu,
+        ] {
+            assert_eq!(unit.to_string().parse::<FeeRateUnit>().unwrap(), *unit);
+        }
+        assert_eq!(
+            "sat/vB".parse::<FeeRateUnit>().unwrap(),
+            FeeRateUnit::SatPerVB
+        );
+        assert!("sat/byte".parse::<FeeRateUnit>().is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use bitcoin::hashes::Hash;
+
+use std::convert::TryInto;
+
+use crate::{db, types::ScriptHash};
+
+const MAX_HASHES: usize = 16;
+
+/// Bloom filter over a block's funded scripthashes, for skipping blocks which definitely don't
+/// contain a given scripthash. A positive match may be false, so it must be verified by reading
+/// the block's transactions.
+#[derive(Debug, PartialEq)]
+pub(crate) struct BlockFilter {
+    hashes: u8, // number of bits set per scripthash
+    bits: Box<[u8]>,
+}
+
+impl BlockFilter {
+    /// `size` is the (positive) filter size in bytes, bounding its false-positive rate.
+    pub(crate) fn new(scripthashes: &[ScriptHash], size: usize) -> Self {
+        let nbits = size * 8;
+        // minimizes the false-positive rate for the given number of items
+        let hashes = match scripthashes.len() {
+            0 => 1,
+            n => ((nbits as f64 / n as f64) * std::f64::consts::LN_2).round() as usize,
+        };
+        let mut filter = Self {
+            hashes: hashes.max(1).min(MAX_HASHES) as u8,
+            bits: vec![0; size].into_boxed_slice(),
+        };
+        for scripthash in scripthashes {
+            for bit in filter.bit_indices(scripthash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    pub(crate) fn contains(&self, scripthash: &ScriptHash) -> bool {
+        self.bit_indices(scripthash)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub(crate) fn to_db_value(&self) -> db::Row {
+        let mut value = Vec::with_capacity(1 + self.bits.len());
+        value.push(self.hashes);
+        value.extend_from_slice(&self.bits);
+        value.into_boxed_slice()
+    }
+
+    pub(crate) fn from_db_value(value: &[u8]) -> Self {
+        let (hashes, bits) = value.split_first().expect("bad BlockFilter");
+        Self {
+            hashes: *hashes,
+            bits: bits.into(),
+        }
+    }
+
+    // Double hashing: skip the first 8 bytes of the scripthash, since they are used as the
+    // funding rows' prefix (so prefix collisions are likely to be filtered out).
+    fn bit_indices(&self, scripthash: &ScriptHash) -> impl Iterator<Item = usize> {
+        let bytes = scripthash.into_inner();
+        let h1 = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        let h2 = u64::from_le_bytes(bytes[16..24].try_into().unwrap()) | 1;
+        let nbits = (self.bits.len() * 8) as u64;
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockFilter;
+    use crate::types::ScriptHash;
+    use bitcoin::Script;
+
+    #[test]
+    fn test_block_filter() {
+        let scripthash = |n: u32| ScriptHash::new(&Script::from(n.to_le_bytes().to_vec()));
+        let funded: Vec<ScriptHash> = (0..1000).map(scripthash).collect();
+        let filter = BlockFilter::new(&funded, 2048);
+        assert!(funded.iter().all(|s| filter.contains(s)));
+
+        let false_positives = (1000..11000)
+            .filter(|n| filter.contains(&scripthash(*n)))
+            .count();
+        assert!(false_positives < 50, "{} false positives", false_positives); // ~0.04% expected
+
+        let value = filter.to_db_value();
+        assert_eq!(value.len(), 2049);
+        assert_eq!(BlockFilter::from_db_value(&value), filter);
+
+        let empty = BlockFilter::new(&[], 16);
+        assert!(!empty.contains(&funded[0]));
+    }
+}
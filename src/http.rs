@@ -0,0 +1,63 @@
+use anyhow::{Context, Result};
+
+use std::io::{BufRead, BufReader, Read};
+
+/// Read an HTTP/1.1 response (sent over a `Connection: close` stream), returning its status code
+/// and body.
+pub(crate) fn read_response(stream: impl Read) -> Result<(u16, Vec<u8>)> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status: u16 = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .with_context(|| format!("invalid HTTP status line: {:?}", line))?;
+    let mut content_length = None;
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        if name.eq_ignore_ascii_case("content-length") {
+            let value = parts.next().unwrap_or("").trim();
+            content_length = Some(value.parse::<u64>()?);
+        }
+    }
+    let mut body = vec![];
+    match content_length {
+        Some(len) => reader.take(len).read_to_end(&mut body)?,
+        None => reader.read_to_end(&mut body)?,
+    };
+    Ok((status, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_response;
+
+    #[test]
+    fn test_read_response() {
+        let response = b"HTTP/1.1 200 OK\r\ncontent-length: 3\r\n\r\nabcdef";
+        assert_eq!(
+            read_response(&response[..]).unwrap(),
+            (200, b"abc".to_vec())
+        );
+
+        let response = b"HTTP/1.1 404 Not Found\r\nServer: test\r\n\r\nnot found";
+        assert_eq!(
+            read_response(&response[..]).unwrap(),
+            (404, b"not found".to_vec())
+        );
+
+        let err = read_response(&b"garbage\r\n\r\n"[..]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid HTTP status line: \"garbage\\r\\n\""
+        );
+    }
+}
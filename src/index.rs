@@ -1,21 +1,29 @@
 use anyhow::{Context, Result};
 use bitcoin::{
     consensus::{deserialize, serialize, Decodable},
-    BlockHeader, OutPoint, Transaction, Txid, VarInt,
+    Amount, BlockHash, BlockHeader, OutPoint, Transaction, TxOut, Txid, VarInt,
 };
+use crossbeam_channel::{bounded, Sender};
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
 
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::{
     chain::Chain,
-    daemon::{BlockHashPosition, Daemon},
+    daemon::{BlockFile, BlockHashPosition, Daemon},
     db::{DBStore, Row, WriteBatch},
+    filter::BlockFilter,
     metrics::{self, Gauge, Histogram, Metrics},
     signals::ExitFlag,
+    thread::{set_cpu_affinity, spawn},
+    throttle::{BlockReadThrottle, RateLimiter},
     types::{
-        FilePosition, HashPrefixRow, HeaderRow, ScriptHash, ScriptHashRow, SpendingPrefixRow,
-        TxidRow,
+        BalanceRow, FilePosition, HashPrefixRow, HeaderRow, ScriptHash, ScriptHashRow,
+        SpendingPrefixRow, TxidRow,
     },
 };
 
@@ -25,6 +33,8 @@ struct Stats {
     update_size: Histogram,
     height: Gauge,
     db_properties: Gauge,
+    read_rate: Gauge,
+    free_disk: Gauge,
 }
 
 impl Stats {
@@ -44,6 +54,16 @@ impl Stats {
             ),
             height: metrics.gauge("index_height", "Indexed block height", "type"),
             db_properties: metrics.gauge("index_db_properties", "Index DB properties", "name"),
+            read_rate: metrics.gauge(
+                "index_block_read_rate",
+                "Effective block read rate during sync (per second)",
+                "unit",
+            ),
+            free_disk: metrics.gauge(
+                "index_db_free_disk",
+                "Free disk space on the DB volume (in bytes)",
+                "type",
+            ),
         }
     }
 
@@ -69,6 +89,14 @@ impl Stats {
         );
     }
 
+    fn observe_read_rate(&self, blocks: usize, bytes: u64, start: Instant) {
+        let elapsed = start.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.read_rate.set("blocks", blocks as f64 / elapsed);
+            self.read_rate.set("bytes", bytes as f64 / elapsed);
+        }
+    }
+
     fn observe_chain(&self, chain: &Chain) {
         self.height.set("tip", chain.height() as f64);
     }
@@ -86,6 +114,8 @@ struct IndexResult {
     funding_rows: Vec<HashPrefixRow>,
     spending_rows: Vec<HashPrefixRow>,
     txid_rows: Vec<HashPrefixRow>,
+    filter: Option<BlockFilter>,
+    balances: Option<BalanceDeltas>,
 }
 
 impl IndexResult {
@@ -99,19 +129,94 @@ impl IndexResult {
         let txid_rows = self.txid_rows.iter().map(HashPrefixRow::to_db_row);
         batch.txid_rows.extend(txid_rows);
 
+        if let Some(filter) = &self.filter {
+            let key = serialize(&self.header_row.hash).into_boxed_slice();
+            batch.filter_rows.push((key, filter.to_db_value()));
+        }
+
         batch.header_rows.push(self.header_row.to_db_row());
         batch.tip_row = serialize(&self.header_row.header.block_hash()).into_boxed_slice();
     }
 }
 
+/// Changes of scripthashes' balances (in satoshis), due to connecting (or disconnecting) blocks
+#[derive(Default)]
+pub(crate) struct BalanceDeltas(HashMap<ScriptHash, i64>);
+
+impl BalanceDeltas {
+    /// Add the transaction's outputs, and subtract the outputs spent by its inputs (`prevouts`).
+    pub(crate) fn add_tx(
+        &mut self,
+        tx: &Transaction,
+        prevouts: &mut impl Iterator<Item = TxOut>,
+    ) -> Result<()> {
+        for txo in &tx.output {
+            // like funding rows, provably unspendable outputs are not indexed
+            if !txo.script_pubkey.is_provably_unspendable() {
+                self.add(txo, 1);
+            }
+        }
+        if tx.is_coin_base() {
+            return Ok(()); // coinbase doesn't have inputs
+        }
+        for txin in &tx.input {
+            let prevout = prevouts
+                .next()
+                .with_context(|| format!("missing prevout {}", txin.previous_output))?;
+            self.add(&prevout, -1);
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, txo: &TxOut, sign: i64) {
+        let value = i64::try_from(txo.value).expect("invalid output value");
+        let scripthash = ScriptHash::new(&txo.script_pubkey);
+        *self.0.entry(scripthash).or_default() += sign * value;
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&ScriptHash, i64)> {
+        self.0
+            .iter()
+            .map(|(scripthash, delta)| (scripthash, *delta))
+    }
+
+    /// Add (or subtract, if `sign` is negative) other balance changes.
+    fn merge(&mut self, other: BalanceDeltas, sign: i64) {
+        for (scripthash, delta) in other.0 {
+            *self.0.entry(scripthash).or_default() += sign * delta;
+        }
+    }
+
+    fn extend(self, batch: &mut WriteBatch) {
+        let rows = self
+            .0
+            .into_iter()
+            .filter(|(_, delta)| *delta != 0)
+            .map(|(scripthash, delta)| (BalanceRow::key(scripthash), delta));
+        batch.balance_rows.extend(rows);
+    }
+}
+
+// Polling interval while indexing is paused due to low disk space
+const FREE_DISK_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// Maximum number of blocks re-indexed by `reindex_range` (bounding the memory used by their rows)
+const MAX_REINDEX_BLOCKS: usize = 1000;
+
 /// Confirmed transactions' address index
 pub struct Index {
-    store: DBStore,
+    store: Arc<DBStore>, // shared with the writer thread during sync
     batch_size: usize,
     lookup_limit: Option<usize>,
     chain: Chain,
     stats: Stats,
     is_ready: bool,
+    read_limiter: Option<RateLimiter>,
+    min_free_disk_bytes: Option<u64>,
+    filter_size: Option<usize>,
+    pool: Option<ThreadPool>, // `None` if blocks are indexed inline
+    balances: bool,
+    dropped: Vec<BlockHash>, // blocks to be rolled back from the balances (see `reindex_last_blocks`)
 }
 
 impl Index {
@@ -122,7 +227,14 @@ impl Index {
         batch_size: usize,
         lookup_limit: Option<usize>,
         reindex_last_blocks: usize,
+        block_read_throttle: Option<BlockReadThrottle>,
+        min_free_disk_bytes: Option<u64>,
+        filter_size: Option<usize>,
+        parallelism: usize,
+        cpu_cores: &[usize],
+        balances: bool,
     ) -> Result<Self> {
+        let mut dropped = vec![];
         if let Some(row) = store.get_tip() {
             let tip = deserialize(&row).expect("invalid tip");
             let rows = store.read_headers();
@@ -130,18 +242,54 @@ impl Index {
                 rows.iter().map(|r| HeaderRow::from_db_row(r)).collect(),
                 tip,
             );
+            if balances {
+                let new_height = chain.height().saturating_sub(reindex_last_blocks);
+                dropped = (new_height + 1..=chain.height())
+                    .map(|height| chain.get_block_hash(height).expect("missing block hash"))
+                    .collect();
+            }
             chain.drop_last_headers(reindex_last_blocks);
         };
         let stats = Stats::new(metrics);
         stats.observe_chain(&chain);
         stats.observe_db(&store);
+        let pool = match parallelism {
+            1 => None,
+            n => {
+                let cores = cpu_cores.to_vec();
+                // by default, use a thread per pinned core (or all CPUs, if not pinned)
+                let n = if n == 0 { cores.len() } else { n };
+                Some(
+                    ThreadPoolBuilder::new()
+                        .num_threads(n) // 0 - use all CPUs
+                        .thread_name(|i| format!("index-{}", i))
+                        .start_handler(move |i| {
+                            if cores.is_empty() {
+                                return;
+                            }
+                            // indexing still works (without pinning), so don't fail it
+                            if let Err(e) = set_cpu_affinity(&cores) {
+                                warn!("failed to pin index-{} to CPU cores {:?}: {}", i, cores, e);
+                            }
+                        })
+                        .build()
+                        .context("failed to create indexing thread pool")?,
+                )
+            }
+        };
         Ok(Index {
-            store,
+            store: Arc::new(store),
             batch_size,
             lookup_limit,
             chain,
             stats,
             is_ready: false,
+            read_limiter: block_read_throttle.map(RateLimiter::new),
+            min_free_disk_bytes,
+            filter_size,
+            pool,
+            balances,
+            dropped,
         })
     }
 
@@ -161,6 +309,30 @@ impl Index {
         Ok(result)
     }
 
+    /// Return false if the block definitely doesn't fund `scripthash` (according to its filter).
+    /// Blocks indexed without a filter may fund any scripthash, and a positive result must still
+    /// be verified by reading the block's transactions.
+    pub(crate) fn block_may_contain(&self, blockhash: BlockHash, scripthash: ScriptHash) -> bool {
+        if self.filter_size.is_none() {
+            return true; // skip DB lookups if block filters are disabled
+        }
+        match self.store.get_filter(&serialize(&blockhash)) {
+            Some(value) => BlockFilter::from_db_value(&value).contains(&scripthash),
+            None => true,
+        }
+    }
+
+    /// Get the scripthash's confirmed balance, if balances are indexed.
+    pub(crate) fn get_balance(&self, scripthash: ScriptHash) -> Result<Option<Amount>> {
+        if !self.balances {
+            return Ok(None);
+        }
+        let balance = self.store.get_balance(&BalanceRow::key(scripthash));
+        let balance = u64::try_from(balance)
+            .with_context(|| format!("invalid {} balance: {}", scripthash, balance))?;
+        Ok(Some(Amount::from_sat(balance)))
+    }
+
     // Note: the `filter_by_*` methods may return transactions from stale blocks..
 
     pub(crate) fn filter_by_txid(&self, txid: Txid) -> impl Iterator<Item = FilePosition> + '_ {
@@ -178,6 +350,22 @@ impl Index {
             .map(position_from_row)
     }
 
+    /// Fail if `scripthash` is funded by more than `max_txs` transactions, using only the funding
+    /// rows (so large histories are rejected before their transactions are read).
+    pub(crate) fn check_funding_txs(&self, scripthash: ScriptHash, max_txs: usize) -> Result<()> {
+        let mut positions = HashSet::new();
+        for pos in self.filter_by_funding(scripthash) {
+            positions.insert(pos);
+            ensure!(
+                positions.len() <= max_txs,
+                "history too large: >{} funding transactions (max_history_txs = {})",
+                max_txs,
+                max_txs
+            );
+        }
+        Ok(())
+    }
+
     pub(crate) fn filter_by_spending(
         &self,
         outpoint: OutPoint,
@@ -200,45 +388,241 @@ impl Index {
         }
         let count = new_headers.len();
         info!("indexing {} blocks", count);
-        let mut header_rows = Vec::with_capacity(new_headers.len());
-        for chunk in new_headers.chunks(self.batch_size) {
+        let mut blocks = daemon.open_blocks(new_headers.iter().map(|h| h.pos).collect());
+        // disconnected blocks' balances are rolled back by the first written batch
+        let mut rollback = if self.balances {
+            Some(self.rollback_balances(daemon, &new_headers[0])?)
+        } else {
+            None
+        };
+
+        // Write each batch while the next one is being indexed. The channel is bounded, so at most
+        // 3 batches are kept in memory (being written, pending and being indexed).
+        let (batch_tx, batch_rx) = bounded::<WriteBatch>(1);
+        let writer = {
+            let store = Arc::clone(&self.store);
+            let stats = self.stats.clone();
+            spawn("index_writer", move || {
+                for batch in batch_rx {
+                    stats.observe_duration("write", || store.write(&batch));
+                    stats.observe_db(&store);
+                }
+                Ok(())
+            })
+        };
+        let mut header_rows = Vec::with_capacity(count);
+        let result = self.index_chunks(
+            daemon,
+            &mut blocks,
+            &new_headers,
+            &mut rollback,
+            &mut header_rows,
+            &batch_tx,
+            exit_flag,
+        );
+        drop(batch_tx); // wait for the pending batches to be written
+        if let Err(panic) = writer.join() {
+            std::panic::resume_unwind(panic); // e.g. a failed DB write
+        }
+        if !header_rows.is_empty() {
+            self.dropped.clear(); // rolled back by the first batch
+        }
+        // keep the chain consistent with the written blocks (even if indexing has failed)
+        self.chain.update(header_rows);
+        result?;
+        self.stats.height.set("tip", self.chain.height() as f64);
+        self.stats.observe_chain(&self.chain);
+        daemon.verify_blocks(&[self.chain.tip()])?; // sanity check
+        Ok(false) // sync is not done
+    }
+
+    /// Get the balance changes rolling back the blocks which were disconnected by a reorg (before
+    /// `first` new block), or dropped on startup - by reading them again with their undo data.
+    fn rollback_balances(
+        &self,
+        daemon: &Daemon,
+        first: &BlockHashPosition,
+    ) -> Result<BalanceDeltas> {
+        let header = BlockHeader::consensus_decode(&mut daemon.open_file(first.pos)?)?;
+        let mut blockhashes = self.dropped.clone();
+        if let Some(fork_height) = self.chain.get_block_height(header.prev_blockhash) {
+            blockhashes.extend((fork_height + 1..=self.chain.height()).map(|height| {
+                self.chain
+                    .get_block_hash(height)
+                    .expect("missing block hash")
+            }));
+        }
+        if !blockhashes.is_empty() {
+            info!("rolling back balances of {} blocks", blockhashes.len());
+        }
+        let mut rollback = BalanceDeltas::default();
+        for blockhash in blockhashes {
+            let (block, _pos) = daemon.read_block(blockhash)?;
+            let mut prevouts = daemon.read_block_undo(blockhash)?.into_iter();
+            let mut deltas = BalanceDeltas::default();
+            for tx in &block.txdata {
+                deltas.add_tx(tx, &mut prevouts)?;
+            }
+            rollback.merge(deltas, -1);
+        }
+        Ok(rollback)
+    }
+
+    fn index_chunks(
+        &mut self,
+        daemon: &Daemon,
+        blocks: &mut impl Iterator<Item = Result<Box<dyn BlockFile>>>,
+        new_headers: &[BlockHashPosition],
+        rollback: &mut Option<BalanceDeltas>,
+        header_rows: &mut Vec<HeaderRow>,
+        batch_tx: &Sender<WriteBatch>,
+        exit_flag: &ExitFlag,
+    ) -> Result<()> {
+        for chunk in new_headers.chunks(self.store.batch_size(self.batch_size)) {
             exit_flag.poll().with_context(|| {
                 format!(
                     "indexing interrupted at block: {}",
                     chunk.first().unwrap().hash
                 )
             })?;
-            header_rows.extend(self.sync_blocks(daemon, chunk)?);
+            self.wait_for_free_disk(exit_flag)?;
+            let balances = rollback.take().unwrap_or_default();
+            let (rows, batch) = self.sync_blocks(daemon, blocks, chunk, balances)?;
+            if batch_tx.send(batch).is_err() {
+                bail!("index writer thread has stopped"); // its panic is resumed by the caller
+            }
+            header_rows.extend(rows);
         }
-        self.chain.update(header_rows);
-        self.stats.height.set("tip", self.chain.height() as f64);
-        self.stats.observe_chain(&self.chain);
-        daemon.verify_blocks(&[self.chain.tip()])?; // sanity check
-        Ok(false) // sync is not done
+        Ok(())
     }
 
+    /// Re-index the blocks at the given heights range (excluding its end), e.g. after fixing an
+    /// indexing bug: their funding, spending and txid rows (found by the blocks' own keys) are
+    /// deleted and derived again from the block files, while the rest of the index is untouched.
+    /// Returns the number of deleted rows. Not supported if balances are indexed, since their
+    /// changes by the stale rows are unknown.
+    pub(crate) fn reindex_range(&self, daemon: &Daemon, start: usize, end: usize) -> Result<usize> {
+        ensure!(
+            !self.balances,
+            "re-indexing a range is not supported when balances are indexed"
+        );
+        let rows = range_header_rows(&self.chain, start, end)?;
+        info!("re-indexing {} blocks at [{}, {})", rows.len(), start, end);
+        let mut blocks = daemon.open_blocks(rows.iter().map(|row| row.pos).collect());
+        let batch_size = self.store.batch_size(self.batch_size);
+        let deleted = reindex_blocks(
+            &self.store,
+            &self.chain,
+            &rows,
+            &mut blocks,
+            self.filter_size,
+            batch_size,
+        )?;
+        info!(
+            "re-indexed {} blocks ({} rows deleted)",
+            rows.len(),
+            deleted
+        );
+        Ok(deleted)
+    }
+
+    /// Pause indexing while the DB volume has less than `min_free_disk_bytes` available,
+    /// so a full disk doesn't fail (or corrupt) DB writes.
+    fn wait_for_free_disk(&self, exit_flag: &ExitFlag) -> Result<()> {
+        let min_free = match self.min_free_disk_bytes {
+            Some(min_free) => min_free,
+            None => return Ok(()),
+        };
+        loop {
+            let free = self.store.free_disk_space()?;
+            self.stats.free_disk.set("available", free as f64);
+            if free >= min_free {
+                return Ok(());
+            }
+            warn!(
+                "indexing paused: {:.3} GB free on DB volume, at least {:.3} GB required",
+                free as f64 / 1e9,
+                min_free as f64 / 1e9
+            );
+            exit_flag
+                .poll()
+                .context("indexing interrupted while waiting for free disk space")?;
+            std::thread::sleep(FREE_DISK_POLL_INTERVAL);
+        }
+    }
+
+    /// `balances` are the initial balance changes (e.g. rolling back disconnected blocks).
     fn sync_blocks(
         &mut self,
         daemon: &Daemon,
+        blocks: &mut impl Iterator<Item = Result<Box<dyn BlockFile>>>,
         chunk: &[BlockHashPosition],
-    ) -> Result<Vec<HeaderRow>> {
+        mut balances: BalanceDeltas,
+    ) -> Result<(Vec<HeaderRow>, WriteBatch)> {
         let mut batch = WriteBatch::default();
         let mut header_rows = Vec::with_capacity(chunk.len());
-        for h in chunk {
-            self.stats.observe_duration("block", || -> Result<()> {
-                let file = daemon.open_file(h.pos)?;
-                let result = index_single_block(h.pos, file)?;
-                result.extend(&mut batch); // FIXME
-                header_rows.push(result.header_row);
-                Ok(())
-            })?;
+        let (start, mut bytes) = (Instant::now(), 0);
+        let stats = &self.stats;
+        let filter_size = self.filter_size;
+        // spent outputs are needed for updating the balances
+        let prevouts: Vec<Option<Vec<TxOut>>> = if self.balances {
+            stats.observe_duration("undo", || {
+                chunk
+                    .par_iter()
+                    .map(|h| daemon.read_block_undo(h.hash).map(Some))
+                    .collect::<Result<_>>()
+            })?
+        } else {
+            vec![None; chunk.len()]
+        };
+        let results: Box<dyn Iterator<Item = Result<IndexResult>> + '_> = match &self.pool {
+            Some(pool) => {
+                let files = chunk
+                    .iter()
+                    .map(|_| next_block(blocks))
+                    .collect::<Result<Vec<_>>>()?;
+                // scripthashes and index rows are computed in parallel (preserving blocks' order)
+                let results: Vec<Result<IndexResult>> = stats.observe_duration("blocks", || {
+                    pool.install(|| {
+                        files
+                            .into_par_iter()
+                            .zip(chunk)
+                            .zip(prevouts)
+                            .map(|((file, h), prevouts)| {
+                                index_single_block(h.pos, file, filter_size, prevouts)
+                            })
+                            .collect()
+                    })
+                });
+                Box::new(results.into_iter())
+            }
+            None => Box::new(chunk.iter().zip(prevouts).map(move |(h, prevouts)| {
+                stats.observe_duration("block", || -> Result<IndexResult> {
+                    index_single_block(h.pos, next_block(blocks)?, filter_size, prevouts)
+                })
+            })),
+        };
+        for result in results {
+            let mut result = result?;
+            if let Some(deltas) = result.balances.take() {
+                balances.merge(deltas, 1);
+            }
+            result.extend(&mut batch); // FIXME
+            let size = result.header_row.size;
+            header_rows.push(result.header_row);
+            bytes += u64::from(size);
+            if let Some(limiter) = &mut self.read_limiter {
+                self.stats
+                    .observe_duration("throttle", || limiter.wait(u64::from(size)));
+            }
+        }
+        self.stats.observe_read_rate(chunk.len(), bytes, start);
+        if self.balances {
+            balances.extend(&mut batch);
         }
         batch.sort();
         self.stats.observe_batch(&batch);
-        self.stats
-            .observe_duration("write", || self.store.write(&batch));
-        self.stats.observe_db(&self.store);
-        Ok(header_rows)
+        Ok((header_rows, batch))
     }
 
     pub(crate) fn is_ready(&self) -> bool {
@@ -246,6 +630,79 @@ impl Index {
     }
 }
 
+fn next_block(
+    blocks: &mut impl Iterator<Item = Result<Box<dyn BlockFile>>>,
+) -> Result<Box<dyn BlockFile>> {
+    blocks.next().context("missing block")?
+}
+
+// Header rows of the indexed blocks at the given heights range (excluding its end)
+fn range_header_rows(chain: &Chain, start: usize, end: usize) -> Result<Vec<HeaderRow>> {
+    ensure!(start > 0, "genesis block is not indexed");
+    ensure!(start < end, "empty heights range [{}, {})", start, end);
+    ensure!(
+        end - start <= MAX_REINDEX_BLOCKS,
+        "too many blocks to re-index: {} (max {})",
+        end - start,
+        MAX_REINDEX_BLOCKS
+    );
+    ensure!(
+        end <= chain.height() + 1,
+        "heights range end {} is beyond the tip (height {})",
+        end,
+        chain.height()
+    );
+    Ok((start..end)
+        .map(|height| {
+            chain
+                .get_header_row(height)
+                .expect("missing header row")
+                .clone()
+        })
+        .collect())
+}
+
+/// Replace the funding, spending and txid rows of the blocks at `rows` (read from `blocks`, in the
+/// same order), returning the number of deleted rows. The stale rows are found by a single index
+/// scan, and deleted by the same write batch as their blocks' new rows (so a failed re-index
+/// leaves the index consistent).
+fn reindex_blocks(
+    store: &DBStore,
+    chain: &Chain,
+    rows: &[HeaderRow],
+    blocks: &mut impl Iterator<Item = Result<Box<dyn BlockFile>>>,
+    filter_size: Option<usize>,
+    batch_size: usize,
+) -> Result<usize> {
+    let mut deleted = 0;
+    let tip_row = serialize(&chain.tip()).into_boxed_slice();
+    for chunk in rows.chunks(batch_size) {
+        let mut batch = WriteBatch::default();
+        for row in chunk {
+            let result = index_single_block(row.pos, next_block(blocks)?, filter_size, None)?;
+            ensure!(
+                result.header_row == *row,
+                "block {} doesn't match its indexed header",
+                row.hash
+            );
+            result.extend(&mut batch);
+        }
+        // stale rows are found by the new rows' keys, within the chunk's blocks
+        let chunk_hashes: HashSet<BlockHash> = chunk.iter().map(|row| row.hash).collect();
+        batch.stale_rows = store.find_stale_rows(&batch, |row| {
+            let pos = HashPrefixRow::from_db_row(row).pos();
+            chain
+                .get_header_row_for(pos)
+                .map_or(false, |header_row| chunk_hashes.contains(&header_row.hash))
+        });
+        deleted += batch.stale_rows.len();
+        batch.tip_row = tip_row.clone(); // the tip is unchanged
+        batch.sort();
+        store.write(&batch);
+    }
+    Ok(deleted)
+}
+
 fn position_from_row(row: Row) -> FilePosition {
     HashPrefixRow::from_db_row(&row).pos()
 }
@@ -254,19 +711,30 @@ fn db_rows_size(rows: &[Row]) -> usize {
     rows.iter().map(|key| key.len()).sum()
 }
 
-fn index_single_block(block_pos: FilePosition, mut file: impl Read + Seek) -> Result<IndexResult> {
+/// `prevouts` are the outputs spent by the block (if balances are indexed).
+fn index_single_block(
+    block_pos: FilePosition,
+    mut file: impl Read + Seek,
+    filter_size: Option<usize>,
+    prevouts: Option<Vec<TxOut>>,
+) -> Result<IndexResult> {
     let block_header = BlockHeader::consensus_decode(&mut file)?;
     let tx_count = VarInt::consensus_decode(&mut file)?.0 as usize;
 
     let mut funding_rows = Vec::with_capacity(tx_count);
     let mut spending_rows = Vec::with_capacity(tx_count);
     let mut txid_rows = Vec::with_capacity(tx_count);
+    let mut scripthashes = vec![]; // used only for the block filter
+    let mut balances = prevouts.map(|prevouts| (BalanceDeltas::default(), prevouts.into_iter()));
 
     for _ in 0..tx_count {
         let offset = file.seek(SeekFrom::Current(0))?;
         let tx_pos = block_pos.with_offset(u32::try_from(offset)?);
         let tx = Transaction::consensus_decode(&mut file)?;
         txid_rows.push(TxidRow::row(tx.txid(), tx_pos));
+        if let Some((deltas, prevouts)) = &mut balances {
+            deltas.add_tx(&tx, prevouts)?;
+        }
 
         funding_rows.extend(
             tx.output
@@ -274,6 +742,9 @@ fn index_single_block(block_pos: FilePosition, mut file: impl Read + Seek) -> Re
                 .filter(|txo| !txo.script_pubkey.is_provably_unspendable())
                 .map(|txo| {
                     let scripthash = ScriptHash::new(&txo.script_pubkey);
+                    if filter_size.is_some() {
+                        scripthashes.push(scripthash);
+                    }
                     ScriptHashRow::row(scripthash, tx_pos)
                 }),
         );
@@ -289,10 +760,241 @@ fn index_single_block(block_pos: FilePosition, mut file: impl Read + Seek) -> Re
     }
     let block_limit = file.seek(SeekFrom::Current(0))?;
     let block_size = u32::try_from(block_limit)? - block_pos.offset;
+    let balances = match balances {
+        Some((deltas, mut prevouts)) => {
+            ensure!(
+                prevouts.next().is_none(),
+                "too many prevouts for block {}",
+                block_header.block_hash()
+            );
+            Some(deltas)
+        }
+        None => None,
+    };
     Ok(IndexResult {
         funding_rows,
         spending_rows,
         txid_rows,
+        filter: filter_size.map(|size| BlockFilter::new(&scripthashes, size)),
+        balances,
         header_row: HeaderRow::new(block_header, block_pos, block_size),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        index_single_block, position_from_row, range_header_rows, reindex_blocks, BalanceDeltas,
+        MAX_REINDEX_BLOCKS,
+    };
+    use crate::chain::Chain;
+    use crate::daemon::BlockFile;
+    use crate::db::{DBStore, DbSyncMode, WriteBatch};
+    use crate::types::{
+        BalanceRow, FilePosition, HeaderRow, ScriptHash, ScriptHashRow, SpendingPrefixRow, TxidRow,
+    };
+    use bitcoin::{
+        consensus::serialize, Block, BlockHash, BlockHeader, OutPoint, Script, Transaction, TxIn,
+        TxOut, Txid,
+    };
+    use std::io::Cursor;
+
+    // spent outputs are given explicitly, so only coinbase inputs' outpoints matter
+    fn tx(previous_output: OutPoint, outputs: &[(&Script, u64)]) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output,
+                script_sig: Script::new(),
+                sequence: 0xFFFFFFFF,
+                witness: vec![],
+            }],
+            output: outputs
+                .iter()
+                .map(|(script, value)| TxOut {
+                    value: *value,
+                    script_pubkey: (*script).clone(),
+                })
+                .collect(),
+        }
+    }
+
+    fn block_deltas(txs: &[Transaction], prevouts: Vec<TxOut>) -> BalanceDeltas {
+        let mut deltas = BalanceDeltas::default();
+        let mut prevouts = prevouts.into_iter();
+        for tx in txs {
+            deltas.add_tx(tx, &mut prevouts).unwrap();
+        }
+        assert!(prevouts.next().is_none());
+        deltas
+    }
+
+    #[test]
+    fn test_balances_reorg() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), false, DbSyncMode::Safe, true).unwrap();
+        let write = |deltas: BalanceDeltas| {
+            let mut batch = WriteBatch::default();
+            deltas.extend(&mut batch);
+            store.write(&batch);
+        };
+        let (a, b, c) = (
+            Script::from(vec![0x51]),
+            Script::from(vec![0x52]),
+            Script::from(vec![0x53]),
+        );
+        let balance =
+            |script: &Script| store.get_balance(&BalanceRow::key(ScriptHash::new(script)));
+
+        let coinbase = |script: &Script| tx(OutPoint::null(), &[(script, 5000)]);
+        let spend = OutPoint::new(Txid::default(), 0);
+        let spent_a = TxOut {
+            value: 5000,
+            script_pubkey: a.clone(),
+        };
+        let op_return = Script::from(vec![0x6a]);
+        write(block_deltas(&[coinbase(&a)], vec![])); // 1
+        let block2 = [
+            coinbase(&c),
+            tx(spend, &[(&b, 3000), (&a, 1900), (&op_return, 0)]),
+        ];
+        write(block_deltas(&block2, vec![spent_a.clone()])); // 2
+        assert_eq!((balance(&a), balance(&b), balance(&c)), (1900, 3000, 5000));
+
+        // block 2 is replaced by a block where `a` pays `c`, with the rollback in the same batch
+        let block2_replacement = [coinbase(&b), tx(spend, &[(&c, 4900)])];
+        let mut deltas = BalanceDeltas::default();
+        deltas.merge(block_deltas(&block2, vec![spent_a.clone()]), -1);
+        deltas.merge(block_deltas(&block2_replacement, vec![spent_a]), 1);
+        write(deltas);
+        assert_eq!((balance(&a), balance(&b), balance(&c)), (0, 5000, 4900));
+        assert_eq!(balance(&op_return), 0);
+
+        // missing prevouts fail the indexing
+        let mut prevouts = vec![].into_iter();
+        assert!(BalanceDeltas::default()
+            .add_tx(&block2[1], &mut prevouts)
+            .is_err());
+    }
+
+    #[test]
+    fn test_reindex_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), false, DbSyncMode::Safe, false).unwrap();
+        let (a, b) = (Script::from(vec![0x51]), Script::from(vec![0x52]));
+
+        // a single block file, containing 4 blocks (where block 2 spends block 1's coinbase)
+        let mut file = vec![];
+        let mut rows: Vec<HeaderRow> = vec![];
+        let (mut prev_coinbase, mut spending_tx) = (Txid::default(), None);
+        for height in 0..4 {
+            let mut txdata = vec![tx(OutPoint::null(), &[(&a, 1000 + height)])];
+            if height == 2 {
+                let spending = tx(OutPoint::new(prev_coinbase, 0), &[(&b, 900)]);
+                spending_tx = Some(spending.clone());
+                txdata.push(spending);
+            }
+            prev_coinbase = txdata[0].txid();
+            let block = Block {
+                header: BlockHeader {
+                    version: 1,
+                    prev_blockhash: rows.last().map_or(BlockHash::default(), |row| row.hash),
+                    merkle_root: Default::default(),
+                    time: 0,
+                    bits: 0,
+                    nonce: 0,
+                },
+                txdata,
+            };
+            let pos = FilePosition {
+                file_id: 0,
+                offset: file.len() as u32,
+            };
+            file.extend(serialize(&block));
+            let size = file.len() as u32 - pos.offset;
+            rows.push(HeaderRow::new(block.header, pos, size));
+        }
+        let open = |pos: FilePosition| -> anyhow::Result<Box<dyn BlockFile>> {
+            let mut cursor = Cursor::new(file.clone());
+            cursor.set_position(pos.offset.into());
+            Ok(Box::new(cursor))
+        };
+        let mut chain = Chain::new(rows[0].clone()); // genesis block is not indexed
+        let mut batch = WriteBatch::default();
+        for row in &rows[1..] {
+            let result = index_single_block(row.pos, open(row.pos).unwrap(), None, None).unwrap();
+            result.extend(&mut batch);
+        }
+        batch.sort();
+        store.write(&batch);
+        chain.update(rows[1..].to_vec());
+
+        let funding = |script: &Script| -> Vec<FilePosition> {
+            let prefix = ScriptHashRow::scan_prefix(ScriptHash::new(script));
+            store.iter_funding(prefix).map(position_from_row).collect()
+        };
+        let spending = |outpoint: OutPoint| -> Vec<FilePosition> {
+            let prefix = SpendingPrefixRow::scan_prefix(outpoint);
+            store.iter_spending(prefix).map(position_from_row).collect()
+        };
+        let spending_tx = spending_tx.unwrap();
+        let txid = |txid: Txid| -> Vec<FilePosition> {
+            store
+                .iter_txid(TxidRow::scan_prefix(txid))
+                .map(position_from_row)
+                .collect()
+        };
+        let expected = (
+            funding(&a),
+            funding(&b),
+            spending(spending_tx.input[0].previous_output),
+            txid(spending_tx.txid()),
+        );
+        assert_eq!(expected.0.len(), 3);
+        assert_eq!(expected.1.len(), 1);
+
+        // corrupt block 2 rows: a funding row at a bogus position (within block 2)
+        let bogus = FilePosition {
+            file_id: 0,
+            offset: expected.1[0].offset + 1,
+        };
+        let mut batch = WriteBatch::default();
+        batch.stale_rows.funding_rows =
+            vec![ScriptHashRow::row(ScriptHash::new(&b), expected.1[0]).to_db_row()];
+        batch.funding_rows = vec![ScriptHashRow::row(ScriptHash::new(&b), bogus).to_db_row()];
+        batch.tip_row = serialize(&chain.tip()).into_boxed_slice();
+        store.write(&batch);
+        assert_eq!(funding(&b), vec![bogus]);
+
+        assert!(range_header_rows(&chain, 0, 2).is_err()); // genesis
+        assert!(range_header_rows(&chain, 2, 2).is_err()); // empty
+        let err = range_header_rows(&chain, 2, 5).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "heights range end 5 is beyond the tip (height 3)"
+        );
+        let err = range_header_rows(&chain, 1, 1 + MAX_REINDEX_BLOCKS + 1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "too many blocks to re-index: 1001 (max 1000)"
+        );
+
+        let range = range_header_rows(&chain, 2, 3).unwrap();
+        let mut blocks = range.iter().map(|row| open(row.pos));
+        let deleted = reindex_blocks(&store, &chain, &range, &mut blocks, None, 1).unwrap();
+        assert_eq!(deleted, 5); // 2 funding rows (including the bogus one), 1 spending, 2 txids
+        assert_eq!(funding(&a), expected.0); // blocks 1 and 3 are untouched
+        assert_eq!(funding(&b), expected.1);
+        assert_eq!(spending(spending_tx.input[0].previous_output), expected.2);
+        assert_eq!(txid(spending_tx.txid()), expected.3);
+        assert_eq!(store.get_tip().unwrap(), serialize(&chain.tip()));
+
+        // mismatching blocks fail the re-index
+        let range = range_header_rows(&chain, 1, 3).unwrap();
+        let mut blocks = range.iter().rev().map(|row| open(row.pos));
+        assert!(reindex_blocks(&store, &chain, &range, &mut blocks, None, 10).is_err());
+        assert_eq!(funding(&a), expected.0); // the stale rows are not deleted
+        assert_eq!(funding(&b), expected.1);
+    }
+}
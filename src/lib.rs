@@ -9,22 +9,33 @@ extern crate serde_derive;
 
 extern crate configure_me;
 
+mod breaker;
 mod cache;
 mod chain;
 mod config;
 mod daemon;
 mod db;
 mod electrum;
+mod fees;
+mod filter;
+mod http;
 mod index;
+mod lru;
 mod mempool;
 mod merkle;
 mod metrics;
 mod p2p;
+mod proxy;
+mod rest;
+mod retry;
+mod script;
 mod server;
 mod signals;
 mod status;
 mod thread;
+mod throttle;
 mod tracker;
 mod types;
+mod undo;
 
 pub use server::run;
@@ -0,0 +1,101 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A simple least-recently-used cache, holding up to `capacity` entries.
+pub(crate) struct Lru<K, V> {
+    capacity: usize,
+    entries: HashMap<K, (V, u64)>, // value and its last access "time"
+    order: BTreeMap<u64, K>,       // keys, ordered by their last access "time"
+    counter: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            counter: 0,
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.counter += 1;
+        self.counter
+    }
+
+    /// Return the cached value (marking it as the most recently used).
+    pub(crate) fn get(&mut self, key: &K) -> Option<&V> {
+        let tick = self.tick();
+        let (value, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(&*last_used);
+        self.order.insert(tick, key.clone());
+        *last_used = tick;
+        Some(&*value)
+    }
+
+    /// Remove the value (if it exists).
+    pub(crate) fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, last_used) = self.entries.remove(key)?;
+        self.order.remove(&last_used);
+        Some(value)
+    }
+
+    /// Add a value (replacing the previous one), evicting the least recently used entries if needed.
+    /// Return the replaced and evicted values.
+    pub(crate) fn insert(&mut self, key: K, value: V) -> Vec<V> {
+        if self.capacity == 0 {
+            return vec![value];
+        }
+        let mut removed = vec![];
+        let tick = self.tick();
+        if let Some((prev, last_used)) = self.entries.insert(key.clone(), (value, tick)) {
+            self.order.remove(&last_used);
+            removed.push(prev);
+        }
+        self.order.insert(tick, key);
+        while self.entries.len() > self.capacity {
+            let oldest = *self.order.keys().next().expect("empty LRU order");
+            let key = self.order.remove(&oldest).expect("missing LRU key");
+            let (value, _) = self.entries.remove(&key).expect("missing LRU entry");
+            removed.push(value);
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lru;
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::new(2);
+        lru.insert(1, "a");
+        lru.insert(2, "b");
+        assert_eq!(lru.get(&1), Some(&"a")); // 2 is now the least recently used
+        assert_eq!(lru.insert(3, "c"), vec!["b"]);
+        assert_eq!(lru.get(&2), None);
+        assert_eq!(lru.get(&1), Some(&"a"));
+        assert_eq!(lru.get(&3), Some(&"c"));
+
+        assert_eq!(lru.insert(1, "A"), vec!["a"]); // replace existing value
+        assert_eq!(lru.insert(4, "d"), vec!["c"]);
+        assert_eq!(lru.get(&3), None);
+        assert_eq!(lru.get(&1), Some(&"A"));
+        assert_eq!(lru.get(&4), Some(&"d"));
+
+        assert_eq!(lru.remove(&1), Some("A"));
+        assert_eq!(lru.remove(&1), None);
+        assert!(lru.insert(5, "e").is_empty()); // no eviction is needed
+        assert_eq!(lru.get(&4), Some(&"d"));
+        assert_eq!(lru.get(&5), Some(&"e"));
+    }
+
+    #[test]
+    fn test_empty_lru() {
+        let mut lru = Lru::new(0);
+        assert_eq!(lru.insert(1, "a"), vec!["a"]);
+        assert_eq!(lru.get(&1), None);
+    }
+}
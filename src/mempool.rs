@@ -1,19 +1,20 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
-use std::iter::FromIterator;
 use std::ops::Bound;
 
 use bitcoin::hashes::Hash;
-use bitcoin::{Amount, OutPoint, Transaction, Txid};
+use bitcoin::{Amount, OutPoint, SignedAmount, Transaction, TxOut, Txid};
 use bitcoincore_rpc::json;
+use parking_lot::Mutex;
 use rayon::prelude::*;
 use serde::ser::{Serialize, SerializeSeq, Serializer};
 
 use crate::{
     daemon::Daemon,
-    metrics::{Gauge, Metrics},
+    index::BalanceDeltas,
+    metrics::{Counter, Gauge, Metrics},
     types::ScriptHash,
 };
 
@@ -23,17 +24,30 @@ pub(crate) struct Entry {
     pub fee: Amount,
     pub vsize: u64,
     pub has_unconfirmed_inputs: bool,
+    pub parents: Vec<Txid>,            // in-mempool parents (when added)
+    pub balance_deltas: BalanceDeltas, // empty, unless the balance index is enabled
 }
 
+// Keep only the most recently orphaned transactions
+const MAX_ORPHANED: usize = 1000;
+
 /// Mempool current state
 pub(crate) struct Mempool {
     entries: HashMap<Txid, Entry>,
     by_funding: BTreeSet<(ScriptHash, Txid)>,
     by_spending: BTreeSet<(OutPoint, Txid)>,
     fees: FeeHistogram,
+    recent: Vec<Txid>, // added during the last sync
+    recent_spent: Mutex<HashMap<Txid, Vec<ScriptHash>>>, // resolved on demand, for recent txs
+    pending: HashSet<Txid>, // reported by bitcoind, but not loaded yet
+    scan_batch_size: Option<usize>,
+    loading: bool, // until the initial mempool scan is over
+    orphaned: VecDeque<Txid>,
+    balances: Option<HashMap<ScriptHash, i64>>, // total mempool deltas (if the balance index is enabled)
     // stats
     vsize: Gauge,
     count: Gauge,
+    orphans: Counter,
 }
 
 // Smallest possible txid
@@ -47,12 +61,19 @@ fn txid_max() -> Txid {
 }
 
 impl Mempool {
-    pub fn new(metrics: &Metrics) -> Self {
+    pub fn new(metrics: &Metrics, scan_batch_size: Option<usize>, balances: bool) -> Self {
         Self {
             entries: Default::default(),
             by_funding: Default::default(),
             by_spending: Default::default(),
             fees: FeeHistogram::empty(),
+            recent: vec![],
+            recent_spent: Default::default(),
+            pending: Default::default(),
+            scan_batch_size,
+            loading: true,
+            orphaned: Default::default(),
+            balances: if balances { Some(HashMap::new()) } else { None },
             vsize: metrics.gauge(
                 "mempool_txs_vsize",
                 "Total vsize of mempool transactions (in bytes)",
@@ -63,17 +84,39 @@ impl Mempool {
                 "Total number of mempool transactions",
                 "fee_rate",
             ),
+            orphans: metrics.counter(
+                "mempool_orphaned_txs",
+                "Mempool transactions removed together with their unconfirmed parents",
+                "type",
+            ),
         }
     }
 
+    /// Return true if the initial mempool scan is not over yet.
+    pub(crate) fn is_loading(&self) -> bool {
+        self.loading
+    }
+
     pub(crate) fn fees_histogram(&self) -> &FeeHistogram {
         &self.fees
     }
 
+    /// Return the most recently orphaned transactions (oldest first).
+    pub(crate) fn get_orphaned_txids(&self) -> Vec<Txid> {
+        self.orphaned.iter().copied().collect()
+    }
+
     pub(crate) fn get(&self, txid: &Txid) -> Option<&Entry> {
         self.entries.get(txid)
     }
 
+    /// Return the total mempool balance change of the scripthash (if the balance index is enabled).
+    pub(crate) fn get_balance_delta(&self, scripthash: &ScriptHash) -> Option<SignedAmount> {
+        let balances = self.balances.as_ref()?;
+        let delta = balances.get(scripthash).copied().unwrap_or(0);
+        Some(SignedAmount::from_sat(delta))
+    }
+
     pub(crate) fn filter_by_funding(&self, scripthash: &ScriptHash) -> Vec<&Entry> {
         let range = (
             Bound::Included((*scripthash, txid_min())),
@@ -85,6 +128,44 @@ impl Mempool {
             .collect()
     }
 
+    /// Return transactions added during the last sync, funding or spending the given scripthashes.
+    /// Inputs spending confirmed outputs are resolved using `get_prevouts` (once per transaction).
+    pub(crate) fn filter_recent(
+        &self,
+        scripthashes: &HashSet<ScriptHash>,
+        get_prevouts: impl Fn(&Txid) -> Result<Vec<TxOut>>,
+    ) -> Result<Vec<(ScriptHash, Txid)>> {
+        let mut result = vec![];
+        for entry in self.recent.iter().filter_map(|txid| self.get(txid)) {
+            let cached = self.recent_spent.lock().get(&entry.txid).cloned();
+            let spending = match cached {
+                Some(spending) => spending,
+                None => {
+                    let spending = spent_scripthashes(&self.entries, entry, &get_prevouts)?;
+                    self.recent_spent
+                        .lock()
+                        .insert(entry.txid, spending.clone());
+                    spending
+                }
+            };
+            let funding = entry
+                .tx
+                .output
+                .iter()
+                .map(|txo| ScriptHash::new(&txo.script_pubkey));
+            let matched: BTreeSet<ScriptHash> = funding
+                .chain(spending)
+                .filter(|scripthash| scripthashes.contains(scripthash))
+                .collect();
+            result.extend(
+                matched
+                    .into_iter()
+                    .map(|scripthash| (scripthash, entry.txid)),
+            );
+        }
+        Ok(result)
+    }
+
     pub(crate) fn filter_by_spending(&self, outpoint: &OutPoint) -> Vec<&Entry> {
         let range = (
             Bound::Included((*outpoint, txid_min())),
@@ -96,41 +177,72 @@ impl Mempool {
             .collect()
     }
 
-    pub fn sync(&mut self, daemon: &Daemon) {
-        let txids = match daemon.get_mempool_txids() {
-            Ok(txids) => txids,
+    /// Return false if more transactions have to be loaded (during the initial mempool scan).
+    /// Removed transactions, which are not confirmed (according to `is_confirmed`) and whose
+    /// parents were removed too, are reported as orphaned (e.g. evicted or replaced parents).
+    pub fn sync(&mut self, daemon: &Daemon, is_confirmed: impl Fn(&Txid) -> bool) -> bool {
+        self.recent.clear();
+        self.recent_spent.get_mut().clear();
+        let delta = match daemon.get_mempool_delta() {
+            Ok(delta) => delta,
             Err(e) => {
                 warn!("mempool sync failed: {}", e);
-                return;
+                return true; // retry on the next sync
             }
         };
-        debug!("loading {} mempool transactions", txids.len());
+        debug!(
+            "mempool delta: {} txs added, {} txs removed",
+            delta.added.len(),
+            delta.removed.len()
+        );
 
-        let new_txids = HashSet::<Txid>::from_iter(txids);
-        let old_txids = HashSet::<Txid>::from_iter(self.entries.keys().copied());
+        let removed_txids: HashSet<Txid> = delta.removed.iter().copied().collect();
+        for txid in find_orphans(&self.entries, &removed_txids) {
+            if is_confirmed(&txid) {
+                continue; // mined together with its parents
+            }
+            debug!("mempool tx {} is orphaned", txid);
+            self.orphans.inc("removed");
+            if self.orphaned.len() == MAX_ORPHANED {
+                self.orphaned.pop_front();
+            }
+            self.orphaned.push_back(txid);
+        }
 
-        let to_add = &new_txids - &old_txids;
-        let to_remove = &old_txids - &new_txids;
+        let mut removed = 0;
+        for txid in delta.removed {
+            self.pending.remove(&txid);
+            if self.entries.contains_key(&txid) {
+                self.remove_entry(txid);
+                removed += 1;
+            }
+        }
+        self.pending.extend(delta.added);
 
-        let removed = to_remove.len();
-        for txid in to_remove {
-            self.remove_entry(txid);
+        let mut to_add: Vec<Txid> = self.pending.iter().copied().collect();
+        if let (true, Some(batch_size)) = (self.loading, self.scan_batch_size) {
+            to_add.truncate(batch_size);
         }
+        let pending = self.pending.len() - to_add.len();
+
+        let with_balances = self.balances.is_some();
         let entries: Vec<_> = to_add
             .par_iter()
             .filter_map(|txid| {
                 match (
-                    daemon.get_transaction(txid, None),
+                    get_transaction(daemon, txid, with_balances),
                     daemon.get_mempool_entry(txid),
                 ) {
-                    (Ok(tx), Ok(entry)) => Some((txid, tx, entry)),
+                    (Ok((tx, deltas)), Ok(entry)) => Some((txid, tx, deltas, entry)),
                     _ => None,
                 }
             })
             .collect();
         let added = entries.len();
-        for (txid, tx, entry) in entries {
-            self.add_entry(*txid, tx, entry);
+        self.recent = entries.iter().map(|(txid, _, _, _)| **txid).collect();
+        for (txid, tx, deltas, entry) in entries {
+            self.pending.remove(txid); // failed transactions will be retried on the next sync
+            self.add_entry(*txid, tx, deltas, entry);
         }
         self.fees = FeeHistogram::new(self.entries.values().map(|e| (e.fee, e.vsize)));
         for i in 1..FeeHistogram::BINS {
@@ -146,9 +258,33 @@ impl Mempool {
             added,
             removed,
         );
+        if pending > 0 {
+            info!(
+                "loading mempool: {} txs loaded, {} pending",
+                self.entries.len(),
+                pending
+            );
+            return false;
+        }
+        if self.loading {
+            info!("mempool loaded: {} txs", self.entries.len());
+            self.loading = false;
+        }
+        true
     }
 
-    fn add_entry(&mut self, txid: Txid, tx: Transaction, entry: json::GetMempoolEntryResult) {
+    fn add_entry(
+        &mut self,
+        txid: Txid,
+        tx: Transaction,
+        balance_deltas: BalanceDeltas,
+        entry: json::GetMempoolEntryResult,
+    ) {
+        if let Some(balances) = self.balances.as_mut() {
+            for (scripthash, delta) in balance_deltas.iter() {
+                update_balance(balances, scripthash, delta);
+            }
+        }
         for txi in &tx.input {
             self.by_spending.insert((txi.previous_output, txid));
         }
@@ -162,6 +298,8 @@ impl Mempool {
             vsize: entry.vsize,
             fee: entry.fees.base,
             has_unconfirmed_inputs: !entry.depends.is_empty(),
+            parents: entry.depends,
+            balance_deltas,
         };
         assert!(
             self.entries.insert(txid, entry).is_none(),
@@ -171,6 +309,11 @@ impl Mempool {
 
     fn remove_entry(&mut self, txid: Txid) {
         let entry = self.entries.remove(&txid).expect("missing tx from mempool");
+        if let Some(balances) = self.balances.as_mut() {
+            for (scripthash, delta) in entry.balance_deltas.iter() {
+                update_balance(balances, scripthash, -delta);
+            }
+        }
         for txi in entry.tx.input {
             self.by_spending.remove(&(txi.previous_output, txid));
         }
@@ -181,6 +324,76 @@ impl Mempool {
     }
 }
 
+// Zero balances are removed, to keep the map small
+fn update_balance(balances: &mut HashMap<ScriptHash, i64>, scripthash: &ScriptHash, delta: i64) {
+    let balance = balances.entry(*scripthash).or_default();
+    *balance += delta;
+    if *balance == 0 {
+        balances.remove(scripthash);
+    }
+}
+
+/// Fetch a mempool transaction, computing its balance deltas if `with_balances` is set.
+fn get_transaction(
+    daemon: &Daemon,
+    txid: &Txid,
+    with_balances: bool,
+) -> Result<(Transaction, BalanceDeltas)> {
+    let mut deltas = BalanceDeltas::default();
+    if !with_balances {
+        return Ok((daemon.get_transaction(txid, None)?, deltas));
+    }
+    let (tx, prevouts) = daemon.get_transaction_with_prevouts(txid)?;
+    deltas.add_tx(&tx, &mut prevouts.into_iter())?;
+    Ok((tx, deltas))
+}
+
+/// Return the scripthashes spent by `entry`'s inputs, fetching its prevouts (using `get_prevouts`)
+/// only if some of them are not in the mempool.
+fn spent_scripthashes(
+    entries: &HashMap<Txid, Entry>,
+    entry: &Entry,
+    get_prevouts: impl Fn(&Txid) -> Result<Vec<TxOut>>,
+) -> Result<Vec<ScriptHash>> {
+    let in_mempool: Option<Vec<ScriptHash>> = entry
+        .tx
+        .input
+        .iter()
+        .map(|txi| {
+            let outpoint = txi.previous_output;
+            let parent = entries.get(&outpoint.txid)?;
+            let txo = parent.tx.output.get(outpoint.vout as usize)?;
+            Some(ScriptHash::new(&txo.script_pubkey))
+        })
+        .collect();
+    if let Some(spending) = in_mempool {
+        return Ok(spending);
+    }
+    let prevouts = get_prevouts(&entry.txid)
+        .with_context(|| format!("failed to get prevouts of mempool tx {}", entry.txid))?;
+    Ok(prevouts
+        .iter()
+        .map(|txo| ScriptHash::new(&txo.script_pubkey))
+        .collect())
+}
+
+/// Return the removed transactions, whose in-mempool parents were removed too.
+fn find_orphans(entries: &HashMap<Txid, Entry>, removed: &HashSet<Txid>) -> Vec<Txid> {
+    let mut orphans: Vec<Txid> = removed
+        .iter()
+        .filter_map(|txid| entries.get(txid))
+        .filter(|entry| {
+            entry
+                .parents
+                .iter()
+                .any(|parent| removed.contains(parent) && entries.contains_key(parent))
+        })
+        .map(|entry| entry.txid)
+        .collect();
+    orphans.sort();
+    orphans
+}
+
 pub(crate) struct FeeHistogram {
     /// bins[64-i] contains transactions' statistics inside the fee band of [2**(i-1), 2**i).
     /// bins[64] = [0, 1)
@@ -247,9 +460,13 @@ impl Serialize for FeeHistogram {
 
 #[cfg(test)]
 mod tests {
-    use super::FeeHistogram;
-    use bitcoin::Amount;
+    use super::{find_orphans, spent_scripthashes, Entry, FeeHistogram};
+    use crate::types::ScriptHash;
+    use anyhow::Result;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Amount, OutPoint, Script, Transaction, TxIn, TxOut, Txid};
     use serde_json::json;
+    use std::collections::{HashMap, HashSet};
 
     #[test]
     fn test_histogram() {
@@ -270,4 +487,98 @@ mod tests {
             json!([[15, 10], [7, 40], [3, 20], [1, 10], [0, 100]])
         );
     }
+
+    #[test]
+    fn test_orphans() {
+        let txid = |n| Txid::from_inner([n; 32]);
+        let entry = |n, parents: Vec<u8>| Entry {
+            txid: txid(n),
+            tx: Transaction {
+                version: 1,
+                lock_time: 0,
+                input: vec![],
+                output: vec![],
+            },
+            fee: Amount::from_sat(1000),
+            vsize: 100,
+            has_unconfirmed_inputs: !parents.is_empty(),
+            parents: parents.into_iter().map(txid).collect(),
+            balance_deltas: Default::default(),
+        };
+        // 1 <- 2 <- 3, 4 <- 5 (where 4 is not loaded)
+        let entries: HashMap<Txid, Entry> = vec![
+            entry(1, vec![]),
+            entry(2, vec![1]),
+            entry(3, vec![2]),
+            entry(5, vec![4]),
+        ]
+        .into_iter()
+        .map(|e| (e.txid, e))
+        .collect();
+
+        let removed = |txids: &[u8]| txids.iter().copied().map(txid).collect::<HashSet<Txid>>();
+        assert_eq!(find_orphans(&entries, &removed(&[1])), vec![]);
+        assert_eq!(find_orphans(&entries, &removed(&[1, 2])), vec![txid(2)]);
+        assert_eq!(
+            find_orphans(&entries, &removed(&[1, 2, 3])),
+            vec![txid(2), txid(3)]
+        );
+        assert_eq!(find_orphans(&entries, &removed(&[2, 3])), vec![txid(3)]);
+        assert_eq!(find_orphans(&entries, &removed(&[4, 5])), vec![]);
+    }
+
+    #[test]
+    fn test_spent_scripthashes() {
+        let txo = |n| TxOut {
+            value: 1000,
+            script_pubkey: Script::from(vec![n]),
+        };
+        let entry = |n, inputs: Vec<(u8, u32)>, outputs: Vec<u8>| Entry {
+            txid: Txid::from_inner([n; 32]),
+            tx: Transaction {
+                version: 1,
+                lock_time: 0,
+                input: inputs
+                    .into_iter()
+                    .map(|(parent, vout)| TxIn {
+                        previous_output: OutPoint::new(Txid::from_inner([parent; 32]), vout),
+                        script_sig: Script::new(),
+                        sequence: 0xffffffff,
+                        witness: vec![],
+                    })
+                    .collect(),
+                output: outputs.into_iter().map(txo).collect(),
+            },
+            fee: Amount::from_sat(1000),
+            vsize: 100,
+            has_unconfirmed_inputs: false,
+            parents: vec![],
+            balance_deltas: Default::default(),
+        };
+        let scripthash = |n| ScriptHash::new(&Script::from(vec![n]));
+        let no_prevouts =
+            |_txid: &Txid| -> Result<Vec<TxOut>> { panic!("unexpected prevouts lookup") };
+
+        // 1 is in the mempool, 9 is confirmed
+        let entries: HashMap<Txid, Entry> = vec![entry(1, vec![(9, 0)], vec![10, 11])]
+            .into_iter()
+            .map(|e| (e.txid, e))
+            .collect();
+
+        let child = entry(2, vec![(1, 1)], vec![]);
+        assert_eq!(
+            spent_scripthashes(&entries, &child, no_prevouts).unwrap(),
+            vec![scripthash(11)]
+        );
+
+        let spender = entry(3, vec![(1, 0), (9, 1)], vec![]);
+        let prevouts = |txid: &Txid| -> Result<Vec<TxOut>> {
+            assert_eq!(*txid, Txid::from_inner([3; 32]));
+            Ok(vec![txo(10), txo(20)])
+        };
+        assert_eq!(
+            spent_scripthashes(&entries, &spender, prevouts).unwrap(),
+            vec![scripthash(10), scripthash(20)]
+        );
+    }
 }
@@ -67,6 +67,26 @@ mod metrics_impl {
                 .expect("failed to register Gauge");
             Gauge { gauge }
         }
+
+        pub fn counter(&self, name: &str, desc: &str, label: &str) -> Counter {
+            let opts = prometheus::Opts::new(name, desc);
+            let counter = prometheus::IntCounterVec::new(opts, &[label]).unwrap();
+            self.reg
+                .register(Box::new(counter.clone()))
+                .expect("failed to register Counter");
+            Counter { counter }
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Counter {
+        counter: prometheus::IntCounterVec,
+    }
+
+    impl Counter {
+        pub fn inc(&self, label: &str) {
+            self.counter.with_label_values(&[label]).inc()
+        }
     }
 
     #[derive(Clone)]
@@ -102,7 +122,7 @@ mod metrics_impl {
 }
 
 #[cfg(feature = "metrics")]
-pub use metrics_impl::{Gauge, Histogram, Metrics};
+pub use metrics_impl::{Counter, Gauge, Histogram, Metrics};
 
 #[cfg(not(feature = "metrics"))]
 mod metrics_fake {
@@ -131,6 +151,17 @@ mod metrics_fake {
         pub fn gauge(&self, _name: &str, _desc: &str, _label: &str) -> Gauge {
             Gauge {}
         }
+
+        pub fn counter(&self, _name: &str, _desc: &str, _label: &str) -> Counter {
+            Counter {}
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Counter {}
+
+    impl Counter {
+        pub fn inc(&self, _label: &str) {}
     }
 
     #[derive(Clone)]
@@ -156,7 +187,7 @@ mod metrics_fake {
 }
 
 #[cfg(not(feature = "metrics"))]
-pub use metrics_fake::{Gauge, Histogram, Metrics};
+pub use metrics_fake::{Counter, Gauge, Histogram, Metrics};
 
 pub(crate) fn default_duration_buckets() -> Vec<f64> {
     vec![
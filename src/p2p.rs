@@ -5,7 +5,8 @@ use bitcoin::{
         Decodable,
     },
     network::{
-        address, constants,
+        address,
+        constants::{self, ServiceFlags},
         message::{self, CommandString, NetworkMessage},
         message_blockdata::{GetHeadersMessage, Inventory},
         message_network,
@@ -13,17 +14,19 @@ use bitcoin::{
     secp256k1::{self, rand::Rng},
     Block, BlockHash, BlockHeader, Network,
 };
-use crossbeam_channel::{bounded, select, Receiver, Sender};
+use crossbeam_channel::{bounded, select, Receiver, RecvTimeoutError, Sender};
 
 use std::io::{self, ErrorKind, Write};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
-use std::sync::Arc;
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Weak};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::{
     chain::Chain,
     config::ELECTRS_VERSION,
-    metrics::{default_duration_buckets, default_size_buckets, Metrics},
+    metrics::{default_duration_buckets, default_size_buckets, Histogram, Metrics},
+    retry::RetryPolicy,
+    signals::ExitFlag,
 };
 
 enum Request {
@@ -43,13 +46,48 @@ pub(crate) struct Connection {
     req_send: Sender<Request>,
     headers_recv: Receiver<Vec<BlockHeader>>,
     new_block_recv: Receiver<()>,
+    new_block_send: Weak<Sender<()>>, // owned by p2p_loop thread (for reconnecting)
+    stream: Arc<TcpStream>,
+    peer: Peer,
+}
+
+/// Parameters for (re)connecting to bitcoind p2p port.
+#[derive(Clone)]
+struct Peer {
+    network: Network,
+    address: SocketAddr,
+    required_services: ServiceFlags,
+    handshake_timeout: Duration,
+    send_duration: Histogram,
+    recv_duration: Histogram,
+    parse_duration: Histogram,
+    recv_size: Histogram,
+}
+
+impl Peer {
+    fn connect(&self) -> Result<TcpStream> {
+        TcpStream::connect_timeout(&self.address, self.handshake_timeout)
+            .with_context(|| format!("{} p2p failed to connect: {:?}", self.network, self.address))
+    }
+}
+
+/// Allows closing the p2p connection, without waiting for in-flight requests.
+pub(crate) struct ShutdownHandle(Arc<TcpStream>);
+
+impl ShutdownHandle {
+    /// Close the TCP connection (causing the p2p threads to exit).
+    pub(crate) fn shutdown(&self) {
+        if let Err(e) = self.0.shutdown(Shutdown::Both) {
+            debug!("failed to shutdown p2p connection: {}", e);
+        }
+    }
 }
 
 impl Connection {
     /// Get new block headers (supporting reorgs).
     /// https://en.bitcoin.it/wiki/Protocol_documentation#getheaders
     /// Defined as `&mut self` to prevent concurrent invocations (https://github.com/romanz/electrs/pull/526#issuecomment-934685515).
-    pub(crate) fn get_new_headers(&mut self, chain: &Chain) -> Result<Vec<BlockHash>> {
+    pub(crate) fn get_new_headers(&mut self, chain: &Chain) -> Result<Vec<BlockHeader>> {
         self.req_send.send(Request::get_new_headers(chain))?;
         let headers = self
             .headers_recv
@@ -64,15 +102,18 @@ impl Connection {
         if chain.get_block_height(prev_blockhash).is_none() {
             bail!("missing prev_blockhash: {}", prev_blockhash);
         };
-        let hashes: Vec<_> = headers.iter().map(BlockHeader::block_hash).collect();
-        for i in 1..hashes.len() {
+        for pair in headers.windows(2) {
             ensure!(
-                hashes[i - 1] == headers[i].prev_blockhash,
+                pair[0].block_hash() == pair[1].prev_blockhash,
                 "received unordered headers: {:?}",
                 headers,
             );
         }
-        Ok(hashes)
+        Ok(headers)
+    }
+
+    pub(crate) fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(Arc::clone(&self.stream))
     }
 
     /// Note: only a single receiver will get the notification (https://github.com/romanz/electrs/pull/526#issuecomment-934687415).
@@ -83,44 +124,86 @@ impl Connection {
     pub(crate) fn connect(
         network: Network,
         address: SocketAddr,
+        required_services: ServiceFlags,
+        handshake_timeout: Duration,
         metrics: &Metrics,
+        retry_policy: &RetryPolicy,
+        exit_flag: &ExitFlag,
     ) -> Result<Self> {
-        let conn = Arc::new(
-            TcpStream::connect(address)
-                .with_context(|| format!("{} p2p failed to connect: {:?}", network, address))?,
-        );
+        let peer = Peer {
+            network,
+            address,
+            required_services,
+            handshake_timeout,
+            send_duration: metrics.histogram_vec(
+                "p2p_send_duration",
+                "Time spent sending p2p messages (in seconds)",
+                "step",
+                default_duration_buckets(),
+            ),
+            recv_duration: metrics.histogram_vec(
+                "p2p_recv_duration",
+                "Time spent receiving p2p messages (in seconds)",
+                "step",
+                default_duration_buckets(),
+            ),
+            parse_duration: metrics.histogram_vec(
+                "p2p_parse_duration",
+                "Time spent parsing p2p messages (in seconds)",
+                "step",
+                default_duration_buckets(),
+            ),
+            recv_size: metrics.histogram_vec(
+                "p2p_recv_size",
+                "Size of p2p messages read (in bytes)",
+                "message",
+                default_size_buckets(),
+            ),
+        };
+        let conn = retry_policy.retry(exit_flag, "p2p connection", |_| true, || peer.connect())?;
+        let (new_block_send, new_block_recv) = bounded::<()>(0);
+        Self::start(conn, peer, Arc::new(new_block_send), new_block_recv)
+    }
+
+    /// Open a new connection to the same peer (re-handshaking), e.g. if the current one silently
+    /// stopped relaying new blocks. The new blocks' notification receiver stays connected, and the
+    /// current connection is closed when dropped. Connecting is not retried.
+    pub(crate) fn reconnect(&self) -> Result<Self> {
+        let new_block_send = self
+            .new_block_send
+            .upgrade()
+            .context("p2p connection is already closed")?;
+        let conn = self.peer.connect()?;
+        Self::start(
+            conn,
+            self.peer.clone(),
+            Arc::new(Sender::clone(&new_block_send)),
+            self.new_block_recv.clone(),
+        )
+    }
+
+    fn start(
+        conn: TcpStream,
+        peer: Peer,
+        new_block_send: Arc<Sender<()>>,
+        new_block_recv: Receiver<()>,
+    ) -> Result<Self> {
+        let conn = Arc::new(conn);
+        let network = peer.network;
+        let address = peer.address;
+        let required_services = peer.required_services;
+        let handshake_timeout = peer.handshake_timeout;
 
         let (tx_send, tx_recv) = bounded::<NetworkMessage>(1);
         let (rx_send, rx_recv) = bounded::<RawNetworkMessage>(1);
 
-        let send_duration = metrics.histogram_vec(
-            "p2p_send_duration",
-            "Time spent sending p2p messages (in seconds)",
-            "step",
-            default_duration_buckets(),
-        );
-        let recv_duration = metrics.histogram_vec(
-            "p2p_recv_duration",
-            "Time spent receiving p2p messages (in seconds)",
-            "step",
-            default_duration_buckets(),
-        );
-        let parse_duration = metrics.histogram_vec(
-            "p2p_parse_duration",
-            "Time spent parsing p2p messages (in seconds)",
-            "step",
-            default_duration_buckets(),
-        );
-        let recv_size = metrics.histogram_vec(
-            "p2p_recv_size",
-            "Size of p2p messages read (in bytes)",
-            "message",
-            default_size_buckets(),
-        );
+        let send_duration = peer.send_duration.clone();
+        let recv_duration = peer.recv_duration.clone();
+        let parse_duration = peer.parse_duration.clone();
+        let recv_size = peer.recv_size.clone();
 
         let stream = Arc::clone(&conn);
         crate::thread::spawn("p2p_send", move || loop {
-            use std::net::Shutdown;
             let msg = match send_duration.observe_duration("wait", || tx_recv.recv()) {
                 Ok(msg) => msg,
                 Err(_) => {
@@ -178,8 +261,8 @@ impl Connection {
 
         let (req_send, req_recv) = bounded::<Request>(1);
         let (headers_send, headers_recv) = bounded::<Vec<BlockHeader>>(1);
-        let (new_block_send, new_block_recv) = bounded::<()>(0);
-        let (init_send, init_recv) = bounded::<()>(0);
+        let (init_send, init_recv) = bounded::<Result<()>>(0);
+        let new_block_weak = Arc::downgrade(&new_block_send);
 
         tx_send.send(build_version_message())?;
 
@@ -190,7 +273,7 @@ impl Connection {
                         Ok(raw_msg) => raw_msg,
                         Err(_) => {  // p2p_recv is closed, so rx_send is disconnected
                             debug!("closing p2p_loop thread: peer has disconnected");
-                            return Ok(()); // new_block_send is dropped, causing the server to exit
+                            return Ok(()); // new_block_send is dropped, causing the server to exit (unless reconnected)
                         }
                     };
 
@@ -205,6 +288,17 @@ impl Connection {
                         }
                         NetworkMessage::Version(version) => {
                             debug!("peer version: {:?}", version);
+                            let missing = ServiceFlags::from(
+                                required_services.as_u64() & !version.services.as_u64(),
+                            );
+                            if missing != ServiceFlags::NONE {
+                                init_send.send(Err(anyhow!(
+                                    "peer {} doesn't support required services: {}",
+                                    address,
+                                    missing
+                                )))?;
+                                return Ok(()); // disconnect from the peer
+                            }
                             tx_send.send(NetworkMessage::Verack)?;
                         }
                         NetworkMessage::Inv(inventory) => {
@@ -218,7 +312,7 @@ impl Connection {
                             tx_send.send(NetworkMessage::Pong(nonce))?; // connection keep-alive
                         }
                         NetworkMessage::Verack => {
-                            init_send.send(())?; // peer acknowledged our version
+                            init_send.send(Ok(()))?; // peer acknowledged our version
                         }
                         NetworkMessage::Headers(headers) => headers_send.send(headers)?,
                         NetworkMessage::Alert(_) => (),  // https://bitcoin.org/en/alert/2016-11-01-alert-retirement
@@ -242,12 +336,34 @@ impl Connection {
             }
         });
 
-        init_recv.recv()?; // wait until `verack` is received
+        // wait until `verack` is received
+        match init_recv.recv_timeout(handshake_timeout) {
+            Ok(result) => result?,
+            Err(RecvTimeoutError::Timeout) => {
+                let _ = conn.shutdown(Shutdown::Both); // stop p2p threads
+                bail!(
+                    "{} p2p handshake timed out after {:?}: check bitcoind p2p port {} (not its RPC port)",
+                    network,
+                    handshake_timeout,
+                    address
+                );
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                bail!(
+                    "{} p2p connection closed during handshake: {:?}",
+                    network,
+                    address
+                )
+            }
+        }
 
         Ok(Connection {
             req_send,
             headers_recv,
             new_block_recv,
+            new_block_send: new_block_weak,
+            stream: conn,
+            peer,
         })
     }
 }
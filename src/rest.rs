@@ -0,0 +1,236 @@
+use anyhow::{Context, Result};
+use bitcoin::{
+    consensus::{deserialize, Decodable},
+    Block, BlockHash, BlockHeader,
+};
+use parking_lot::Mutex;
+
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    daemon::{BlockFile, BlockSource, PrefetchedBlock},
+    http::read_response,
+    lru::Lru,
+    types::FilePosition,
+};
+
+// Keep the most recently fetched blocks (e.g. for reading several transactions of a block)
+const BLOCK_CACHE_SIZE: usize = 16;
+
+/// Fetches binary blocks by their hash from bitcoind's REST interface (requires `rest=1`),
+/// avoiding the hex-encoding overhead of `getblock` RPC (e.g. for remote daemons).
+/// Blocks are also readable by their `blk*.dat` positions (see `add_positions`), so no local
+/// block files are needed.
+pub(crate) struct RestBlockSource {
+    addr: SocketAddr,
+    timeout: Duration,
+    positions: Mutex<BTreeMap<FilePosition, BlockHash>>, // of the blocks' first bytes
+    blocks: Mutex<Lru<BlockHash, Arc<Vec<u8>>>>,
+}
+
+impl RestBlockSource {
+    pub(crate) fn new(addr: SocketAddr, timeout: Duration) -> Self {
+        Self {
+            addr,
+            timeout,
+            positions: Default::default(),
+            blocks: Mutex::new(Lru::new(BLOCK_CACHE_SIZE)),
+        }
+    }
+
+    /// Register the blocks' positions (as reported by `getblocklocations`).
+    pub(crate) fn add_positions(
+        &self,
+        positions: impl IntoIterator<Item = (FilePosition, BlockHash)>,
+    ) {
+        self.positions.lock().extend(positions);
+    }
+
+    pub(crate) fn get_block(&self, blockhash: BlockHash) -> Result<Block> {
+        let data = self.get_block_bytes(blockhash)?;
+        deserialize(&data)
+            .with_context(|| format!("failed to decode block {} from REST", blockhash))
+    }
+
+    /// Return the position and contents of the block containing `pos`.
+    fn block_at(&self, pos: FilePosition) -> Result<(FilePosition, Arc<Vec<u8>>)> {
+        let (start, blockhash) = self
+            .positions
+            .lock()
+            .range(..=pos)
+            .next_back()
+            .map(|(start, blockhash)| (*start, *blockhash))
+            .filter(|(start, _)| start.file_id == pos.file_id)
+            .with_context(|| format!("no known block at {:?}", pos))?;
+        let cached = self.blocks.lock().get(&blockhash).cloned();
+        let data = match cached {
+            Some(data) => data,
+            None => {
+                let data = Arc::new(self.get_block_bytes(blockhash)?);
+                self.blocks.lock().insert(blockhash, Arc::clone(&data));
+                data
+            }
+        };
+        ensure!(
+            ((pos.offset - start.offset) as usize) < data.len(),
+            "no known block at {:?}",
+            pos
+        );
+        Ok((start, data))
+    }
+
+    fn get_block_bytes(&self, blockhash: BlockHash) -> Result<Vec<u8>> {
+        let path = format!("/rest/block/{}.bin", blockhash);
+        let (status, body) = self
+            .get(&path)
+            .with_context(|| format!("REST request failed: http://{}{}", self.addr, path))?;
+        match status {
+            200 => (),
+            // unknown paths get an empty response (unknown blocks are reported in the body)
+            404 if body.is_empty() => bail!("bitcoind REST is disabled (run it with rest=1)"),
+            _ => bail!(
+                "failed to get block {} via REST: HTTP {} ({})",
+                blockhash,
+                status,
+                String::from_utf8_lossy(&body).trim()
+            ),
+        }
+        let header = BlockHeader::consensus_decode(&body[..])
+            .with_context(|| format!("failed to decode block {} from REST", blockhash))?;
+        ensure!(
+            header.block_hash() == blockhash,
+            "REST returned block {} instead of {}",
+            header.block_hash(),
+            blockhash
+        );
+        Ok(body)
+    }
+
+    fn get(&self, path: &str) -> Result<(u16, Vec<u8>)> {
+        let mut stream = TcpStream::connect_timeout(&self.addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, self.addr
+        );
+        stream.write_all(request.as_bytes())?;
+        stream.flush()?;
+
+        read_response(stream)
+    }
+}
+
+impl BlockSource for RestBlockSource {
+    fn open(&self, pos: FilePosition) -> Result<Box<dyn BlockFile>> {
+        let (start, data) = self.block_at(pos)?;
+        let mut block = PrefetchedBlock::new(data.to_vec(), u64::from(start.offset));
+        block.seek(SeekFrom::Start(u64::from(pos.offset)))?;
+        Ok(Box::new(block))
+    }
+
+    fn open_undo(&self, _pos: FilePosition) -> Result<Box<dyn Read + Send>> {
+        bail!("block undo data is not available via REST")
+    }
+
+    fn read_block(&self, pos: FilePosition) -> Result<Box<dyn BlockFile>> {
+        let (start, data) = self.block_at(pos)?;
+        ensure!(start == pos, "no known block starting at {:?}", pos);
+        Ok(Box::new(PrefetchedBlock::new(
+            data.to_vec(),
+            u64::from(start.offset),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RestBlockSource;
+    use crate::{daemon::BlockSource, types::FilePosition};
+    use bitcoin::{blockdata::constants::genesis_block, consensus::serialize, Network};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    // Serve a single HTTP response
+    fn serve(status: &str, body: Vec<u8>) -> RestBlockSource {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let status = status.to_owned();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear(); // skip request headers
+            }
+            let mut stream = reader.into_inner();
+            let header = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n",
+                status,
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).unwrap();
+            stream.write_all(&body).unwrap();
+        });
+        RestBlockSource::new(addr, Duration::from_secs(10))
+    }
+
+    #[test]
+    fn test_get_block() {
+        let block = genesis_block(Network::Regtest);
+        let blockhash = block.block_hash();
+
+        let rest = serve("200 OK", serialize(&block));
+        assert_eq!(rest.get_block(blockhash).unwrap(), block);
+
+        let rest = serve("200 OK", serialize(&genesis_block(Network::Bitcoin)));
+        let err = rest.get_block(blockhash).unwrap_err().to_string();
+        assert!(err.starts_with("REST returned block"), "{}", err);
+
+        let rest = serve("404 Not Found", vec![]);
+        let err = rest.get_block(blockhash).unwrap_err().to_string();
+        assert_eq!(err, "bitcoind REST is disabled (run it with rest=1)");
+
+        let body = format!("{} not found\r\n", blockhash).into_bytes();
+        let rest = serve("404 Not Found", body);
+        let err = rest.get_block(blockhash).unwrap_err().to_string();
+        assert!(err.ends_with(&format!("HTTP 404 ({} not found)", blockhash)));
+    }
+
+    #[test]
+    fn test_block_source() {
+        let block = genesis_block(Network::Regtest);
+        let block_bytes = serialize(&block);
+        let pos = FilePosition {
+            file_id: 0,
+            offset: 8,
+        };
+
+        // the block is fetched once, and then read from the cache
+        let rest = serve("200 OK", block_bytes.clone());
+        rest.add_positions(std::iter::once((pos, block.block_hash())));
+        let mut buf = vec![];
+        rest.read_block(pos).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, block_bytes);
+        let txs = pos.with_offset(pos.offset + 80);
+        assert_eq!(rest.read_range(txs, 4).unwrap(), &block_bytes[80..84]);
+
+        let end = pos.with_offset(pos.offset + block_bytes.len() as u32);
+        for unknown in &[pos.with_offset(4), end, FilePosition { file_id: 1, ..pos }] {
+            let err = rest.open(*unknown).map(|_| ()).unwrap_err();
+            assert_eq!(err.to_string(), format!("no known block at {:?}", unknown));
+        }
+        let err = rest.read_block(txs).map(|_| ()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("no known block starting at {:?}", txs)
+        );
+        assert!(rest.open_undo(pos).is_err());
+    }
+}
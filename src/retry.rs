@@ -0,0 +1,99 @@
+use anyhow::{Context, Result};
+use bitcoin::secp256k1::rand::{thread_rng, Rng};
+
+use std::time::Duration;
+
+use crate::signals::ExitFlag;
+
+/// Exponential backoff policy, shared by bitcoind RPC and p2p connection retries.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    pub max_attempts: usize,
+    pub jitter: f64, // maximal relative change of the delay (0 - no jitter)
+}
+
+impl RetryPolicy {
+    /// Delay before retrying after the `attempt`-th failure (starting from 1), without jitter.
+    pub(crate) fn backoff(&self, attempt: usize) -> Duration {
+        let exponent = std::cmp::min(attempt.saturating_sub(1), 1024) as i32;
+        let delay = self.base_delay.as_secs_f64() * self.multiplier.powi(exponent);
+        Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+    }
+
+    /// Delay before retrying after the `attempt`-th failure (starting from 1).
+    pub(crate) fn delay(&self, attempt: usize) -> Duration {
+        let delay = self.backoff(attempt);
+        if self.jitter <= 0.0 {
+            return delay;
+        }
+        let factor = 1.0 + self.jitter * (2.0 * thread_rng().gen::<f64>() - 1.0);
+        delay.mul_f64(factor.max(0.0))
+    }
+
+    /// Run `f` until it succeeds, fails with a non-retryable error, or `max_attempts` are made.
+    pub(crate) fn retry<T>(
+        &self,
+        exit_flag: &ExitFlag,
+        what: &str,
+        is_retryable: impl Fn(&anyhow::Error) -> bool,
+        mut f: impl FnMut() -> Result<T>,
+    ) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            exit_flag
+                .poll()
+                .with_context(|| format!("{} interrupted", what))?;
+            match f() {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < self.max_attempts && is_retryable(&e) => {
+                    let delay = self.delay(attempt);
+                    warn!(
+                        "{} failed (attempt {}/{}), retrying in {:?}: {:#}",
+                        what, attempt, self.max_attempts, delay, e
+                    );
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+    use std::time::Duration;
+
+    fn policy(jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_attempts: 5,
+            jitter,
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = policy(0.0);
+        let delays: Vec<u128> = (1..=6).map(|i| policy.delay(i).as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(policy.delay(usize::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let policy = policy(0.5);
+        for attempt in 1..=6 {
+            let backoff = policy.backoff(attempt);
+            let delay = policy.delay(attempt);
+            assert!(delay >= backoff.mul_f64(0.5));
+            assert!(delay <= backoff.mul_f64(1.5));
+        }
+    }
+}
@@ -0,0 +1,439 @@
+use bitcoin::{
+    blockdata::script::Instruction, hashes::hex::ToHex, util::address::Payload, Address, Network,
+    PublicKey, Script, Transaction, TxIn, TxOut,
+};
+
+use std::fmt;
+use std::str::FromStr;
+
+/// scriptPubKey type (used for blocks' statistics)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OutputType {
+    P2pk,
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    OpReturn,
+    Unknown, // non-standard or unsupported
+}
+
+impl OutputType {
+    pub(crate) fn classify(script: &Script) -> Self {
+        if script.is_p2pkh() {
+            OutputType::P2pkh
+        } else if script.is_p2sh() {
+            OutputType::P2sh
+        } else if script.is_v0_p2wpkh() {
+            OutputType::P2wpkh
+        } else if script.is_v0_p2wsh() {
+            OutputType::P2wsh
+        } else if is_p2tr(script) {
+            OutputType::P2tr
+        } else if script.is_p2pk() {
+            OutputType::P2pk
+        } else if script.is_op_return() {
+            OutputType::OpReturn
+        } else {
+            OutputType::Unknown
+        }
+    }
+}
+
+/// Encode `script` as addresses for `network` (empty for non-standard scripts).
+/// P2PK outputs are also shown as the P2PKH address of their public key (similar to older bitcoind
+/// versions). A P2SH address may wrap a segwit program, but it's unknown until the output is spent.
+pub(crate) fn scriptpubkey_to_addresses(script: &Script, network: Network) -> Vec<String> {
+    if script.is_p2pk() {
+        let bytes = script.as_bytes();
+        return match PublicKey::from_slice(&bytes[1..bytes.len() - 1]) {
+            Ok(pubkey) => vec![Address::p2pkh(&pubkey, network).to_string()],
+            Err(_) => vec![], // invalid public key
+        };
+    }
+    Address::from_script(script, network)
+        .map(|address| address.to_string())
+        .into_iter()
+        .collect()
+}
+
+/// Render `script` as a space-separated list of opcodes and pushed data, following bitcoind's `asm`
+/// conventions: pushes of up to 4 bytes are shown as (possibly non-minimal) numbers, longer ones in
+/// hex, and an invalid script ends with "[error]". Signature hash types are not decoded.
+pub(crate) fn script_to_asm(script: &Script) -> String {
+    let mut parts = vec![];
+    for instruction in script.instructions() {
+        let instruction = match instruction {
+            Ok(instruction) => instruction,
+            Err(_) => {
+                parts.push("[error]".to_owned());
+                break;
+            }
+        };
+        parts.push(match instruction {
+            Instruction::PushBytes(data) if data.len() <= 4 => script_num(data).to_string(),
+            Instruction::PushBytes(data) => data.to_hex(),
+            Instruction::Op(op) => match op.into_u8() {
+                0x4f => "-1".to_owned(),
+                code @ 0x51..=0x60 => (code - 0x50).to_string(),
+                0xb1 => "OP_CHECKLOCKTIMEVERIFY".to_owned(),
+                0xb2 => "OP_CHECKSEQUENCEVERIFY".to_owned(),
+                0xba => "OP_CHECKSIGADD".to_owned(),
+                0xbb..=0xfe => "OP_UNKNOWN".to_owned(),
+                0xff => "OP_INVALIDOPCODE".to_owned(),
+                _ => format!("{:?}", op),
+            },
+        });
+    }
+    parts.join(" ")
+}
+
+// Little-endian, sign-magnitude encoded number (without requiring minimal encoding)
+fn script_num(data: &[u8]) -> i64 {
+    let value = data
+        .iter()
+        .rev()
+        .fold(0i64, |value, byte| (value << 8) | i64::from(*byte));
+    match data.last() {
+        Some(last) if last & 0x80 != 0 => -(value & !(0x80 << (8 * (data.len() - 1)))),
+        _ => value,
+    }
+}
+
+/// Legacy (and P2SH) signature operations cost 4 times more than witness ones (see BIP-141)
+const WITNESS_SCALE_FACTOR: usize = 4;
+
+/// Signature operations' cost of a transaction, limited by consensus to 80,000 per block (like
+/// bitcoind's `GetTransactionSigOpCost()`, assuming P2SH and segwit are active). `prevouts` are the
+/// outputs spent by its inputs (in the same order), and are ignored for a coinbase transaction.
+pub(crate) fn tx_sigop_cost(tx: &Transaction, prevouts: &[TxOut]) -> usize {
+    let legacy = tx
+        .input
+        .iter()
+        .map(|txin| &txin.script_sig)
+        .chain(tx.output.iter().map(|txout| &txout.script_pubkey))
+        .map(|script| sigop_count(script, false))
+        .sum::<usize>();
+    let mut cost = legacy * WITNESS_SCALE_FACTOR;
+    if tx.is_coin_base() {
+        return cost;
+    }
+    for (txin, prevout) in tx.input.iter().zip(prevouts) {
+        let script_pubkey = &prevout.script_pubkey;
+        if script_pubkey.is_p2sh() {
+            if let Some(redeem_script) = last_push(&txin.script_sig) {
+                cost += sigop_count(&redeem_script, true) * WITNESS_SCALE_FACTOR;
+            }
+        }
+        cost += witness_sigop_count(txin, script_pubkey);
+    }
+    cost
+}
+
+// Like bitcoind's `CScript::GetSigOpCount()`: a multisig is counted as 20 sigops, unless `accurate`
+// is set and its number of keys is pushed by the previous opcode (as done for P2SH and P2WSH).
+fn sigop_count(script: &Script, accurate: bool) -> usize {
+    let mut count = 0;
+    let mut last_op = None;
+    for instruction in script.instructions() {
+        let op = match instruction {
+            Ok(Instruction::Op(op)) => op.into_u8(),
+            Ok(Instruction::PushBytes(_)) => {
+                last_op = None;
+                continue;
+            }
+            Err(_) => break, // sigops are counted until the first invalid opcode
+        };
+        match op {
+            0xac | 0xad => count += 1, // OP_CHECKSIG(VERIFY)
+            0xae | 0xaf => {
+                // OP_CHECKMULTISIG(VERIFY)
+                count += match (accurate, last_op) {
+                    (true, Some(keys @ 0x51..=0x60)) => usize::from(keys - 0x50), // OP_1..OP_16
+                    _ => 20,
+                }
+            }
+            _ => (),
+        }
+        last_op = Some(op);
+    }
+    count
+}
+
+// The data pushed last by a push-only `script_sig` (e.g. a P2SH redeem script), like bitcoind's
+// `IsPushOnly()` - so OP_1NEGATE, OP_RESERVED and OP_1..OP_16 are also treated as (empty) pushes.
+fn last_push(script_sig: &Script) -> Option<Script> {
+    let mut data: &[u8] = &[];
+    for instruction in script_sig.instructions() {
+        match instruction.ok()? {
+            Instruction::PushBytes(bytes) => data = bytes,
+            Instruction::Op(op) if op.into_u8() <= 0x60 => data = &[],
+            Instruction::Op(_) => return None,
+        }
+    }
+    Some(Script::from(data.to_vec()))
+}
+
+// Native or P2SH-wrapped witness programs' sigops (only segwit v0 programs have sigops)
+fn witness_sigop_count(txin: &TxIn, script_pubkey: &Script) -> usize {
+    let program = if script_pubkey.is_witness_program() {
+        script_pubkey.clone()
+    } else if script_pubkey.is_p2sh() {
+        match last_push(&txin.script_sig) {
+            Some(redeem_script) if redeem_script.is_witness_program() => redeem_script,
+            _ => return 0,
+        }
+    } else {
+        return 0;
+    };
+    if program.as_bytes()[0] != 0x00 {
+        return 0; // e.g. taproot spends are limited by their witness size instead
+    }
+    match program.len() - 2 {
+        20 => 1, // P2WPKH
+        32 => txin.witness.last().map_or(0, |witness_script| {
+            sigop_count(&Script::from(witness_script.clone()), true)
+        }),
+        _ => 0,
+    }
+}
+
+/// A valid address for the configured network
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct AddressInfo {
+    pub address: String,
+    #[serde(rename = "scriptpubkey")]
+    pub script_pubkey: String, // hex-encoded
+    #[serde(rename = "type")]
+    pub output_type: OutputType,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum AddressError {
+    Invalid(String),
+    WrongNetwork { expected: Network, actual: Network },
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressError::Invalid(e) => write!(f, "invalid address: {}", e),
+            AddressError::WrongNetwork { expected, actual } => {
+                write!(f, "{} address is not valid for {}", actual, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+/// Parse `addr`, checking that it's valid for `network`.
+pub(crate) fn validate_address(addr: &str, network: Network) -> Result<AddressInfo, AddressError> {
+    let address = Address::from_str(addr).map_err(|e| AddressError::Invalid(e.to_string()))?;
+    if !is_valid_for_network(&address, network) {
+        return Err(AddressError::WrongNetwork {
+            expected: network,
+            actual: address.network,
+        });
+    }
+    let script_pubkey = address.script_pubkey();
+    Ok(AddressInfo {
+        address: address.to_string(),
+        script_pubkey: script_pubkey.to_hex(),
+        output_type: OutputType::classify(&script_pubkey),
+    })
+}
+
+// Testnet, signet and regtest share their base58 prefixes (and testnet and signet share their
+// bech32 prefix), so such addresses are parsed as testnet ones.
+fn is_valid_for_network(address: &Address, network: Network) -> bool {
+    if address.network == network {
+        return true;
+    }
+    match (address.network, network) {
+        (Network::Testnet, Network::Signet) => true,
+        (Network::Testnet, Network::Regtest) => match address.payload {
+            Payload::PubkeyHash(_) | Payload::ScriptHash(_) => true,
+            Payload::WitnessProgram { .. } => false, // regtest uses "bcrt" bech32 prefix
+        },
+        _ => false,
+    }
+}
+
+/// Segwit v1 output with a 32-byte witness program (see BIP-341)
+fn is_p2tr(script: &Script) -> bool {
+    let bytes = script.as_bytes();
+    bytes.len() == 34 && bytes[0] == 0x51 && bytes[1] == 0x20 // OP_1 OP_PUSHBYTES_32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        script_to_asm, scriptpubkey_to_addresses, tx_sigop_cost, validate_address, AddressError,
+        OutputType,
+    };
+    use bitcoin::{hashes::hex::FromHex, Network, OutPoint, Script, Transaction, TxIn, TxOut};
+
+    #[test]
+    fn test_classify() {
+        let classify = |hex: &str| OutputType::classify(&Script::from(Vec::from_hex(hex).unwrap()));
+        let hash20 = "00112233445566778899aabbccddeeff00112233";
+        let hash32 = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+        assert_eq!(
+            classify(&format!("76a914{}88ac", hash20)),
+            OutputType::P2pkh
+        );
+        assert_eq!(classify(&format!("a914{}87", hash20)), OutputType::P2sh);
+        assert_eq!(classify(&format!("0014{}", hash20)), OutputType::P2wpkh);
+        assert_eq!(classify(&format!("0020{}", hash32)), OutputType::P2wsh);
+        assert_eq!(classify(&format!("5120{}", hash32)), OutputType::P2tr);
+        assert_eq!(classify(&format!("21{}00ac", hash32)), OutputType::P2pk);
+        assert_eq!(classify("6a0401020304"), OutputType::OpReturn);
+        assert_eq!(classify("51"), OutputType::Unknown);
+        assert_eq!(classify(""), OutputType::Unknown);
+    }
+
+    #[test]
+    fn test_scriptpubkey_to_addresses() {
+        let addresses = |hex: &str, network| {
+            scriptpubkey_to_addresses(&Script::from(Vec::from_hex(hex).unwrap()), network)
+        };
+        // genesis block coinbase output
+        let p2pk = "4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac";
+        assert_eq!(
+            addresses(p2pk, Network::Bitcoin),
+            vec!["1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"]
+        );
+        let hash20 = "00112233445566778899aabbccddeeff00112233";
+        assert_eq!(
+            addresses(&format!("76a914{}88ac", hash20), Network::Bitcoin),
+            vec!["11MXTrefsj1ZS3Q5e9D6DxGzZKHWALyo9"]
+        );
+        assert_eq!(
+            addresses(&format!("0014{}", hash20), Network::Testnet),
+            vec!["tb1qqqgjyv6y24n80zye42aueh0wluqpzg3n8z32vr"]
+        );
+        let hash32 = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+        assert!(addresses(&format!("21{}00ac", hash32), Network::Bitcoin).is_empty()); // invalid key
+        assert!(addresses("6a0401020304", Network::Bitcoin).is_empty());
+        assert!(addresses("51", Network::Bitcoin).is_empty());
+    }
+
+    #[test]
+    fn test_validate_address() {
+        let p2pkh = "11MXTrefsj1ZS3Q5e9D6DxGzZKHWALyo9";
+        let info = validate_address(p2pkh, Network::Bitcoin).unwrap();
+        assert_eq!(info.address, p2pkh);
+        assert_eq!(
+            info.script_pubkey,
+            "76a91400112233445566778899aabbccddeeff0011223388ac"
+        );
+        assert_eq!(info.output_type, OutputType::P2pkh);
+        assert_eq!(
+            validate_address(p2pkh, Network::Testnet),
+            Err(AddressError::WrongNetwork {
+                expected: Network::Testnet,
+                actual: Network::Bitcoin
+            })
+        );
+
+        let testnet_p2pkh = "mfXJpWwdUuAGLYX1oD7av9AbrYuzUZSGHb";
+        assert!(validate_address(testnet_p2pkh, Network::Testnet).is_ok());
+        assert!(validate_address(testnet_p2pkh, Network::Signet).is_ok());
+        assert!(validate_address(testnet_p2pkh, Network::Regtest).is_ok());
+        assert!(validate_address(testnet_p2pkh, Network::Bitcoin).is_err());
+
+        let testnet_p2wpkh = "tb1qqqgjyv6y24n80zye42aueh0wluqpzg3n8z32vr";
+        let info = validate_address(testnet_p2wpkh, Network::Signet).unwrap();
+        assert_eq!(info.output_type, OutputType::P2wpkh);
+        assert!(validate_address(testnet_p2wpkh, Network::Regtest).is_err());
+
+        assert!(matches!(
+            validate_address("11MXTrefsj1ZS3Q5e9D6DxGzZKHWALyo8", Network::Bitcoin), // bad checksum
+            Err(AddressError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_script_to_asm() {
+        let asm = |hex: &str| script_to_asm(&Script::from(Vec::from_hex(hex).unwrap()));
+        assert_eq!(
+            asm("76a91400112233445566778899aabbccddeeff0011223388ac"),
+            "OP_DUP OP_HASH160 00112233445566778899aabbccddeeff00112233 OP_EQUALVERIFY OP_CHECKSIG"
+        );
+        assert_eq!(asm(""), "");
+        assert_eq!(asm("00"), "0");
+        assert_eq!(asm("4f5160"), "-1 1 16");
+        assert_eq!(asm("6a04ffffff7f0181028000"), "OP_RETURN 2147483647 -1 128");
+        assert_eq!(asm("6a050011223344"), "OP_RETURN 0011223344");
+        assert_eq!(asm("4c01024d010003"), "2 3"); // non-minimal pushes
+        assert_eq!(
+            asm("b1b2babbff"),
+            "OP_CHECKLOCKTIMEVERIFY OP_CHECKSEQUENCEVERIFY OP_CHECKSIGADD OP_UNKNOWN OP_INVALIDOPCODE"
+        );
+        assert_eq!(asm("5103aabb"), "1 [error]"); // truncated push
+    }
+
+    #[test]
+    fn test_tx_sigop_cost() {
+        let script = |hex: &str| Script::from(Vec::from_hex(hex).unwrap());
+        let spend = |script_sig: Script, witness: Vec<Vec<u8>>| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Default::default(), 0), // not a coinbase
+                script_sig,
+                sequence: 0xFFFFFFFF,
+                witness,
+            }],
+            output: vec![],
+        };
+        let prevout = |script_pubkey: Script| TxOut {
+            value: 1000,
+            script_pubkey,
+        };
+        let hash20 = "00112233445566778899aabbccddeeff00112233";
+        let hash32 = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+        let p2sh = script(&format!("a914{}87", hash20));
+        // OP_2 <key> <key> <key> OP_3 OP_CHECKMULTISIG (with dummy 1-byte keys)
+        let multisig = Vec::from_hex("5201010102010353ae").unwrap();
+
+        // legacy outputs are counted (multisig as 20 sigops), but not the spent P2PKH output
+        let mut tx = spend(Script::new(), vec![]);
+        tx.output = vec![
+            prevout(script(&format!("76a914{}88ac", hash20))),
+            prevout(Script::from(multisig.clone())),
+        ];
+        let p2pkh = prevout(script(&format!("76a914{}88ac", hash20)));
+        assert_eq!(tx_sigop_cost(&tx, &[p2pkh]), (1 + 20) * 4);
+
+        // P2SH multisig is counted accurately, using its redeem script
+        let mut script_sig = vec![0x00, multisig.len() as u8];
+        script_sig.extend(&multisig);
+        let tx = spend(Script::from(script_sig), vec![]);
+        assert_eq!(tx_sigop_cost(&tx, &[prevout(p2sh.clone())]), 3 * 4);
+
+        // non-push-only scriptSig has no P2SH sigops (only the legacy OP_CHECKSIG)
+        let tx = spend(script("0101ac"), vec![]);
+        assert_eq!(tx_sigop_cost(&tx, &[prevout(p2sh.clone())]), 4);
+
+        // witness sigops are not scaled
+        let p2wpkh = prevout(script(&format!("0014{}", hash20)));
+        let tx = spend(Script::new(), vec![vec![0x30], vec![0x02]]);
+        assert_eq!(tx_sigop_cost(&tx, &[p2wpkh]), 1);
+        let p2wsh = prevout(script(&format!("0020{}", hash32)));
+        let tx = spend(Script::new(), vec![vec![], multisig.clone()]);
+        assert_eq!(tx_sigop_cost(&tx, &[p2wsh]), 3);
+
+        // P2SH-wrapped P2WPKH
+        let tx = spend(script(&format!("160014{}", hash20)), vec![vec![0x30]]);
+        assert_eq!(tx_sigop_cost(&tx, &[prevout(p2sh)]), 1);
+
+        // taproot spends have no sigops
+        let p2tr = prevout(script(&format!("5120{}", hash32)));
+        let tx = spend(Script::new(), vec![multisig]);
+        assert_eq!(tx_sigop_cost(&tx, &[p2tr]), 0);
+    }
+}
@@ -6,7 +6,7 @@ use std::{
     collections::hash_map::HashMap,
     io::{BufRead, BufReader, Write},
     iter::once,
-    net::{Shutdown, TcpListener, TcpStream},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, TcpListener, TcpStream},
 };
 
 use crate::{
@@ -25,7 +25,8 @@ struct Peer {
 
 impl Peer {
     fn new(id: usize, stream: TcpStream) -> Self {
-        let client = Client::default();
+        let tag = stream.peer_addr().ok().map(|addr| subnet(addr.ip()).into());
+        let client = Client::new(tag);
         Self { id, client, stream }
     }
 
@@ -105,7 +106,10 @@ fn serve() -> Result<()> {
                 // Handle signals for graceful shutdown
                 recv(rpc.signal().receiver()) -> result => {
                     result.context("signal channel disconnected")?;
-                    rpc.signal().exit_flag().poll().context("RPC server interrupted")?;
+                    if let Err(e) = rpc.signal().exit_flag().poll() {
+                        rpc.shutdown(); // close bitcoind connections before exiting
+                        return Err(e).context("RPC server interrupted");
+                    }
                 },
                 // Handle new blocks' notifications
                 recv(new_block_rx) -> result => match result {
@@ -209,6 +213,20 @@ fn handle_peer_events(
     }
 }
 
+/// Group clients by their subnet (IPv4 /24 or IPv6 /48), for accounting bitcoind RPCs.
+fn subnet(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}/24", Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            format!("{}/48", Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+        }
+    }
+}
+
 fn accept_loop(listener: TcpListener, server_tx: Sender<Event>) -> Result<()> {
     for (peer_id, conn) in listener.incoming().enumerate() {
         let stream = conn.context("failed to accept")?;
@@ -24,6 +24,7 @@ struct TxEntry {
     txid: Txid,
     outputs: Vec<TxOutput>, // relevant funded outputs and their amounts
     spent: Vec<OutPoint>,   // relevant spent outpoints
+    is_coinbase: bool,      // funded outputs are spendable only after coinbase maturity
 }
 
 struct TxOutput {
@@ -37,6 +38,7 @@ impl TxEntry {
             txid,
             outputs: Vec::new(),
             spent: Vec::new(),
+            is_coinbase: false,
         }
     }
 
@@ -83,6 +85,10 @@ impl std::fmt::Display for Height {
     }
 }
 
+/// Position of a confirmed history entry: its block height, and its index among the scripthash's
+/// transactions in that block (used for paginating the history).
+pub(crate) type HistoryCursor = (usize, usize);
+
 // A single history entry:
 // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-history
 // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-mempool
@@ -142,6 +148,15 @@ pub(crate) struct Balance {
     mempool_delta: SignedAmount,
 }
 
+impl Balance {
+    pub(crate) fn new(confirmed_balance: Amount, mempool_delta: SignedAmount) -> Self {
+        Self {
+            confirmed_balance,
+            mempool_delta,
+        }
+    }
+}
+
 // A single unspent transaction output entry:
 // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-listunspent
 #[derive(Serialize)]
@@ -151,12 +166,21 @@ pub(crate) struct UnspentEntry {
     tx_pos: u32,
     #[serde(with = "bitcoin::util::amount::serde::as_sat")]
     value: Amount,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maturity: Option<usize>, // blocks until a coinbase output is spendable (if annotation is enabled)
+}
+
+/// Number of blocks to be mined, until a coinbase output confirmed at `height` can be spent
+/// (using the current `tip_height` and `window` blocks of coinbase maturity).
+fn remaining_maturity(height: usize, tip_height: usize, window: usize) -> usize {
+    let confirmations = (tip_height + 1).saturating_sub(height);
+    window.saturating_sub(confirmations)
 }
 
 #[derive(Default)]
 struct Unspent {
-    // mapping an outpoint to its value & confirmation height
-    outpoints: HashMap<OutPoint, (Amount, usize)>,
+    // mapping an outpoint to its value, confirmation height & whether it is a coinbase output
+    outpoints: HashMap<OutPoint, (Amount, usize, bool)>,
     confirmed_balance: Amount,
     mempool_delta: SignedAmount,
 }
@@ -183,14 +207,24 @@ impl Unspent {
         unspent
     }
 
-    fn into_entries(self) -> Vec<UnspentEntry> {
+    fn into_entries(
+        self,
+        tip_height: usize,
+        coinbase_maturity: Option<usize>,
+    ) -> Vec<UnspentEntry> {
         self.outpoints
             .into_iter()
-            .map(|(outpoint, (value, height))| UnspentEntry {
+            .map(|(outpoint, (value, height, is_coinbase))| UnspentEntry {
                 height,
                 tx_hash: outpoint.txid,
                 tx_pos: outpoint.vout,
                 value,
+                maturity: match coinbase_maturity {
+                    Some(window) if is_coinbase => {
+                        Some(remaining_maturity(height, tip_height, window))
+                    }
+                    _ => None,
+                },
             })
             .collect()
     }
@@ -207,7 +241,8 @@ impl Unspent {
                 txid: entry.txid,
                 vout: output.index,
             };
-            self.outpoints.insert(outpoint, (output.value, height));
+            self.outpoints
+                .insert(outpoint, (output.value, height, entry.is_coinbase));
         }
     }
 
@@ -260,8 +295,13 @@ impl ScriptHashStatus {
             .collect()
     }
 
-    pub(crate) fn get_unspent(&self, chain: &Chain) -> Vec<UnspentEntry> {
-        Unspent::build(self, chain).into_entries()
+    /// Annotate coinbase outputs with their remaining maturity, if `coinbase_maturity` is set.
+    pub(crate) fn get_unspent(
+        &self,
+        chain: &Chain,
+        coinbase_maturity: Option<usize>,
+    ) -> Vec<UnspentEntry> {
+        Unspent::build(self, chain).into_entries(chain.height(), coinbase_maturity)
     }
 
     pub(crate) fn get_balance(&self, chain: &Chain) -> Balance {
@@ -276,6 +316,32 @@ impl ScriptHashStatus {
         &self.history
     }
 
+    /// Get up to `limit` confirmed history entries following `cursor` (or from the first one), and
+    /// the cursor of the last returned entry (None if no more entries follow it). Since the history
+    /// is appended at the tip, confirmed pages are stable (unless their blocks are reorged).
+    pub(crate) fn get_history_page(
+        &self,
+        cursor: Option<HistoryCursor>,
+        limit: usize,
+    ) -> (Vec<&HistoryEntry>, Option<HistoryCursor>) {
+        let mut entries = history_cursors(&self.history)
+            .skip_while(|(pos, _)| cursor.map_or(false, |cursor| *pos <= cursor));
+        let page: Vec<(HistoryCursor, &HistoryEntry)> = entries.by_ref().take(limit).collect();
+        let next = match entries.next() {
+            Some(_) => page.last().map(|(pos, _)| *pos),
+            None => None,
+        };
+        (page.into_iter().map(|(_, entry)| entry).collect(), next)
+    }
+
+    /// Get the mempool history entries (following the confirmed ones).
+    pub(crate) fn get_unconfirmed_history(&self) -> Vec<&HistoryEntry> {
+        self.history
+            .iter()
+            .filter(|entry| matches!(entry.height, Height::Unconfirmed { .. }))
+            .collect()
+    }
+
     /// Collect all confirmed history entries (in block order).
     fn get_confirmed_history(&self, chain: &Chain) -> Vec<HistoryEntry> {
         self.confirmed_height_entries(chain)
@@ -367,6 +433,12 @@ impl ScriptHashStatus {
         let mut result = HashMap::<BlockHash, HashMap<u32, TxEntry>>::new();
 
         let funding_positions = index.limit_result(index.filter_by_funding(scripthash))?;
+        // skip blocks not funding this scripthash (due to funding rows' prefix collisions)
+        let funding_positions = funding_positions.into_iter().filter(|pos| {
+            chain
+                .get_header_row_for(*pos)
+                .map_or(true, |row| index.block_may_contain(row.hash, scripthash))
+        });
         self.for_new_blocks(funding_positions, chain, daemon, |blockhash, txs| {
             let block_entries = result.entry(blockhash).or_default();
             filter_txs(txs, |tx| filter_outputs(tx, scripthash)).for_each(
@@ -376,12 +448,14 @@ impl ScriptHashStatus {
                      txid,
                      result: funding_outputs,
                  }| {
+                    let is_coinbase = tx.is_coin_base();
                     cache.add_tx(txid, move || tx);
                     outpoints.extend(make_outpoints(txid, &funding_outputs));
-                    block_entries
+                    let entry = block_entries
                         .entry(offset)
-                        .or_insert_with(|| TxEntry::new(txid))
-                        .outputs = funding_outputs;
+                        .or_insert_with(|| TxEntry::new(txid));
+                    entry.outputs = funding_outputs;
+                    entry.is_coinbase = is_coinbase;
                 },
             );
         })?;
@@ -534,6 +608,25 @@ fn filter_inputs(tx: &Transaction, outpoints: &HashSet<OutPoint>) -> Vec<OutPoin
         .collect()
 }
 
+/// Annotate the confirmed `history` entries (in block order) with their cursors.
+fn history_cursors(
+    history: &[HistoryEntry],
+) -> impl Iterator<Item = (HistoryCursor, &HistoryEntry)> {
+    let mut last: Option<HistoryCursor> = None;
+    history.iter().filter_map(move |entry| {
+        let height = match entry.height {
+            Height::Confirmed { height } => height,
+            Height::Unconfirmed { .. } => return None,
+        };
+        let txpos = match last {
+            Some((last_height, last_txpos)) if last_height == height => last_txpos + 1,
+            _ => 0,
+        };
+        last = Some((height, txpos));
+        Some(((height, txpos), entry))
+    })
+}
+
 fn compute_status_hash(history: &[HistoryEntry]) -> Option<StatusHash> {
     if history.is_empty() {
         return None;
@@ -576,8 +669,12 @@ fn filter_txs<T: Send>(
 
 #[cfg(test)]
 mod tests {
-    use super::HistoryEntry;
-    use bitcoin::{hashes::hex::FromHex, Amount, Txid};
+    use super::{remaining_maturity, HistoryEntry, ScriptHashStatus};
+    use crate::types::ScriptHash;
+    use bitcoin::{
+        hashes::{hex::FromHex, Hash},
+        Amount, Script, Txid,
+    };
     use serde_json::json;
 
     #[test]
@@ -602,4 +699,48 @@ mod tests {
             json!({"tx_hash": "5b75086dafeede555fc8f9a810d8b10df57c46f9f176ccc3dd8d2fa20edd685b", "height": 0, "fee": 123})
         );
     }
+
+    #[test]
+    fn test_history_page() {
+        let txid = |n: u8| Txid::from_inner([n; 32]);
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&Script::new()));
+        status.history = vec![
+            HistoryEntry::confirmed(txid(1), 100),
+            HistoryEntry::confirmed(txid(2), 100),
+            HistoryEntry::confirmed(txid(3), 105),
+            HistoryEntry::confirmed(txid(4), 107),
+            HistoryEntry::unconfirmed(txid(5), false, Amount::from_sat(123)),
+        ];
+        let txids = |entries: Vec<&HistoryEntry>| -> Vec<Txid> {
+            entries.into_iter().map(|entry| entry.txid).collect()
+        };
+
+        let (page, next) = status.get_history_page(None, 2);
+        assert_eq!(
+            (txids(page), next),
+            (vec![txid(1), txid(2)], Some((100, 1)))
+        );
+        let (page, next) = status.get_history_page(Some((100, 0)), 2);
+        assert_eq!(
+            (txids(page), next),
+            (vec![txid(2), txid(3)], Some((105, 0)))
+        );
+        let (page, next) = status.get_history_page(Some((105, 0)), 2);
+        assert_eq!((txids(page), next), (vec![txid(4)], None));
+        let (page, next) = status.get_history_page(Some((107, 0)), 2);
+        assert_eq!((txids(page), next), (vec![], None));
+        let (page, next) = status.get_history_page(None, 4);
+        assert_eq!(next, None);
+        assert_eq!(page.len(), 4);
+
+        assert_eq!(txids(status.get_unconfirmed_history()), vec![txid(5)]);
+    }
+
+    #[test]
+    fn test_remaining_maturity() {
+        assert_eq!(remaining_maturity(1000, 1000, 100), 99);
+        assert_eq!(remaining_maturity(1000, 1098, 100), 1);
+        assert_eq!(remaining_maturity(1000, 1099, 100), 0); // spendable in the next block
+        assert_eq!(remaining_maturity(1000, 2000, 100), 0);
+    }
 }
@@ -14,3 +14,27 @@ where
         })
         .expect("failed to spawn a thread")
 }
+
+/// Pin the current thread to the given CPU cores.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_cpu_affinity(cores: &[usize]) -> Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &core in cores {
+        ensure!(
+            core < libc::CPU_SETSIZE as usize,
+            "invalid CPU core: {}",
+            core
+        );
+        unsafe { libc::CPU_SET(core, &mut set) };
+    }
+    let size = std::mem::size_of::<libc::cpu_set_t>();
+    if unsafe { libc::sched_setaffinity(0, size, &set) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set_cpu_affinity(_cores: &[usize]) -> Result<()> {
+    bail!("CPU affinity is not supported on this platform")
+}
@@ -0,0 +1,92 @@
+use std::time::{Duration, Instant};
+
+/// Block reads' rate limits (allowing to reduce the disk IO impact on a co-located bitcoind)
+#[derive(Clone, Debug)]
+pub struct BlockReadThrottle {
+    pub max_blocks_per_sec: Option<f64>,
+    pub max_bytes_per_sec: Option<f64>,
+}
+
+impl BlockReadThrottle {
+    /// Minimal duration of reading a block of `size` bytes.
+    fn cost(&self, size: u64) -> Duration {
+        let by_blocks = self.max_blocks_per_sec.map_or(0.0, |rate| 1.0 / rate);
+        let by_bytes = self
+            .max_bytes_per_sec
+            .map_or(0.0, |rate| size as f64 / rate);
+        Duration::from_secs_f64(by_blocks.max(by_bytes))
+    }
+}
+
+/// Delays block reads, so their rate will not exceed the configured limits.
+pub(crate) struct RateLimiter {
+    throttle: BlockReadThrottle,
+    next: Option<Instant>, // when the next block read is allowed
+}
+
+impl RateLimiter {
+    pub(crate) fn new(throttle: BlockReadThrottle) -> Self {
+        Self {
+            throttle,
+            next: None,
+        }
+    }
+
+    /// Should be called after reading a block of `size` bytes (sleeping if needed).
+    pub(crate) fn wait(&mut self, size: u64) {
+        let delay = self.delay(Instant::now(), size);
+        if delay > Duration::from_secs(0) {
+            std::thread::sleep(delay);
+        }
+    }
+
+    fn delay(&mut self, now: Instant, size: u64) -> Duration {
+        // don't accumulate "credit" while idle (so bursts are not allowed)
+        let start = match self.next {
+            Some(next) if next > now => next,
+            _ => now,
+        };
+        let next = start + self.throttle.cost(size);
+        self.next = Some(next);
+        next - now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockReadThrottle, RateLimiter};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_cost() {
+        let throttle = BlockReadThrottle {
+            max_blocks_per_sec: Some(10.0),
+            max_bytes_per_sec: Some(1e6),
+        };
+        assert_eq!(throttle.cost(1000), Duration::from_millis(100));
+        assert_eq!(throttle.cost(500_000), Duration::from_millis(500));
+
+        let unlimited = BlockReadThrottle {
+            max_blocks_per_sec: None,
+            max_bytes_per_sec: None,
+        };
+        assert_eq!(unlimited.cost(1_000_000), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(BlockReadThrottle {
+            max_blocks_per_sec: Some(10.0),
+            max_bytes_per_sec: None,
+        });
+        let now = Instant::now();
+        assert_eq!(limiter.delay(now, 1), Duration::from_millis(100));
+        // the previous read is still "in progress"
+        assert_eq!(limiter.delay(now, 1), Duration::from_millis(200));
+        let later = now + Duration::from_millis(150);
+        assert_eq!(limiter.delay(later, 1), Duration::from_millis(150));
+        // idle time is not accumulated
+        let idle = now + Duration::from_secs(10);
+        assert_eq!(limiter.delay(idle, 1), Duration::from_millis(100));
+    }
+}
@@ -1,5 +1,14 @@
 use anyhow::{Context, Result};
-use bitcoin::{consensus::Decodable, BlockHash, Transaction, Txid};
+use bitcoin::{
+    blockdata::constants::genesis_block,
+    consensus::{serialize, Decodable},
+    BlockHash, Network, OutPoint, SignedAmount, Transaction, Txid,
+};
+use parking_lot::Mutex;
+use rayon::prelude::*;
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 
 use crate::{
     cache::Cache,
@@ -8,39 +17,73 @@ use crate::{
     daemon::Daemon,
     db::DBStore,
     index::Index,
+    lru::Lru,
     mempool::{FeeHistogram, Mempool},
     metrics::Metrics,
     signals::ExitFlag,
     status::{Balance, ScriptHashStatus, UnspentEntry},
+    types::{FilePosition, HeaderRow, ScriptHash},
 };
 
+/// Number of scripthashes whose first funding block is cached
+const FIRST_BLOCK_CACHE_SIZE: usize = 10_000;
+
 /// Electrum protocol subscriptions' tracker
 pub struct Tracker {
     index: Index,
     mempool: Mempool,
     ignore_mempool: bool,
+    mempool_only: bool,
+    coinbase_maturity: Option<usize>, // annotate unspent coinbase outputs, if set
+    first_blocks: Mutex<Lru<ScriptHash, BlockHash>>,
 }
 
 pub(crate) enum Error {
     NotReady,
+    MempoolOnly,
 }
 
 impl Tracker {
     pub fn new(config: &Config, daemon: &Daemon, metrics: &Metrics) -> Result<Self> {
-        let store = DBStore::open(&config.db_path, config.auto_reindex)?;
-        let chain = Chain::new(daemon.get_genesis()?);
+        let store = DBStore::open(
+            &config.db_path,
+            config.auto_reindex,
+            config.db_sync_mode,
+            config.index_balances,
+        )?;
+        let genesis = if config.mempool_only {
+            genesis_header_row(config.network) // block files are not available
+        } else {
+            daemon.get_genesis()?
+        };
+        let chain = Chain::new(genesis);
+        let index = Index::load(
+            store,
+            chain,
+            metrics,
+            config.index_batch_size,
+            config.index_lookup_limit,
+            config.reindex_last_blocks,
+            config.block_read_throttle.clone(),
+            config.min_free_disk_bytes,
+            config.block_filter_size,
+            config.index_tx_parallelism,
+            &config.index_cpu_cores,
+            config.index_balances,
+        )
+        .context("failed to open index")?;
+        daemon.add_block_positions(index.chain());
         Ok(Self {
-            index: Index::load(
-                store,
-                chain,
+            index,
+            mempool: Mempool::new(
                 metrics,
-                config.index_batch_size,
-                config.index_lookup_limit,
-                config.reindex_last_blocks,
-            )
-            .context("failed to open index")?,
-            mempool: Mempool::new(metrics),
+                config.mempool_scan_batch_size,
+                config.index_balances,
+            ),
             ignore_mempool: config.ignore_mempool,
+            mempool_only: config.mempool_only,
+            coinbase_maturity: config.coinbase_maturity,
+            first_blocks: Mutex::new(Lru::new(FIRST_BLOCK_CACHE_SIZE)),
         })
     }
 
@@ -53,19 +96,58 @@ impl Tracker {
     }
 
     pub(crate) fn get_unspent(&self, status: &ScriptHashStatus) -> Vec<UnspentEntry> {
-        status.get_unspent(self.index.chain())
+        status.get_unspent(self.index.chain(), self.coinbase_maturity)
     }
 
     pub(crate) fn sync(&mut self, daemon: &Daemon, exit_flag: &ExitFlag) -> Result<bool> {
+        if self.mempool_only {
+            // blocks are not indexed, so transactions mined with their parents look orphaned
+            return Ok(self.mempool.sync(daemon, |_txid| false));
+        }
         let done = self.index.sync(daemon, exit_flag)?;
         if done && !self.ignore_mempool {
-            self.mempool.sync(daemon);
             // TODO: double check tip - and retry on diff
+            let index = &self.index;
+            let is_confirmed = |txid: &Txid| index.filter_by_txid(*txid).next().is_some();
+            // initial mempool scan may require a few iterations
+            return Ok(self.mempool.sync(daemon, is_confirmed));
         }
         Ok(done)
     }
 
+    pub(crate) fn filter_new_mempool_txs(
+        &self,
+        scripthashes: &HashSet<ScriptHash>,
+        daemon: &Daemon,
+    ) -> Result<Vec<(ScriptHash, Txid)>> {
+        self.mempool.filter_recent(scripthashes, |txid| {
+            Ok(daemon.get_transaction_with_prevouts(txid)?.1)
+        })
+    }
+
+    pub(crate) fn get_orphaned_txids(&self) -> Vec<Txid> {
+        self.mempool.get_orphaned_txids()
+    }
+
+    /// Return the mempool transactions spending `outpoint` (more than one, while a replacement
+    /// is being processed).
+    pub(crate) fn get_mempool_spenders(&self, outpoint: &OutPoint) -> Vec<Txid> {
+        self.mempool
+            .filter_by_spending(outpoint)
+            .into_iter()
+            .map(|entry| entry.txid)
+            .collect()
+    }
+
+    /// Return true if mempool queries are not available yet (during the initial mempool scan).
+    pub(crate) fn is_mempool_loading(&self) -> bool {
+        !self.ignore_mempool && self.mempool.is_loading()
+    }
+
     pub(crate) fn status(&self) -> Result<(), Error> {
+        if self.mempool_only {
+            return Err(Error::MempoolOnly);
+        }
         if self.index.is_ready() {
             return Ok(());
         }
@@ -83,10 +165,61 @@ impl Tracker {
         Ok(prev_statushash != status.statushash())
     }
 
+    /// See `Index::check_funding_txs`.
+    pub(crate) fn check_funding_txs(&self, scripthash: ScriptHash, max_txs: usize) -> Result<()> {
+        self.index.check_funding_txs(scripthash, max_txs)
+    }
+
     pub(crate) fn get_balance(&self, status: &ScriptHashStatus) -> Balance {
         status.get_balance(self.chain())
     }
 
+    /// Re-index the blocks at the given heights range (see `Index::reindex_range`).
+    pub(crate) fn reindex_range(&self, daemon: &Daemon, start: usize, end: usize) -> Result<usize> {
+        self.index.reindex_range(daemon, start, end)
+    }
+
+    /// Get the scripthash balance from the balance index (if enabled), without its history.
+    pub(crate) fn get_indexed_balance(&self, scripthash: &ScriptHash) -> Result<Option<Balance>> {
+        let confirmed = match self.index.get_balance(*scripthash)? {
+            Some(confirmed) => confirmed,
+            None => return Ok(None),
+        };
+        let mempool_delta = if self.ignore_mempool {
+            SignedAmount::from_sat(0)
+        } else {
+            match self.mempool.get_balance_delta(scripthash) {
+                Some(delta) => delta,
+                None => return Ok(None),
+            }
+        };
+        Ok(Some(Balance::new(confirmed, mempool_delta)))
+    }
+
+    /// Get the confirmations of multiple transactions using the index (without bitcoind RPCs),
+    /// returning 0 for mempool transactions and `None` for unknown ones.
+    pub(crate) fn get_confirmations(
+        &self,
+        daemon: &Daemon,
+        txids: &[Txid],
+    ) -> Result<HashMap<Txid, Option<u32>>> {
+        let chain = self.chain();
+        txids
+            .par_iter() // for parallel reads
+            .map(|txid| {
+                if self.mempool.get(txid).is_some() {
+                    return Ok((*txid, Some(0)));
+                }
+                let confirmations = self
+                    .lookup_transaction(daemon, chain, *txid)?
+                    .and_then(|(blockhash, _tx)| chain.get_block_height(blockhash))
+                    .map(|height| u32::try_from(chain.height() + 1 - height))
+                    .transpose()?;
+                Ok((*txid, confirmations))
+            })
+            .collect()
+    }
+
     pub(crate) fn lookup_transaction(
         &self,
         daemon: &Daemon,
@@ -104,4 +237,60 @@ impl Tracker {
         }
         Ok(None)
     }
+
+    /// Get the height of the earliest block funding `scripthash` (which is also the first block
+    /// touching it), or None if it was never funded. The result is cached while its block remains
+    /// in the active chain.
+    pub(crate) fn get_scripthash_first_height(
+        &self,
+        daemon: &Daemon,
+        scripthash: ScriptHash,
+    ) -> Result<Option<usize>> {
+        let chain = self.index.chain();
+        if let Some(blockhash) = self.first_blocks.lock().get(&scripthash) {
+            if let Some(height) = chain.get_block_height(*blockhash) {
+                return Ok(Some(height));
+            }
+        }
+        // Funding rows are ordered by their file position (not by height), so all of them are
+        // scanned. The lookup limit is not applied, since only the earliest transactions are read.
+        let mut candidates: Vec<(usize, FilePosition, BlockHash)> = self
+            .index
+            .filter_by_funding(scripthash)
+            .filter_map(|pos| {
+                let row = chain.get_header_row_for(pos)?; // skip stale blocks
+                Some((chain.get_block_height(row.hash)?, pos, row.hash))
+            })
+            .collect();
+        candidates.sort_unstable();
+        for (height, pos, blockhash) in candidates {
+            // skip blocks not funding this scripthash (due to funding rows' prefix collisions)
+            if !self.index.block_may_contain(blockhash, scripthash) {
+                continue;
+            }
+            let tx = Transaction::consensus_decode(&mut daemon.open_file(pos)?)?;
+            if tx
+                .output
+                .iter()
+                .any(|txo| ScriptHash::new(&txo.script_pubkey) == scripthash)
+            {
+                self.first_blocks.lock().insert(scripthash, blockhash);
+                return Ok(Some(height));
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn genesis_header_row(network: Network) -> HeaderRow {
+    let block = genesis_block(network);
+    HeaderRow {
+        header: block.header,
+        hash: block.block_hash(),
+        pos: FilePosition {
+            file_id: 0,
+            offset: 0,
+        },
+        size: u32::try_from(serialize(&block).len()).expect("invalid genesis block size"),
+    }
 }
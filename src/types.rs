@@ -117,6 +117,15 @@ impl ScriptHashRow {
     }
 }
 
+/// Balances are keyed by the full scripthash (since they can't be verified like funding rows).
+pub(crate) struct BalanceRow;
+
+impl BalanceRow {
+    pub(crate) fn key(scripthash: ScriptHash) -> db::Row {
+        scripthash[..].to_vec().into_boxed_slice()
+    }
+}
+
 // ***************************************************************************
 
 hash_newtype!(
@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use bitcoin::{
+    consensus::Decodable, hashes::Hash, secp256k1, PubkeyHash, PublicKey, Script, ScriptHash,
+    TxOut, VarInt,
+};
+
+use std::io::Read;
+
+/// Maximum scriptPubKey size (larger scripts are stored as `OP_RETURN` by bitcoind)
+const MAX_SCRIPT_SIZE: u64 = 10_000;
+
+/// Parse bitcoind's `CBlockUndo` (stored in `rev*.dat` files), returning the spent outputs
+/// of each non-coinbase transaction in the block.
+pub(crate) fn parse_block_undo(r: &mut impl Read) -> Result<Vec<Vec<TxOut>>> {
+    let txs_count = read_compact_size(r).context("failed to read undo txs count")?;
+    let mut txs = vec![];
+    for tx_index in 0..txs_count {
+        let coins_count = read_compact_size(r)
+            .with_context(|| format!("failed to read undo #{} coins count", tx_index))?;
+        let mut coins = vec![];
+        for coin_index in 0..coins_count {
+            let txout = read_coin(r).with_context(|| {
+                format!("failed to read undo #{} coin #{}", tx_index, coin_index)
+            })?;
+            coins.push(txout);
+        }
+        txs.push(coins);
+    }
+    Ok(txs)
+}
+
+fn read_compact_size(r: &mut impl Read) -> Result<u64> {
+    Ok(VarInt::consensus_decode(r)?.0)
+}
+
+// See `TxInUndoFormatter` in https://github.com/bitcoin/bitcoin/blob/master/src/undo.h
+fn read_coin(r: &mut impl Read) -> Result<TxOut> {
+    let code = read_varint(r)?; // height * 2 + is_coinbase
+    if code >> 1 > 0 {
+        read_varint(r)?; // unused (kept for compatibility with older undo format)
+    }
+    let value = decompress_amount(read_varint(r)?);
+    let script_pubkey = read_script(r)?;
+    Ok(TxOut {
+        value,
+        script_pubkey,
+    })
+}
+
+// MSB base-128 encoding (see `ReadVarInt` in https://github.com/bitcoin/bitcoin/blob/master/src/serialize.h)
+fn read_varint(r: &mut impl Read) -> Result<u64> {
+    let mut n: u64 = 0;
+    loop {
+        let byte = read_bytes::<[u8; 1]>(r)?[0];
+        ensure!(n <= (u64::MAX >> 7), "varint too large");
+        n = (n << 7) | u64::from(byte & 0x7F);
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+        ensure!(n < u64::MAX, "varint too large");
+        n += 1;
+    }
+}
+
+// See `DecompressAmount` in https://github.com/bitcoin/bitcoin/blob/master/src/compressor.cpp
+fn decompress_amount(x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+    let mut x = x - 1;
+    let mut e = x % 10;
+    x /= 10;
+    let mut n = if e < 9 {
+        let d = (x % 9) + 1;
+        x /= 9;
+        x * 10 + d
+    } else {
+        x + 1
+    };
+    while e > 0 {
+        n *= 10;
+        e -= 1;
+    }
+    n
+}
+
+// See `ScriptCompression` in https://github.com/bitcoin/bitcoin/blob/master/src/compressor.h
+fn read_script(r: &mut impl Read) -> Result<Script> {
+    let script = match read_varint(r)? {
+        0 => Script::new_p2pkh(&PubkeyHash::from_inner(read_bytes(r)?)),
+        1 => Script::new_p2sh(&ScriptHash::from_inner(read_bytes(r)?)),
+        kind @ 2..=5 => {
+            let mut key = [0u8; 33];
+            key[0] = if kind < 4 { kind as u8 } else { kind as u8 - 2 };
+            key[1..].copy_from_slice(&read_bytes::<[u8; 32]>(r)?);
+            let key = secp256k1::PublicKey::from_slice(&key).context("invalid public key")?;
+            Script::new_p2pk(&PublicKey {
+                compressed: kind < 4,
+                key,
+            })
+        }
+        size => {
+            let size = size - 6;
+            ensure!(size <= MAX_SCRIPT_SIZE, "script too large: {}", size);
+            let mut script = vec![0u8; size as usize];
+            r.read_exact(&mut script)?;
+            Script::from(script)
+        }
+    };
+    Ok(script)
+}
+
+fn read_bytes<T: Default + AsMut<[u8]>>(r: &mut impl Read) -> Result<T> {
+    let mut bytes = T::default();
+    r.read_exact(bytes.as_mut())?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decompress_amount, parse_block_undo, read_varint};
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+
+    #[test]
+    fn test_varint() {
+        let read = |hex: &str| read_varint(&mut &Vec::from_hex(hex).unwrap()[..]).unwrap();
+        assert_eq!(read("00"), 0);
+        assert_eq!(read("7f"), 127);
+        assert_eq!(read("8000"), 128);
+        assert_eq!(read("807f"), 255);
+        assert_eq!(read("8100"), 256);
+        assert_eq!(read("8048"), 200);
+        assert!(read_varint(&mut &[0x80][..]).is_err());
+    }
+
+    #[test]
+    fn test_decompress_amount() {
+        assert_eq!(decompress_amount(0), 0);
+        assert_eq!(decompress_amount(1), 1);
+        assert_eq!(decompress_amount(9), 100_000_000);
+        assert_eq!(decompress_amount(50), 5_000_000_000);
+    }
+
+    #[test]
+    fn test_parse_block_undo() {
+        let hash = "00112233445566778899aabbccddeeff00112233";
+        let generator = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let hex = [
+            "0202".to_owned(),               // 2 transactions, the first one spending 2 coins
+            format!("8048000900{}", hash),   // height=100, unused version, 1 BTC, P2PKH
+            format!("0300321c0014{}", hash), // height=1 (coinbase), unused, 50 BTC, P2WPKH
+            "01".to_owned(),                 // the second transaction spends a single coin
+            format!("000104{}", generator),  // height=0 (no unused), 1 sat, P2PK (uncompressed)
+        ]
+        .concat();
+        let undo = parse_block_undo(&mut &Vec::from_hex(&hex).unwrap()[..]).unwrap();
+        assert_eq!(undo.len(), 2);
+        assert_eq!(undo[0].len(), 2);
+        assert_eq!(undo[1].len(), 1);
+
+        assert_eq!(undo[0][0].value, 100_000_000);
+        assert_eq!(
+            undo[0][0].script_pubkey.to_hex(),
+            format!("76a914{}88ac", hash)
+        );
+        assert_eq!(undo[0][1].value, 5_000_000_000);
+        assert_eq!(undo[0][1].script_pubkey.to_hex(), format!("0014{}", hash));
+        assert_eq!(undo[1][0].value, 1);
+        assert_eq!(
+            undo[1][0].script_pubkey.to_hex(),
+            format!(
+                "4104{}{}ac",
+                generator, "483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8"
+            )
+        );
+
+        // truncated data
+        assert!(parse_block_undo(&mut &Vec::from_hex(&hex[..hex.len() - 2]).unwrap()[..]).is_err());
+    }
+}